@@ -1,41 +1,94 @@
 use image::RgbImage;
 use std::fs;
 
-const BRUSH_DIM: u32 = 320;
+/// Largest brush width and height when `PR_BRUSH_DIM` isn't set
+const DEFAULT_BRUSH_DIM: u32 = 320;
+
+/// Width of the white columns between brushes, the same as `ATLAS_GUTTER` in `src/brushes.rs`
+const ATLAS_GUTTER: u32 = 4;
 
 fn main() {
+    println!("cargo:rerun-if-env-changed=PR_BRUSH_DIM");
+    let brush_dim = match std::env::var("PR_BRUSH_DIM") {
+        Ok(dim) => match dim.parse::<u32>() {
+            Ok(dim) if dim > 0 => dim,
+            _ => panic!("PR_BRUSH_DIM must be a positive integer, got '{dim}'"),
+        },
+        Err(_) => DEFAULT_BRUSH_DIM,
+    };
+
     let manifest_dir = std::env::var("CARGO_MANIFEST_DIR").unwrap();
     let brush_dir = format!("{}/res/textures/brushes", manifest_dir);
     println!("cargo:rerun-if-changed={}", brush_dir);
-    let brushes = fs::read_dir(brush_dir)
+    let mut brushes = fs::read_dir(brush_dir)
         .unwrap()
         .map(|dir| dir.unwrap().path())
         .filter(|p| p.file_name().unwrap().to_string_lossy() != ".DS_Store")
         .collect::<Vec<_>>();
-    let out_image_width = BRUSH_DIM * brushes.len() as u32;
-    let out_image_height = BRUSH_DIM;
-    let mut out_image = RgbImage::new(out_image_width, out_image_height);
-    out_image.fill(0xff);
+    // Directory iteration order depends on the filesystem, sort so brush indices are stable
+    brushes.sort();
 
-    let num_brushes = brushes.len();
+    let brush_names = brushes
+        .iter()
+        .map(|p| p.file_name().unwrap().to_string_lossy().into_owned())
+        .collect::<Vec<_>>()
+        .join(",");
+    let brushes = brushes
+        .into_iter()
+        .map(|brush_path| {
+            println!("cargo:rerun-if-changed={}", brush_path.to_string_lossy());
+            let brush = image::open(&brush_path)
+                .unwrap_or_else(|e| panic!("Failed to open brush '{}': {e}", brush_path.display()))
+                .into_rgb8();
+            if brush.width() > brush_dim || brush.height() > brush_dim {
+                panic!(
+                    "Brush '{}' is {}x{}, larger than the {brush_dim}x{brush_dim} brush limit \
+                     (set PR_BRUSH_DIM to change it)",
+                    brush_path.display(),
+                    brush.width(),
+                    brush.height()
+                );
+            }
+            brush
+        })
+        .collect::<Vec<_>>();
 
-    for (i, brush) in brushes.into_iter().enumerate() {
-        println!("cargo:rerun-if-changed={}", brush.to_string_lossy());
-        let brush = image::open(brush).unwrap().into_rgb8();
-        assert_eq!(brush.width(), BRUSH_DIM);
-        let x_offset = i as u32 * BRUSH_DIM;
-        let y_offset = (BRUSH_DIM - brush.height()) / 2;
+    // Brushes are packed side by side at their own size with a gutter between them, the rest of
+    // the atlas is white
+    let out_image_width = brushes.iter().map(|brush| brush.width()).sum::<u32>()
+        + ATLAS_GUTTER * (brushes.len() as u32).saturating_sub(1);
+    let out_image_height = brushes
+        .iter()
+        .map(|brush| brush.height())
+        .max()
+        .unwrap_or(1);
+    let mut out_image = RgbImage::new(out_image_width, out_image_height);
+    out_image.fill(0xff);
 
+    let mut rects = vec![];
+    let mut x_offset = 0;
+    for brush in brushes {
         for (x0, y0, p) in brush.enumerate_pixels() {
-            out_image.put_pixel(x_offset + x0, y_offset + y0, *p);
+            out_image.put_pixel(x_offset + x0, y0, *p);
         }
+        rects.push(format!(
+            "[{x_offset}, 0, {}, {}]",
+            brush.width(),
+            brush.height()
+        ));
+        x_offset += brush.width() + ATLAS_GUTTER;
     }
 
     let out_dir = std::env::var("OUT_DIR").unwrap();
     let out_file = format!("{}/brushes.png", out_dir);
     out_image.save(out_file).unwrap();
+    // Pixel rectangles `[x, y, width, height]` of the brushes in the atlas, included by
+    // `src/brushes.rs`
+    let rects_file = format!("{}/brush_rects.rs", out_dir);
+    fs::write(rects_file, format!("&[{}]", rects.join(", "))).unwrap();
 
-    println!("cargo:rustc-env=PR_NUM_BRUSHES={num_brushes}");
+    println!("cargo:rustc-env=PR_BRUSH_NAMES={brush_names}");
+    println!("cargo:rustc-env=PR_BRUSH_DIM={brush_dim}");
 
     println!("cargo:rerun-if-changed=build.rs");
 }
@@ -0,0 +1,19 @@
+#!/bin/sh
+# Renders every scene in res/scenes and compares it to its reference render in res/references.
+# Pass --update-reference to write new references, or --tolerance to change the accepted difference.
+set -u
+
+cargo build --release || exit 1
+mkdir -p res/references
+
+status=0
+for scene in res/scenes/*.toml; do
+    ./target/release/paint_render "$scene" --reference res/references "$@" || status=1
+done
+
+if [ $status -eq 0 ]; then
+    echo "All scenes match their references"
+else
+    echo "Some scenes differ from their references"
+fi
+exit $status
@@ -0,0 +1,60 @@
+use serde::{Deserialize, Serialize};
+
+/// Keyframes of a float parameter as `[time in seconds, value]` pairs, linearly interpolated and
+/// held before the first and after the last keyframe
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(from = "Vec<(f32, f32)>", into = "Vec<(f32, f32)>")]
+pub struct Curve {
+    keys: Vec<(f32, f32)>,
+}
+
+impl From<Vec<(f32, f32)>> for Curve {
+    fn from(mut keys: Vec<(f32, f32)>) -> Self {
+        keys.sort_by(|(a, _), (b, _)| a.total_cmp(b));
+        Self { keys }
+    }
+}
+
+impl From<Curve> for Vec<(f32, f32)> {
+    fn from(curve: Curve) -> Self {
+        curve.keys
+    }
+}
+
+impl Curve {
+    /// Value at `time`, `None` without keyframes
+    pub fn evaluate(&self, time: f32) -> Option<f32> {
+        let next = self.keys.partition_point(|&(t, _)| t <= time);
+        match (self.keys.get(next.wrapping_sub(1)), self.keys.get(next)) {
+            (Some(&(t0, v0)), Some(&(t1, v1))) => Some(v0 + (v1 - v0) * (time - t0) / (t1 - t0)),
+            (Some(&(_, v)), None) | (None, Some(&(_, v))) => Some(v),
+            (None, None) => None,
+        }
+    }
+
+    /// Time of the last keyframe
+    pub fn duration(&self) -> f32 {
+        self.keys.last().map_or(0.0, |&(t, _)| t)
+    }
+}
+
+/// Parameters animated over time, set with `[animation]` in the scene
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+pub struct Animation {
+    pub quantization: Option<Curve>,
+    pub brush_size: Option<Curve>,
+    /// Start over after the last keyframe
+    #[serde(default, rename = "loop")]
+    pub looping: bool,
+}
+
+impl Animation {
+    /// Time of the last keyframe of any curve
+    pub fn duration(&self) -> f32 {
+        [&self.quantization, &self.brush_size]
+            .into_iter()
+            .flatten()
+            .map(Curve::duration)
+            .fold(0.0, f32::max)
+    }
+}
@@ -0,0 +1,90 @@
+use glium::{
+    backend::Facade,
+    framebuffer::SimpleFrameBuffer,
+    texture::{MipmapsOption, SrgbTexture2d, Texture2d, UncompressedFloatFormat},
+    uniform, DrawParameters, IndexBuffer, Program, Surface, VertexBuffer,
+};
+
+use crate::PostProcessVert;
+
+/// Number of horizontal and vertical blur pass pairs, each widens the glow
+const BLUR_PASSES: usize = 2;
+
+/// Half resolution buffers the bright parts of the painting are blurred in
+pub struct BloomTargets {
+    /// Holds the blurred result after `draw_bloom`
+    ping: Texture2d,
+    pong: Texture2d,
+}
+
+impl BloomTargets {
+    /// Targets for a `width` by `height` frame
+    pub fn new(display: &impl Facade, width: u32, height: u32) -> Self {
+        let color = || {
+            Texture2d::empty_with_format(
+                display,
+                UncompressedFloatFormat::F16F16F16F16,
+                MipmapsOption::NoMipmap,
+                (width / 2).max(1),
+                (height / 2).max(1),
+            )
+            .unwrap()
+        };
+        Self {
+            ping: color(),
+            pong: color(),
+        }
+    }
+
+    /// The blurred bright parts drawn by the last `draw_bloom`
+    pub fn texture(&self) -> &Texture2d {
+        &self.ping
+    }
+}
+
+/// Thresholds `source` into the targets and blurs it, leaving the glow in `targets.texture()`
+pub fn draw_bloom(
+    display: &impl Facade,
+    targets: &BloomTargets,
+    source: &SrgbTexture2d,
+    threshold: f32,
+    quad: &(VertexBuffer<PostProcessVert>, IndexBuffer<u8>),
+    threshold_program: &Program,
+    blur_program: &Program,
+) {
+    SimpleFrameBuffer::new(display, &targets.ping)
+        .unwrap()
+        .draw(
+            &quad.0,
+            &quad.1,
+            threshold_program,
+            &uniform! {
+                source_texture: source,
+                threshold: threshold,
+            },
+            &DrawParameters::default(),
+        )
+        .unwrap();
+
+    let (width, height) = (targets.ping.width() as f32, targets.ping.height() as f32);
+    for _ in 0..BLUR_PASSES {
+        for (from, to, direction) in [
+            (&targets.ping, &targets.pong, [1.0 / width, 0.0]),
+            (&targets.pong, &targets.ping, [0.0, 1.0 / height]),
+        ] {
+            SimpleFrameBuffer::new(display, to)
+                .unwrap()
+                .draw(
+                    &quad.0,
+                    &quad.1,
+                    blur_program,
+                    &uniform! {
+                        source_texture: from,
+                        direction: direction,
+                    },
+                    &DrawParameters::default(),
+                )
+                .unwrap();
+        }
+    }
+}
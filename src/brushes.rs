@@ -0,0 +1,209 @@
+//! The brush atlas: every brush texture side by side at its own size, at most `brush_dim` pixels
+//! in either direction and `ATLAS_GUTTER` pixels apart, with the rectangle of each brush. The
+//! built-in atlas is packed from `res/textures/brushes` by `build.rs`, a scene's `brush_dir` is
+//! packed the same way when the scene loads.
+
+use std::{
+    fmt, fs,
+    io::{self, Cursor},
+    path::{Path, PathBuf},
+};
+
+use image::{DynamicImage, ImageError, ImageFormat, ImageOutputFormat, RgbImage, RgbaImage};
+
+const BUILTIN_ATLAS: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/brushes.png"));
+const BUILTIN_RECTS: &[[u32; 4]] = include!(concat!(env!("OUT_DIR"), "/brush_rects.rs"));
+
+/// Width of the white columns between brushes, so filtering at the edge of a brush doesn't blend
+/// in its neighbour. A multiple of 4 keeps the brushes on 4 pixel boundaries.
+const ATLAS_GUTTER: u32 = 4;
+
+#[derive(Debug)]
+pub enum BrushError {
+    Io(io::Error),
+    Image(PathBuf, ImageError),
+    /// A brush wider or taller than `brush_dim`
+    Size(PathBuf, (u32, u32)),
+    Empty,
+}
+
+impl fmt::Display for BrushError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BrushError::Io(e) => write!(f, "{e}"),
+            BrushError::Image(path, e) => write!(f, "'{}': {e}", path.display()),
+            BrushError::Size(path, (width, height)) => write!(
+                f,
+                "'{}' is {width}x{height}, larger than the {dim}x{dim} brush limit",
+                path.display(),
+                dim = brush_dim()
+            ),
+            BrushError::Empty => write!(f, "no brush images"),
+        }
+    }
+}
+
+impl From<io::Error> for BrushError {
+    fn from(e: io::Error) -> Self {
+        BrushError::Io(e)
+    }
+}
+
+/// Largest width and height of a brush. Set at build time with the `PR_BRUSH_DIM` environment
+/// variable
+fn brush_dim() -> u32 {
+    env!("PR_BRUSH_DIM").parse().unwrap()
+}
+
+/// The images in `brush_dir`, like the PNGs and JPEGs of the built-in brushes, sorted so brush
+/// indices are stable
+fn brush_files(brush_dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut brushes = fs::read_dir(brush_dir)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .filter(|path| {
+            path.as_ref()
+                .map_or(true, |path| ImageFormat::from_path(path).is_ok())
+        })
+        .collect::<io::Result<Vec<_>>>()?;
+    brushes.sort();
+    Ok(brushes)
+}
+
+/// File names of the brushes in `brush_dir`, or of the built-in brushes without one, in the order
+/// of the atlas
+pub fn brush_names(brush_dir: Option<&Path>) -> io::Result<Vec<String>> {
+    Ok(match brush_dir {
+        Some(brush_dir) => brush_files(brush_dir)?
+            .iter()
+            .map(|path| path.file_name().unwrap().to_string_lossy().into_owned())
+            .collect(),
+        None => env!("PR_BRUSH_NAMES")
+            .split(',')
+            .map(String::from)
+            .collect(),
+    })
+}
+
+/// Brushes packed side by side, `ATLAS_GUTTER` pixels apart
+pub struct BrushAtlas {
+    pub image: RgbaImage,
+    /// Pixel rectangle `[x, y, width, height]` of every brush in `image`, by brush index
+    pub rects: Vec<[u32; 4]>,
+}
+
+impl BrushAtlas {
+    /// `rects` as texture coordinates `[u, v, width, height]`, with `v` going up from the bottom
+    /// row of `image` like the uploaded texture. The rectangles go from the centers of the edge
+    /// texels, so linear filtering never reaches past the brush.
+    pub fn uv_rects(&self) -> Vec<[f32; 4]> {
+        let (width, height) = (self.image.width() as f32, self.image.height() as f32);
+        self.rects
+            .iter()
+            .map(|&[x, y, w, h]| {
+                [
+                    (x as f32 + 0.5) / width,
+                    1.0 - ((y + h) as f32 - 0.5) / height,
+                    (w as f32 - 1.0).max(0.0) / width,
+                    (h as f32 - 1.0).max(0.0) / height,
+                ]
+            })
+            .collect()
+    }
+
+    /// `image` encoded as PNG
+    pub fn png(&self) -> Result<Vec<u8>, BrushError> {
+        let mut png = Cursor::new(vec![]);
+        self.image
+            .write_to(&mut png, ImageOutputFormat::Png)
+            .map_err(|e| BrushError::Image("brushes.png".into(), e))?;
+        Ok(png.into_inner())
+    }
+}
+
+/// The atlas of the brushes in `brush_dir`, or the built-in atlas without one. The brushes are
+/// top aligned, the atlas is as tall as the tallest one and white around the shorter ones and in
+/// the gutters.
+pub fn load_atlas(brush_dir: Option<&Path>) -> Result<BrushAtlas, BrushError> {
+    let Some(brush_dir) = brush_dir else {
+        return Ok(BrushAtlas {
+            image: image::load_from_memory(BUILTIN_ATLAS)
+                .map(DynamicImage::into_rgba8)
+                .map_err(|e| BrushError::Image("brushes.png".into(), e))?,
+            rects: BUILTIN_RECTS.to_vec(),
+        });
+    };
+
+    let paths = brush_files(brush_dir)?;
+    if paths.is_empty() {
+        return Err(BrushError::Empty);
+    }
+
+    let brush_dim = brush_dim();
+    let brushes = paths
+        .into_iter()
+        .map(|path| {
+            let brush = image::open(&path)
+                .map_err(|e| BrushError::Image(path.clone(), e))?
+                .into_rgb8();
+            if brush.width() > brush_dim || brush.height() > brush_dim {
+                return Err(BrushError::Size(path, brush.dimensions()));
+            }
+            Ok(brush)
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let width = brushes.iter().map(|brush| brush.width()).sum::<u32>()
+        + ATLAS_GUTTER * (brushes.len() as u32 - 1);
+    let height = brushes
+        .iter()
+        .map(|brush| brush.height())
+        .max()
+        .unwrap_or(1);
+    let mut atlas = RgbImage::new(width, height);
+    atlas.fill(0xff);
+    let mut rects = vec![];
+    let mut x_offset = 0;
+    for brush in brushes {
+        for (x, y, pixel) in brush.enumerate_pixels() {
+            atlas.put_pixel(x_offset + x, y, *pixel);
+        }
+        rects.push([x_offset, 0, brush.width(), brush.height()]);
+        x_offset += brush.width() + ATLAS_GUTTER;
+    }
+    Ok(BrushAtlas {
+        image: DynamicImage::ImageRgb8(atlas).into_rgba8(),
+        rects,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, process};
+
+    use super::*;
+
+    #[test]
+    fn brushes_are_apart_and_sampled_inside() {
+        let dir = env::temp_dir().join(format!("paint_render_brushes_{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        RgbImage::new(10, 20).save(dir.join("a.png")).unwrap();
+        RgbImage::new(6, 8).save(dir.join("b.png")).unwrap();
+
+        let atlas = load_atlas(Some(&dir)).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(atlas.rects, [[0, 0, 10, 20], [10 + ATLAS_GUTTER, 0, 6, 8]]);
+        assert_eq!(atlas.image.dimensions(), (16 + ATLAS_GUTTER, 20));
+        // The gutter is white
+        assert_eq!(atlas.image.get_pixel(10, 0).0, [255; 4]);
+
+        // The second brush from the middle of its first texel to the middle of its last
+        let (width, height) = (atlas.image.width() as f32, 20.0);
+        let [u, v, w, h] = atlas.uv_rects()[1];
+        let texel = |a: f32, b: f32| (a - b).abs() < 1.0e-6;
+        assert!(texel(u * width, 10.5 + ATLAS_GUTTER as f32));
+        assert!(texel((u + w) * width, 15.5 + ATLAS_GUTTER as f32));
+        // The rows are flipped, the brush is at the top
+        assert!(texel((1.0 - v) * height, 7.5));
+        assert!(texel((1.0 - v - h) * height, 0.5));
+    }
+}
@@ -0,0 +1,277 @@
+//! Viewer bundles: a painting exported as a directory that a WebGL viewer can draw without the
+//! native binary.
+//!
+//! A bundle directory contains:
+//!
+//! * `bundle.json`: a [`Bundle`], describing everything else
+//! * `points.bin`: every stroke as [`POINT_FLOATS`] little-endian `f32`s, laid out as described
+//!   by [`Bundle::point_attributes`]. Strokes are unsorted, the viewer sorts them by depth
+//! * the albedo texture, the brush atlas (`brushes.png`, [`Bundle::num_brushes`] brushes side by
+//!   side at their own size with white gutters between them, in [`Bundle::brush_rects`]) and the
+//!   canvas texture (`canvas.png` unless the scene has its own)
+//!
+//! [`VERSION`] is bumped on any incompatible change to this layout. [`read_bundle`] reads the
+//! strokes back.
+
+use std::{
+    array,
+    borrow::Cow,
+    fs::{self, File},
+    io::{self, BufReader, BufWriter, Write},
+    path::Path,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{manifest::CameraManifest, point_gen::Point, Params};
+
+pub const VERSION: u32 = 6;
+
+/// Number of `f32`s per stroke in `points.bin`
+pub const POINT_FLOATS: usize = 24;
+
+const POINTS_FILE: &str = "points.bin";
+const BUNDLE_FILE: &str = "bundle.json";
+
+/// Contents of `bundle.json`
+#[derive(Debug, Serialize)]
+pub struct Bundle<'a> {
+    pub version: u32,
+    pub crate_version: &'static str,
+    pub num_points: usize,
+    pub point_attributes: &'static [PointAttribute],
+    pub albedo_texture: String,
+    pub brush_atlas: &'static str,
+    pub num_brushes: u32,
+    /// Texture coordinates `[u, v, width, height]` of every brush in the atlas, with `v` going up
+    /// from the bottom of the image. They span the centers of the brush's edge texels
+    pub brush_rects: Vec<[f32; 4]>,
+    pub canvas_texture: String,
+    pub background: [f32; 3],
+    pub paint_background: [f32; 3],
+    pub params: &'a Params,
+    pub model_matrix: [[f32; 4]; 4],
+    /// Transforms of every instance, applied before `model_matrix`
+    pub instances: Vec<[[f32; 4]; 4]>,
+    pub camera: CameraManifest,
+}
+
+/// The parts of `bundle.json` needed to read the strokes back
+#[derive(Debug, Deserialize)]
+pub struct BundleHeader {
+    pub version: u32,
+    pub num_points: usize,
+    pub point_attributes: Vec<PointAttribute>,
+}
+
+/// One attribute of the strokes in `points.bin`, `components` floats starting at float `offset`
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct PointAttribute {
+    pub name: Cow<'static, str>,
+    pub offset: usize,
+    pub components: usize,
+}
+
+/// The attributes of a stroke, in order
+pub static POINT_ATTRIBUTES: [PointAttribute; 11] = [
+    PointAttribute::new("position", 0, 3),
+    PointAttribute::new("normal", 3, 3),
+    PointAttribute::new("tangent", 6, 3),
+    PointAttribute::new("bitangent", 9, 3),
+    PointAttribute::new("uv", 12, 2),
+    // Stored as a float so every attribute has the same type
+    PointAttribute::new("brush_index", 14, 1),
+    PointAttribute::new("length", 15, 1),
+    // Random rotation and size variation
+    PointAttribute::new("jitter", 16, 2),
+    // Stroke direction in uv space, zero to follow the tangent
+    PointAttribute::new("flow", 18, 2),
+    // Linear albedo, drawn instead of the albedo texture with `params.flat_color`
+    PointAttribute::new("color", 20, 3),
+    // Random rank, adaptive density draws the strokes under a fraction that shrinks with distance
+    PointAttribute::new("lod", 23, 1),
+];
+
+impl PointAttribute {
+    const fn new(name: &'static str, offset: usize, components: usize) -> Self {
+        Self {
+            name: Cow::Borrowed(name),
+            offset,
+            components,
+        }
+    }
+}
+
+fn point_floats(point: &Point) -> [f32; POINT_FLOATS] {
+    let mut floats = [0.0; POINT_FLOATS];
+    floats[0..3].copy_from_slice(&point.position);
+    floats[3..6].copy_from_slice(&point.normal);
+    floats[6..9].copy_from_slice(&point.tangent);
+    floats[9..12].copy_from_slice(&point.bitangent);
+    floats[12..14].copy_from_slice(&point.uv);
+    floats[14] = point.brush_index as f32;
+    floats[15] = point.stroke_length_rand;
+    floats[16..18].copy_from_slice(&point.jitter);
+    floats[18..20].copy_from_slice(&point.flow);
+    floats[20..23].copy_from_slice(&point.color);
+    floats[23] = point.lod;
+    floats
+}
+
+fn point_from_floats(floats: &[f32; POINT_FLOATS]) -> Point {
+    fn array<const N: usize>(floats: &[f32], start: usize) -> [f32; N] {
+        array::from_fn(|i| floats[start + i])
+    }
+    Point {
+        position: array(floats, 0),
+        normal: array(floats, 3),
+        tangent: array(floats, 6),
+        bitangent: array(floats, 9),
+        uv: array(floats, 12),
+        brush_index: floats[14] as i32,
+        stroke_length_rand: floats[15],
+        jitter: array(floats, 16),
+        flow: array(floats, 18),
+        color: array(floats, 20),
+        lod: floats[23],
+    }
+}
+
+fn write_points(path: &Path, points: &[Point]) -> io::Result<()> {
+    let mut file = BufWriter::new(File::create(path)?);
+    for point in points {
+        for float in point_floats(point) {
+            file.write_all(&float.to_le_bytes())?;
+        }
+    }
+    file.flush()
+}
+
+/// Writes `bundle` and its files to `dir`, creating it if needed. The albedo texture is copied
+/// from `albedo_source`, the built-in textures are written from their PNG bytes.
+pub fn write_bundle(
+    dir: &Path,
+    bundle: &Bundle,
+    points: &[Point],
+    albedo_source: &Path,
+    brushes_png: &[u8],
+    canvas_png: &[u8],
+) -> io::Result<()> {
+    fs::create_dir_all(dir)?;
+    write_points(&dir.join(POINTS_FILE), points)?;
+    fs::copy(albedo_source, dir.join(&bundle.albedo_texture))?;
+    fs::write(dir.join(bundle.brush_atlas), brushes_png)?;
+    fs::write(dir.join(&bundle.canvas_texture), canvas_png)?;
+
+    let file = BufWriter::new(File::create(dir.join(BUNDLE_FILE))?);
+    serde_json::to_writer_pretty(file, bundle)?;
+    Ok(())
+}
+
+/// Reads the header and strokes of the bundle in `dir`. Bundles of another version or with a
+/// different stroke layout are refused.
+pub fn read_bundle(dir: &Path) -> io::Result<(BundleHeader, Vec<Point>)> {
+    let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidData, message);
+
+    let file = BufReader::new(File::open(dir.join(BUNDLE_FILE))?);
+    let header: BundleHeader = serde_json::from_reader(file)?;
+    if header.version != VERSION {
+        return Err(invalid(format!(
+            "bundle version {} is not {VERSION}",
+            header.version
+        )));
+    }
+    if header.point_attributes != POINT_ATTRIBUTES {
+        return Err(invalid("unknown stroke layout".to_string()));
+    }
+
+    let bytes = fs::read(dir.join(POINTS_FILE))?;
+    let stroke_bytes = POINT_FLOATS * 4;
+    if bytes.len() != header.num_points * stroke_bytes {
+        return Err(invalid(format!(
+            "{POINTS_FILE} has {} bytes, {} strokes need {}",
+            bytes.len(),
+            header.num_points,
+            header.num_points * stroke_bytes
+        )));
+    }
+    let points = bytes
+        .chunks_exact(stroke_bytes)
+        .map(|stroke| {
+            point_from_floats(&array::from_fn(|i| {
+                f32::from_le_bytes(stroke[i * 4..i * 4 + 4].try_into().unwrap())
+            }))
+        })
+        .collect();
+    Ok((header, points))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, process};
+
+    use cgmath::{Deg, Matrix4, Point3, SquareMatrix, Vector3};
+
+    use super::*;
+    use crate::{camera::Camera, Params, Scene};
+
+    #[test]
+    fn bundles_round_trip() {
+        let scene =
+            Scene::load(&Path::new(env!("CARGO_MANIFEST_DIR")).join("res/scenes/apple.toml"))
+                .unwrap()
+                .scene;
+        let params = Params::from_scene(&scene);
+        let camera = Camera::new(
+            Point3::new(0.0, 0.0, 2.0),
+            -Vector3::unit_z(),
+            Deg(60.0),
+            1.5,
+            0.1,
+            10.0,
+        );
+        let points = (0..100)
+            .map(|i| {
+                let x = i as f32 / 100.0;
+                Point::new(
+                    [x, 1.0 - x, 0.5],
+                    [0.0, 0.0, 1.0],
+                    [1.0, 0.0, 0.0],
+                    [0.0, 1.0, 0.0],
+                    [x, x],
+                    i % 3,
+                )
+                .with_jitter(1, i as u64)
+                .with_flow([0.0, x])
+                .with_color([x, 0.5, 1.0])
+            })
+            .collect::<Vec<_>>();
+        let bundle = Bundle {
+            version: VERSION,
+            crate_version: env!("CARGO_PKG_VERSION"),
+            num_points: points.len(),
+            point_attributes: &POINT_ATTRIBUTES,
+            albedo_texture: "albedo.png".to_string(),
+            brush_atlas: "brushes.png",
+            num_brushes: 3,
+            brush_rects: vec![[0.0, 0.0, 1.0 / 3.0, 1.0]; 3],
+            canvas_texture: "canvas.png".to_string(),
+            background: [1.0, 1.0, 1.0],
+            paint_background: [0.9, 0.9, 0.8],
+            params: &params,
+            model_matrix: Matrix4::identity().into(),
+            instances: vec![],
+            camera: (&camera).into(),
+        };
+
+        let dir = env::temp_dir().join(format!("paint_render_bundle_{}", process::id()));
+        write_bundle(&dir, &bundle, &points, &scene.albedo_texture, b"", b"").unwrap();
+        let read = read_bundle(&dir);
+        fs::remove_dir_all(&dir).unwrap();
+
+        let (header, read_points) = read.unwrap();
+        assert_eq!(header.version, VERSION);
+        assert_eq!(header.num_points, points.len());
+        assert_eq!(read_points, points);
+    }
+}
@@ -1,12 +1,30 @@
 use std::cell::Cell;
 
-use cgmath::{prelude::*, Deg, Matrix4, Point3, Rad, Vector3};
+use cgmath::{prelude::*, vec4, Deg, Matrix4, Point3, Rad, Vector3, Vector4};
+
+/// Vertical field of view brush sizes are tuned for, and the default of scenes
+pub const DEFAULT_FOV: Deg<f32> = Deg(100.0);
+
+/// Closest `Camera::zoom` gets to the camera target
+const MIN_TARGET_DISTANCE: f32 = 0.01;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Projection {
+    Perspective,
+    /// Parallel projection showing `height` world units vertically
+    Orthographic {
+        height: f32,
+    },
+}
 
 #[derive(Debug)]
 pub struct Camera {
     position: Point3<f32>,
     direction: Vector3<f32>,
+    /// Point orbited by `rotate_up`, moved along with the camera by `translate`
+    target: Point3<f32>,
     fov: Rad<f32>,
+    projection: Projection,
     aspect_ratio: f32,
     near: f32,
     far: f32,
@@ -26,7 +44,9 @@ impl Camera {
         Self {
             position,
             direction,
+            target: Point3::origin(),
             fov: fov.into(),
+            projection: Projection::Perspective,
             aspect_ratio,
             near,
             far,
@@ -37,22 +57,59 @@ impl Camera {
 
     pub fn rotate_up(&mut self, angle: impl Into<Rad<f32>>) {
         let angle = angle.into();
-        let theta: Deg<_> = self.position.to_vec().angle(Vector3::unit_y()).into();
+        let offset = self.position - self.target;
+        let theta: Deg<_> = offset.angle(Vector3::unit_y()).into();
         let angle_d: Deg<_> = angle.into();
         if (theta.0 + angle_d.0 < 5.0 && angle.0 < 0.0)
             || (theta.0 + angle_d.0 > 175.0 && angle.0 > 0.0)
         {
             return;
         }
-        let distance = self.position.distance(Point3::origin());
+        let offset = Matrix4::from_axis_angle(self.right(), angle) * offset.extend(0.0);
+        self.position = self.target + offset.truncate();
+        self.direction = -offset.truncate().normalize();
+        self.reset_view_perspective();
+    }
 
-        self.position = Point3::from_vec(
-            distance
-                * (Matrix4::from_axis_angle(self.right(), angle)
-                    * self.position.to_vec().normalize().extend(1.0))
-                .truncate(),
-        );
-        self.direction = -self.position.to_vec().normalize();
+    /// Orbits the camera horizontally around the vertical axis through its target
+    pub fn rotate_around(&mut self, angle: impl Into<Rad<f32>>) {
+        let rotation = Matrix4::from_angle_y(angle);
+        let offset = rotation * (self.position - self.target).extend(0.0);
+        self.position = self.target + offset.truncate();
+        self.direction = (rotation * self.direction.extend(0.0)).truncate();
+        self.reset_view_perspective();
+    }
+
+    /// Moves the camera and the point it orbits by `delta`, keeping its direction
+    pub fn translate(&mut self, delta: Vector3<f32>) {
+        self.position += delta;
+        self.target += delta;
+        self.reset_view_perspective();
+    }
+
+    /// Moves the camera `distance` away from `target`, keeping its direction, and orbits `target`
+    pub fn look_at(&mut self, target: Point3<f32>, distance: f32) {
+        self.target = target;
+        self.position = target - self.direction.normalize() * distance;
+        self.reset_view_perspective();
+    }
+
+    /// Moves the camera back along its direction until the sphere fits the view, and fits the
+    /// depth range around it
+    pub fn frame(&mut self, center: Point3<f32>, radius: f32) {
+        // A little margin around the sphere
+        let radius = radius * 1.05;
+        let tan_half_fov = (self.fov / 2.0).tan();
+        let half_fov = Rad(tan_half_fov.min(tan_half_fov * self.aspect_ratio).atan());
+        let distance = radius / half_fov.sin();
+        self.look_at(center, distance);
+
+        if let Projection::Orthographic { height } = &mut self.projection {
+            *height = 2.0 * radius * (1.0 / self.aspect_ratio).max(1.0);
+        }
+        // Strokes stick out of the surface a little
+        self.near = (distance - 1.5 * radius).max(0.01 * distance);
+        self.far = distance + 1.5 * radius;
         self.reset_view_perspective();
     }
 
@@ -64,8 +121,131 @@ impl Camera {
         self.position
     }
 
+    pub fn direction(&self) -> Vector3<f32> {
+        self.direction
+    }
+
+    /// Point the camera orbits
+    pub fn target(&self) -> Point3<f32> {
+        self.target
+    }
+
+    /// Moves the camera to `position`, turning it to keep looking at its target
+    pub fn set_position(&mut self, position: Point3<f32>) {
+        self.position = position;
+        self.face_target();
+    }
+
+    /// Turns the camera to look at `target` from where it is, and orbits `target` from now on
+    pub fn set_target(&mut self, target: Point3<f32>) {
+        self.target = target;
+        self.face_target();
+    }
+
+    fn face_target(&mut self) {
+        // Keeps the old direction if the camera is on the target
+        let direction = self.target - self.position;
+        if direction.magnitude2() > 0.0 {
+            self.direction = direction.normalize();
+        }
+        self.reset_view_perspective();
+    }
+
+    pub fn fov(&self) -> Rad<f32> {
+        self.fov
+    }
+
+    pub fn projection(&self) -> Projection {
+        self.projection
+    }
+
+    pub fn set_projection(&mut self, projection: Projection) {
+        self.projection = projection;
+        self.reset_view_perspective();
+    }
+
+    pub fn set_fov(&mut self, fov: impl Into<Rad<f32>>) {
+        self.fov = fov.into();
+        self.reset_view_perspective();
+    }
+
+    pub fn set_near_far(&mut self, near: f32, far: f32) {
+        self.near = near;
+        self.far = far;
+        self.reset_view_perspective();
+    }
+
+    /// Scale of the clip space stroke offsets of the geometry shader. Perspective divides them
+    /// by w, so this keeps strokes the same size in world units at any field of view and under an
+    /// orthographic projection as at `DEFAULT_FOV`.
+    pub fn stroke_scale(&self) -> f32 {
+        let reference = (Rad::from(DEFAULT_FOV) / 2.0).tan();
+        match self.projection {
+            Projection::Perspective => reference / (self.fov / 2.0).tan(),
+            Projection::Orthographic { height } => 2.0 * reference / height,
+        }
+    }
+
+    pub fn aspect_ratio(&self) -> f32 {
+        self.aspect_ratio
+    }
+
+    pub fn near(&self) -> f32 {
+        self.near
+    }
+
+    pub fn far(&self) -> f32 {
+        self.far
+    }
+
+    /// World space ray through the center of pixel (`x`, `y`) of a `width` by `height` image,
+    /// as its origin on the near plane and normalized direction
+    pub fn ray_from_pixel(
+        &self,
+        x: f32,
+        y: f32,
+        width: u32,
+        height: u32,
+    ) -> (Point3<f32>, Vector3<f32>) {
+        let x = 2.0 * (x + 0.5) / width as f32 - 1.0;
+        let y = 1.0 - 2.0 * (y + 0.5) / height as f32;
+
+        let inverse = (Matrix4::from(self.perspective()) * Matrix4::from(self.view()))
+            .invert()
+            .unwrap();
+        let unproject = |z: f32| {
+            let p: Vector4<f32> = inverse * vec4(x, y, z, 1.0);
+            Point3::from_homogeneous(p)
+        };
+        let origin = unproject(-1.0);
+        (origin, (unproject(1.0) - origin).normalize())
+    }
+
+    /// Size of the view changed by `zoom`: the distance to the target in front of the camera, or
+    /// the height shown by an orthographic projection
+    pub fn zoom_scale(&self) -> f32 {
+        match self.projection {
+            Projection::Perspective => self.target_depth().max(MIN_TARGET_DISTANCE),
+            Projection::Orthographic { height } => height,
+        }
+    }
+
+    /// Distance of the target in front of the camera, along its direction
+    fn target_depth(&self) -> f32 {
+        (self.target - self.position).dot(self.direction.normalize())
+    }
+
+    /// Moves the camera `amount` forward, or narrows an orthographic view by `amount`. The camera
+    /// stops short of its target instead of passing through it.
     pub fn zoom(&mut self, amount: f32) {
-        self.position += self.direction.normalize() * amount;
+        match &mut self.projection {
+            Projection::Perspective => {
+                let amount = amount.min((self.target_depth() - MIN_TARGET_DISTANCE).max(0.0));
+                self.position += self.direction.normalize() * amount;
+            }
+            // Moving wouldn't change the size of anything
+            Projection::Orthographic { height } => *height = (*height - amount).max(0.01),
+        }
         self.reset_view_perspective();
     }
 
@@ -99,10 +279,84 @@ impl Camera {
         if let Some(perspective) = self.perspective.get() {
             perspective
         } else {
-            self.perspective.set(Some(
-                cgmath::perspective(self.fov, self.aspect_ratio, self.near, self.far).into(),
-            ));
+            let projection = match self.projection {
+                Projection::Perspective => {
+                    cgmath::perspective(self.fov, self.aspect_ratio, self.near, self.far)
+                }
+                Projection::Orthographic { height } => {
+                    let (half_width, half_height) =
+                        (0.5 * height * self.aspect_ratio, 0.5 * height);
+                    cgmath::ortho(
+                        -half_width,
+                        half_width,
+                        -half_height,
+                        half_height,
+                        self.near,
+                        self.far,
+                    )
+                }
+            };
+            self.perspective.set(Some(projection.into()));
             self.perspective.get().unwrap()
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WIDTH: u32 = 101;
+    const HEIGHT: u32 = 51;
+
+    fn camera() -> Camera {
+        Camera::new(
+            Point3::new(1.0, 2.0, 3.0),
+            Vector3::new(-1.0, -0.5, -2.0).normalize(),
+            Deg(60.0),
+            WIDTH as f32 / HEIGHT as f32,
+            0.1,
+            10.0,
+        )
+    }
+
+    fn assert_close(a: f32, b: f32) {
+        assert!((a - b).abs() < 1.0e-3, "{a} != {b}");
+    }
+
+    #[test]
+    fn center_pixel_ray_follows_the_camera_direction() {
+        let camera = camera();
+        let (origin, direction) = camera.ray_from_pixel(50.0, 25.0, WIDTH, HEIGHT);
+
+        assert_close(direction.angle(camera.direction()).0, 0.0);
+        // The ray starts on the near plane
+        assert_close((origin - camera.position()).magnitude(), camera.near());
+    }
+
+    #[test]
+    fn corner_pixel_rays_match_the_field_of_view() {
+        let camera = camera();
+        let up = camera.right().cross(camera.direction());
+        let tan_half_fov = (camera.fov() / 2.0).tan();
+
+        // The outer corner of the top left pixel
+        let (_, direction) = camera.ray_from_pixel(-0.5, -0.5, WIDTH, HEIGHT);
+        let forward = direction.dot(camera.direction());
+        assert_close(direction.dot(up) / forward, tan_half_fov);
+        assert_close(
+            direction.dot(camera.right()) / forward,
+            -tan_half_fov * camera.aspect_ratio(),
+        );
+
+        // The outer corner of the bottom right pixel
+        let (_, direction) =
+            camera.ray_from_pixel(WIDTH as f32 - 0.5, HEIGHT as f32 - 0.5, WIDTH, HEIGHT);
+        let forward = direction.dot(camera.direction());
+        assert_close(direction.dot(up) / forward, -tan_half_fov);
+        assert_close(
+            direction.dot(camera.right()) / forward,
+            tan_half_fov * camera.aspect_ratio(),
+        );
+    }
+}
@@ -0,0 +1,265 @@
+use glium::{
+    backend::Facade,
+    framebuffer::SimpleFrameBuffer,
+    texture::{
+        DepthFormat, DepthTexture2d, MipmapsOption, Texture2d, TextureCreationError,
+        UncompressedFloatFormat,
+    },
+    uniform, Blend, BlendingFunction, DrawParameters, IndexBuffer, LinearBlendingFactor, Program,
+    Surface, VertexBuffer,
+};
+
+use crate::PostProcessVert;
+
+/// Adds a premultiplied layer under the layers in front of it
+const UNDER_BLEND: Blend = Blend {
+    color: BlendingFunction::Addition {
+        source: LinearBlendingFactor::OneMinusDestinationAlpha,
+        destination: LinearBlendingFactor::One,
+    },
+    alpha: BlendingFunction::Addition {
+        source: LinearBlendingFactor::OneMinusDestinationAlpha,
+        destination: LinearBlendingFactor::One,
+    },
+    constant_value: (0.0, 0.0, 0.0, 0.0),
+};
+
+/// Draws premultiplied colors over the target
+const PREMULTIPLIED_OVER_BLEND: Blend = Blend {
+    color: BlendingFunction::Addition {
+        source: LinearBlendingFactor::One,
+        destination: LinearBlendingFactor::OneMinusSourceAlpha,
+    },
+    alpha: BlendingFunction::Addition {
+        source: LinearBlendingFactor::One,
+        destination: LinearBlendingFactor::OneMinusSourceAlpha,
+    },
+    constant_value: (0.0, 0.0, 0.0, 0.0),
+};
+
+/// Offscreen buffers used to peel the strokes into layers front to back
+pub struct DepthPeelTargets {
+    /// Color of the layer currently being peeled
+    layer: Texture2d,
+    /// Premultiplied color of all peeled layers so far
+    accum: Texture2d,
+    /// Depth of the current and previous layer, swapped after each peel
+    depth: [DepthTexture2d; 2],
+}
+
+impl DepthPeelTargets {
+    pub fn new(
+        display: &impl Facade,
+        width: u32,
+        height: u32,
+    ) -> Result<Self, TextureCreationError> {
+        let color = || {
+            Texture2d::empty_with_format(
+                display,
+                UncompressedFloatFormat::F16F16F16F16,
+                MipmapsOption::NoMipmap,
+                width,
+                height,
+            )
+        };
+        let depth = || {
+            DepthTexture2d::empty_with_format(
+                display,
+                DepthFormat::F32,
+                MipmapsOption::NoMipmap,
+                width,
+                height,
+            )
+        };
+        Ok(Self {
+            layer: color()?,
+            accum: color()?,
+            depth: [depth()?, depth()?],
+        })
+    }
+
+    pub fn dimensions(&self) -> (u32, u32) {
+        (self.layer.width(), self.layer.height())
+    }
+}
+
+/// Draws `layers` depth peeled layers of strokes and composites them over `target`.
+///
+/// `draw_layer` is called once per layer with the framebuffer to draw into and the depth of the
+/// previous layer; it must discard fragments at or in front of that depth.
+pub fn draw_depth_peeled(
+    display: &impl Facade,
+    targets: &DepthPeelTargets,
+    target: &mut impl Surface,
+    layers: u32,
+    quad: &(VertexBuffer<PostProcessVert>, IndexBuffer<u8>),
+    composite_program: &Program,
+    mut draw_layer: impl FnMut(&mut SimpleFrameBuffer, &DepthTexture2d),
+) {
+    SimpleFrameBuffer::new(display, &targets.accum)
+        .unwrap()
+        .clear_color(0.0, 0.0, 0.0, 0.0);
+    // Nothing is in front of the first layer
+    SimpleFrameBuffer::depth_only(display, &targets.depth[1])
+        .unwrap()
+        .clear_depth(0.0);
+
+    for i in 0..layers as usize {
+        let depth = &targets.depth[i % 2];
+        let previous_depth = &targets.depth[(i + 1) % 2];
+
+        {
+            let mut layer =
+                SimpleFrameBuffer::with_depth_buffer(display, &targets.layer, depth).unwrap();
+            layer.clear_color_and_depth((0.0, 0.0, 0.0, 0.0), 1.0);
+            draw_layer(&mut layer, previous_depth);
+        }
+
+        // Add the layer under everything peeled so far
+        let mut accum = SimpleFrameBuffer::new(display, &targets.accum).unwrap();
+        accum
+            .draw(
+                &quad.0,
+                &quad.1,
+                composite_program,
+                &uniform! {
+                    layer_texture: &targets.layer,
+                    premultiply: true,
+                },
+                &DrawParameters {
+                    blend: UNDER_BLEND,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+    }
+
+    // Composite the premultiplied layers over what is already in the target
+    target
+        .draw(
+            &quad.0,
+            &quad.1,
+            composite_program,
+            &uniform! {
+                layer_texture: &targets.accum,
+                premultiply: false,
+            },
+            &DrawParameters {
+                blend: PREMULTIPLIED_OVER_BLEND,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Fragments more transparent than this are discarded while peeling, as in point.frag
+    const PEEL_ALPHA_CUTOFF: f32 = 0.05;
+
+    /// The result of blending `src` onto `dst` with `blend` on the GPU
+    fn apply(blend: &Blend, src: [f32; 4], dst: [f32; 4]) -> [f32; 4] {
+        let factor = |factor: &LinearBlendingFactor, i: usize| match factor {
+            LinearBlendingFactor::Zero => 0.0,
+            LinearBlendingFactor::One => 1.0,
+            LinearBlendingFactor::SourceAlpha => src[3],
+            LinearBlendingFactor::OneMinusSourceAlpha => 1.0 - src[3],
+            LinearBlendingFactor::OneMinusDestinationAlpha => 1.0 - dst[3],
+            LinearBlendingFactor::SourceColor => src[i],
+            factor => panic!("unsupported blend factor {factor:?}"),
+        };
+        let mut out = [0.0; 4];
+        for (i, out) in out.iter_mut().enumerate() {
+            let function = if i < 3 { &blend.color } else { &blend.alpha };
+            let BlendingFunction::Addition {
+                source,
+                destination,
+            } = function
+            else {
+                panic!("unsupported blend function {function:?}")
+            };
+            *out = src[i] * factor(source, i) + dst[i] * factor(destination, i);
+        }
+        out
+    }
+
+    /// One pixel of `fragments` (depth, straight alpha color) drawn back to front with alpha
+    /// blending, like the sorted strokes
+    fn sorted(fragments: &[(f32, [f32; 4])], background: [f32; 4]) -> [f32; 4] {
+        let mut fragments = fragments.to_vec();
+        fragments.sort_by(|a, b| b.0.total_cmp(&a.0));
+        fragments.iter().fold(background, |dst, &(_, src)| {
+            apply(&Blend::alpha_blending(), src, dst)
+        })
+    }
+
+    /// One pixel of `fragments` peeled into `layers` layers and composited like
+    /// `draw_depth_peeled`
+    fn peeled(fragments: &[(f32, [f32; 4])], background: [f32; 4], layers: u32) -> [f32; 4] {
+        let mut accum = [0.0; 4];
+        let mut previous_depth = 0.0;
+        for _ in 0..layers {
+            // The nearest fragment behind the previous layer wins the depth test
+            let Some(&(depth, [r, g, b, a])) = fragments
+                .iter()
+                .filter(|(depth, color)| *depth > previous_depth && color[3] >= PEEL_ALPHA_CUTOFF)
+                .min_by(|a, b| a.0.total_cmp(&b.0))
+            else {
+                break;
+            };
+            previous_depth = depth;
+            // peel_composite.frag premultiplies the layer
+            accum = apply(&UNDER_BLEND, [r * a, g * a, b * a, a], accum);
+        }
+        apply(&PREMULTIPLIED_OVER_BLEND, accum, background)
+    }
+
+    fn assert_close(a: [f32; 4], b: [f32; 4], tolerance: f32) {
+        assert!(
+            a.iter()
+                .zip(&b)
+                .take(3)
+                .all(|(a, b)| (a - b).abs() <= tolerance),
+            "{a:?} != {b:?}"
+        );
+    }
+
+    #[test]
+    fn peeling_matches_sorting() {
+        let background = [0.9, 0.85, 0.8, 1.0];
+        let fragments = [
+            (0.5, [0.8, 0.1, 0.1, 0.6]),
+            (0.2, [0.1, 0.7, 0.2, 0.3]),
+            (0.8, [0.1, 0.2, 0.9, 0.9]),
+            (0.35, [0.5, 0.5, 0.1, 0.5]),
+        ];
+
+        let reference = sorted(&fragments, background);
+        assert_close(peeled(&fragments, background, 4), reference, 1e-5);
+        // Extra layers find nothing left to peel
+        assert_close(peeled(&fragments, background, 8), reference, 1e-5);
+    }
+
+    #[test]
+    fn peeling_drops_only_nearly_transparent_fragments() {
+        let background = [0.2, 0.2, 0.2, 1.0];
+        let fragments = [(0.3, [1.0, 1.0, 1.0, 0.04]), (0.6, [0.9, 0.3, 0.1, 0.7])];
+
+        assert_close(
+            peeled(&fragments, background, 2),
+            sorted(&fragments, background),
+            PEEL_ALPHA_CUTOFF,
+        );
+    }
+
+    #[test]
+    fn too_few_layers_lose_the_back_strokes() {
+        let background = [0.0, 0.0, 0.0, 1.0];
+        let fragments = [(0.2, [1.0, 0.0, 0.0, 0.5]), (0.7, [0.0, 1.0, 0.0, 0.5])];
+
+        let color = peeled(&fragments, background, 1);
+        assert_close(color, [0.5, 0.0, 0.0, 1.0], 1e-5);
+    }
+}
@@ -0,0 +1,37 @@
+use image::RgbImage;
+
+/// Scale applied to the differences so that small changes are visible
+const AMPLIFY: f32 = 8.0;
+
+/// Per channel differences between two images, in the 0 to 1 range
+#[derive(Debug, Copy, Clone)]
+pub struct DiffStats {
+    pub mean: f32,
+    pub max: f32,
+}
+
+/// Amplified absolute difference of two images of the same size
+pub fn diff_images(a: &RgbImage, b: &RgbImage) -> (RgbImage, DiffStats) {
+    assert_eq!(a.dimensions(), b.dimensions());
+
+    let mut sum = 0.0_f64;
+    let mut max: f32 = 0.0;
+
+    let image = RgbImage::from_fn(a.width(), a.height(), |x, y| {
+        let (a, b) = (a.get_pixel(x, y), b.get_pixel(x, y));
+        let mut pixel = [0; 3];
+        for (c, out) in pixel.iter_mut().enumerate() {
+            let delta = (a.0[c] as f32 - b.0[c] as f32).abs() / 255.0;
+            sum += delta as f64;
+            max = max.max(delta);
+            *out = ((delta * AMPLIFY).min(1.0) * 255.0).round() as u8;
+        }
+        image::Rgb(pixel)
+    });
+
+    let stats = DiffStats {
+        mean: (sum / (a.width() * a.height() * 3) as f64) as f32,
+        max,
+    };
+    (image, stats)
+}
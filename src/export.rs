@@ -0,0 +1,221 @@
+use std::path::Path;
+
+use image::{ImageResult, RgbImage, Rgba32FImage};
+use serde::Serialize;
+
+/// Filter used when downsampling a supersampled frame
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize)]
+pub enum ResolveFilter {
+    /// Averages each `supersample x supersample` block
+    Box,
+    /// Triangle filter twice as wide as a block, softer than `Box`
+    Tent,
+}
+
+impl ResolveFilter {
+    pub const ALL: [ResolveFilter; 2] = [ResolveFilter::Box, ResolveFilter::Tent];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            ResolveFilter::Box => "Box",
+            ResolveFilter::Tent => "Tent",
+        }
+    }
+
+    fn weight(self, distance: f32, supersample: f32) -> f32 {
+        match self {
+            ResolveFilter::Box => {
+                if distance < supersample / 2.0 {
+                    1.0
+                } else {
+                    0.0
+                }
+            }
+            ResolveFilter::Tent => (1.0 - distance / supersample).max(0.0),
+        }
+    }
+
+    fn radius(self, supersample: f32) -> f32 {
+        match self {
+            ResolveFilter::Box => supersample / 2.0,
+            ResolveFilter::Tent => supersample,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Serialize)]
+pub struct ExportSettings {
+    pub supersample: u32,
+    pub filter: ResolveFilter,
+}
+
+impl Default for ExportSettings {
+    fn default() -> Self {
+        Self {
+            supersample: 1,
+            filter: ResolveFilter::Box,
+        }
+    }
+}
+
+/// Encodes a linear color channel with the sRGB transfer function
+pub fn linear_to_srgb(c: f32) -> u8 {
+    let c = c.clamp(0.0, 1.0);
+    let c = if c <= 0.0031308 {
+        c * 12.92
+    } else {
+        1.055 * c.powf(1.0 / 2.4) - 0.055
+    };
+    (c * 255.0).round() as u8
+}
+
+/// Downsamples a linear RGBA float frame by `settings.supersample` and writes it to `path`
+pub fn save_linear_frame(
+    pixels: &[f32],
+    width: u32,
+    height: u32,
+    settings: ExportSettings,
+    path: impl AsRef<Path>,
+) -> ImageResult<()> {
+    resolve_linear_frame(pixels, width, height, settings).save(path)
+}
+
+/// Downsamples a linear RGBA float frame by `settings.supersample` into an sRGB image.
+///
+/// `pixels` is laid out the way OpenGL reads it back: rows from bottom to top. Filtering happens
+/// in linear space and the result is only sRGB encoded once, at the end.
+pub fn resolve_linear_frame(
+    pixels: &[f32],
+    width: u32,
+    height: u32,
+    settings: ExportSettings,
+) -> RgbImage {
+    let supersample = settings.supersample.max(1);
+    let out_width = width / supersample;
+    let out_height = height / supersample;
+
+    let ss = supersample as f32;
+    let radius = settings.filter.radius(ss);
+
+    let mut image = RgbImage::new(out_width, out_height);
+
+    for (x, y, pixel) in image.enumerate_pixels_mut() {
+        // Flip so that the first row of the image is the top of the frame
+        let center_x = (x as f32 + 0.5) * ss;
+        let center_y = ((out_height - 1 - y) as f32 + 0.5) * ss;
+
+        let min_x = (center_x - radius).floor().max(0.0) as u32;
+        let max_x = ((center_x + radius).ceil() as u32).min(width);
+        let min_y = (center_y - radius).floor().max(0.0) as u32;
+        let max_y = ((center_y + radius).ceil() as u32).min(height);
+
+        let mut sum = [0.0; 3];
+        let mut total_weight = 0.0;
+
+        for sy in min_y..max_y {
+            let wy = settings
+                .filter
+                .weight((sy as f32 + 0.5 - center_y).abs(), ss);
+            for sx in min_x..max_x {
+                let wx = settings
+                    .filter
+                    .weight((sx as f32 + 0.5 - center_x).abs(), ss);
+                let weight = wx * wy;
+                if weight == 0.0 {
+                    continue;
+                }
+                let i = ((sy * width + sx) * 4) as usize;
+                for (c, sum) in sum.iter_mut().enumerate() {
+                    *sum += pixels[i + c] * weight;
+                }
+                total_weight += weight;
+            }
+        }
+
+        for (c, sum) in sum.iter().enumerate() {
+            pixel.0[c] = linear_to_srgb(sum / total_weight);
+        }
+    }
+
+    image
+}
+
+/// Writes a linear RGBA float frame to `path` as an OpenEXR image without any encoding.
+///
+/// `pixels` is laid out the way OpenGL reads it back: rows from bottom to top.
+pub fn save_linear_exr(
+    pixels: &[f32],
+    width: u32,
+    height: u32,
+    path: impl AsRef<Path>,
+) -> ImageResult<()> {
+    let image = Rgba32FImage::from_fn(width, height, |x, y| {
+        let i = (((height - 1 - y) * width + x) * 4) as usize;
+        image::Rgba([pixels[i], pixels[i + 1], pixels[i + 2], pixels[i + 3]])
+    });
+    image.save(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A linear RGBA frame with every pixel set by `f(x, y)`
+    fn frame(width: u32, height: u32, f: impl Fn(u32, u32) -> f32) -> Vec<f32> {
+        (0..height)
+            .flat_map(|y| (0..width).map(move |x| (x, y)))
+            .flat_map(|(x, y)| {
+                let c = f(x, y);
+                [c, c, c, 1.0]
+            })
+            .collect()
+    }
+
+    #[test]
+    fn half_gray_is_the_srgb_midpoint() {
+        let pixels = frame(4, 4, |_, _| 0.5);
+        let image = resolve_linear_frame(&pixels, 4, 4, ExportSettings::default());
+        // 0.5 linear encodes to 0.735 sRGB, not 128
+        assert!(image.pixels().all(|p| p.0 == [188, 188, 188]));
+    }
+
+    #[test]
+    fn supersampled_checkerboard_resolves_in_linear_space() {
+        // Averaging black and white gives half the light, which a gamma-wrong average of the
+        // encoded values would turn into 128
+        let pixels = frame(16, 16, |x, y| ((x + y) % 2) as f32);
+        for filter in ResolveFilter::ALL {
+            let settings = ExportSettings {
+                supersample: 2,
+                filter,
+            };
+            let image = resolve_linear_frame(&pixels, 16, 16, settings);
+            assert_eq!(image.dimensions(), (8, 8));
+            // The tent filter is cut off at the edges of the frame
+            for (x, y, p) in image.enumerate_pixels() {
+                if (1..7).contains(&x) && (1..7).contains(&y) {
+                    assert!(
+                        p.0.iter().all(|&c| c.abs_diff(188) <= 1),
+                        "{filter:?}: {p:?}"
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn exr_round_trips_linear_values() {
+        // Above 1 and below the smallest 8-bit step, which only a float format keeps. The
+        // bottom row is read back first.
+        let pixels = frame(3, 2, |x, y| if y == 0 { 3.25 } else { 0.001 * x as f32 });
+        let path =
+            std::env::temp_dir().join(format!("paint_render_test_{}.exr", std::process::id()));
+        save_linear_exr(&pixels, 3, 2, &path).unwrap();
+        let image = image::open(&path).unwrap().into_rgba32f();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(image.dimensions(), (3, 2));
+        assert_eq!(image.get_pixel(1, 1).0, [3.25, 3.25, 3.25, 1.0]);
+        assert_eq!(image.get_pixel(2, 0).0, [0.002, 0.002, 0.002, 1.0]);
+    }
+}
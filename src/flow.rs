@@ -0,0 +1,121 @@
+use cgmath::{prelude::*, Vector2};
+use image::{imageops, GrayImage, RgbaImage};
+
+/// Images larger than this are downscaled before computing their flow, which only needs the
+/// large scale features
+const MAX_SIZE: u32 = 1024;
+
+/// Direction of the image features of a texture, in uv space, so strokes can follow them
+#[derive(Debug, Clone)]
+pub struct FlowField {
+    width: u32,
+    height: u32,
+    /// Unit directions, or zero where the image is flat, rows from top to bottom
+    directions: Vec<Vector2<f32>>,
+}
+
+impl FlowField {
+    /// Flow along the edges of `image`, from its smoothed structure tensor. `smoothing` is the
+    /// blur radius in pixels of the downscaled image, larger values give smoother flow.
+    pub fn from_image(image: &RgbaImage, smoothing: u32) -> Self {
+        let mut luma: GrayImage = imageops::grayscale(image);
+        if luma.width() > MAX_SIZE || luma.height() > MAX_SIZE {
+            let scale = MAX_SIZE as f32 / luma.width().max(luma.height()) as f32;
+            luma = imageops::resize(
+                &luma,
+                ((luma.width() as f32 * scale) as u32).max(1),
+                ((luma.height() as f32 * scale) as u32).max(1),
+                imageops::FilterType::Triangle,
+            );
+        }
+        let (width, height) = luma.dimensions();
+
+        let at = |x: i64, y: i64| {
+            let x = x.clamp(0, width as i64 - 1) as u32;
+            let y = y.clamp(0, height as i64 - 1) as u32;
+            luma.get_pixel(x, y).0[0] as f32 / 255.0
+        };
+
+        // Sobel gradients, as the structure tensor [xx, xy, yy] of each pixel
+        let mut tensor = Vec::with_capacity((width * height) as usize);
+        for y in 0..height as i64 {
+            for x in 0..width as i64 {
+                let gx = (at(x + 1, y - 1) + 2.0 * at(x + 1, y) + at(x + 1, y + 1))
+                    - (at(x - 1, y - 1) + 2.0 * at(x - 1, y) + at(x - 1, y + 1));
+                let gy = (at(x - 1, y + 1) + 2.0 * at(x, y + 1) + at(x + 1, y + 1))
+                    - (at(x - 1, y - 1) + 2.0 * at(x, y - 1) + at(x + 1, y - 1));
+                tensor.push([gx * gx, gx * gy, gy * gy]);
+            }
+        }
+
+        // Averaging the tensor (unlike the gradient) keeps opposite gradients of the two sides
+        // of a line from canceling out
+        let tensor = box_blur(&tensor, width as usize, height as usize, smoothing as usize);
+
+        let directions = tensor
+            .iter()
+            .map(|&[xx, xy, yy]| {
+                if xx + yy < 1.0e-6 {
+                    return Vector2::zero();
+                }
+                // The gradient is at `angle`, the edge runs perpendicular to it
+                let angle = 0.5 * (2.0 * xy).atan2(xx - yy);
+                // Image rows go down while v goes up
+                Vector2::new(-angle.sin(), -angle.cos())
+            })
+            .collect();
+
+        Self {
+            width,
+            height,
+            directions,
+        }
+    }
+
+    /// Direction at `uv` with nearest filtering, zero where the image is flat
+    pub fn sample(&self, uv: Vector2<f32>) -> Vector2<f32> {
+        let x = (uv.x.rem_euclid(1.0) * self.width as f32) as u32;
+        let y = ((1.0 - uv.y.rem_euclid(1.0)) * self.height as f32) as u32;
+        let x = x.min(self.width - 1);
+        let y = y.min(self.height - 1);
+        self.directions[(y * self.width + x) as usize]
+    }
+}
+
+/// Separable box blur of `radius` pixels, clamping at the edges
+fn box_blur(values: &[[f32; 3]], width: usize, height: usize, radius: usize) -> Vec<[f32; 3]> {
+    if radius == 0 {
+        return values.to_vec();
+    }
+
+    let blur_line =
+        |get: &dyn Fn(usize) -> [f32; 3], len: usize, out: &mut dyn FnMut(usize, [f32; 3])| {
+            for i in 0..len {
+                let (start, end) = (i.saturating_sub(radius), (i + radius).min(len - 1));
+                let mut sum = [0.0; 3];
+                for j in start..=end {
+                    let value = get(j);
+                    for c in 0..3 {
+                        sum[c] += value[c];
+                    }
+                }
+                let n = (end - start + 1) as f32;
+                out(i, sum.map(|s| s / n));
+            }
+        };
+
+    let mut horizontal = vec![[0.0; 3]; values.len()];
+    for y in 0..height {
+        blur_line(&|x| values[y * width + x], width, &mut |x, v| {
+            horizontal[y * width + x] = v
+        });
+    }
+
+    let mut blurred = vec![[0.0; 3]; values.len()];
+    for x in 0..width {
+        blur_line(&|y| horizontal[y * width + x], height, &mut |y, v| {
+            blurred[y * width + x] = v
+        });
+    }
+    blurred
+}
@@ -0,0 +1,173 @@
+use std::path::{Path, PathBuf};
+
+use cgmath::{prelude::*, Matrix3, Matrix4, Point3, Vector3};
+use gltf::{buffer::Source, mesh::Mode, Gltf, Node};
+use log::warn;
+use tobj::{Mesh, Model};
+
+/// Loads the triangle primitives of a glTF or GLB file as shapes like those of an OBJ file.
+///
+/// Each primitive of a mesh becomes a shape named after its node (or mesh), with a `.<index>`
+/// suffix when the mesh has several primitives. The node transforms are applied to the vertices,
+/// so the shapes are placed as in the file's default scene. Normals and uvs are left empty when
+/// the primitive has none, and the uvs are flipped to the bottom-left origin of OBJ files.
+pub fn load_gltf(path: &Path, uv_channel: usize) -> Result<Vec<Model>, gltf::Error> {
+    let Gltf { document, blob } = Gltf::open(path)?;
+    let buffers = gltf::import_buffers(&document, path.parent(), blob)?;
+
+    let mut nodes = vec![];
+    match document
+        .default_scene()
+        .or_else(|| document.scenes().next())
+    {
+        Some(scene) => {
+            for node in scene.nodes() {
+                collect_nodes(node, Matrix4::identity(), &mut nodes);
+            }
+        }
+        // Without a scene the meshes are taken as they are
+        None => nodes.extend(
+            document
+                .meshes()
+                .map(|mesh| (mesh_name(&mesh), mesh, Matrix4::identity())),
+        ),
+    }
+
+    let mut missing_uv_channel = false;
+    let mut models = vec![];
+    for (name, mesh, transform) in nodes {
+        let normal_matrix = Matrix3::from_cols(
+            transform.x.truncate(),
+            transform.y.truncate(),
+            transform.z.truncate(),
+        );
+        // Mirroring transforms turn the triangles inside out
+        let flip_winding = normal_matrix.determinant() < 0.0;
+        let normal_matrix = normal_matrix
+            .invert()
+            .unwrap_or(Matrix3::identity())
+            .transpose();
+
+        let num_primitives = mesh.primitives().len();
+        for primitive in mesh.primitives() {
+            let name = if num_primitives > 1 {
+                format!("{name}.{}", primitive.index())
+            } else {
+                name.clone()
+            };
+            if primitive.mode() != Mode::Triangles {
+                warn!(
+                    "Skipping shape {name} of '{}', its primitive is {:?} rather than triangles",
+                    path.display(),
+                    primitive.mode()
+                );
+                continue;
+            }
+
+            let reader = primitive.reader(|buffer| Some(&buffers[buffer.index()]));
+            let Some(positions) = reader.read_positions() else {
+                warn!(
+                    "Skipping shape {name} of '{}', it has no positions",
+                    path.display()
+                );
+                continue;
+            };
+            let positions = positions
+                .flat_map(|p| {
+                    let p = transform.transform_point(Point3::from(p));
+                    [p.x, p.y, p.z]
+                })
+                .collect::<Vec<_>>();
+            let num_vertices = positions.len() / 3;
+
+            let normals = reader.read_normals().map_or(vec![], |normals| {
+                normals
+                    .flat_map(|n| {
+                        let n = (normal_matrix * Vector3::from(n)).normalize();
+                        [n.x, n.y, n.z]
+                    })
+                    .collect()
+            });
+
+            let tex_coords = match reader.read_tex_coords(uv_channel as u32) {
+                Some(tex_coords) => Some(tex_coords),
+                None if uv_channel != 0 => {
+                    missing_uv_channel = true;
+                    reader.read_tex_coords(0)
+                }
+                None => None,
+            };
+            let texcoords = tex_coords.map_or(vec![], |tex_coords| {
+                tex_coords
+                    .into_f32()
+                    .flat_map(|[u, v]| [u, 1.0 - v])
+                    .collect()
+            });
+
+            let mut indices = match reader.read_indices() {
+                Some(indices) => indices.into_u32().collect::<Vec<_>>(),
+                None => (0..num_vertices as u32).collect(),
+            };
+            indices.truncate(indices.len() / 3 * 3);
+            if flip_winding {
+                for triangle in indices.chunks_exact_mut(3) {
+                    triangle.swap(1, 2);
+                }
+            }
+
+            let mesh = Mesh {
+                positions,
+                normals,
+                texcoords,
+                indices,
+                ..Mesh::default()
+            };
+            models.push(Model::new(mesh, name));
+        }
+    }
+
+    if missing_uv_channel {
+        warn!(
+            "uv channel {uv_channel} does not exist in every shape of '{}', using channel 0",
+            path.display()
+        );
+    }
+
+    Ok(models)
+}
+
+/// Paths of the external buffers the meshes of the glTF file at `path` are loaded from. Buffers
+/// in a GLB file's binary chunk or in data URIs are part of the file and aren't included.
+pub fn buffer_paths(path: &Path) -> Result<Vec<PathBuf>, gltf::Error> {
+    let Gltf { document, .. } = Gltf::open(path)?;
+    let dir = path.parent().unwrap_or(Path::new(""));
+    Ok(document
+        .buffers()
+        .filter_map(|buffer| match buffer.source() {
+            Source::Uri(uri) if !uri.starts_with("data:") => Some(dir.join(uri)),
+            _ => None,
+        })
+        .collect())
+}
+
+/// Appends every node with a mesh under `node`, with its name and transform into the scene
+fn collect_nodes<'a>(
+    node: Node<'a>,
+    parent: Matrix4<f32>,
+    nodes: &mut Vec<(String, gltf::Mesh<'a>, Matrix4<f32>)>,
+) {
+    let transform = parent * Matrix4::from(node.transform().matrix());
+    if let Some(mesh) = node.mesh() {
+        let name = node.name().map_or_else(|| mesh_name(&mesh), str::to_string);
+        nodes.push((name, mesh, transform));
+    }
+    for child in node.children() {
+        collect_nodes(child, transform, nodes);
+    }
+}
+
+/// Name of `mesh`, or its index when it has none
+fn mesh_name(mesh: &gltf::Mesh) -> String {
+    mesh.name()
+        .map_or_else(|| format!("mesh{}", mesh.index()), str::to_string)
+}
@@ -0,0 +1,55 @@
+use cgmath::{Deg, Matrix4};
+use glium::{backend::Facade, implement_vertex, VertexBuffer};
+use serde::{Deserialize, Serialize};
+
+/// A copy of the scene's models declared with `[[instance]]`, also used to place the objects of
+/// `obj_file`
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+pub struct InstanceDesc {
+    position: Option<[f32; 3]>,
+    /// Euler angles in degrees, applied in x, y, z order
+    rotation: Option<[f32; 3]>,
+    scale: Option<[f32; 3]>,
+}
+
+impl InstanceDesc {
+    pub fn transform(&self) -> Matrix4<f32> {
+        let [x, y, z] = self.position.unwrap_or([0.0; 3]);
+        let [rx, ry, rz] = self.rotation.unwrap_or([0.0; 3]);
+        let [sx, sy, sz] = self.scale.unwrap_or([1.0; 3]);
+        Matrix4::from_translation([x, y, z].into())
+            * Matrix4::from_angle_z(Deg(rz))
+            * Matrix4::from_angle_y(Deg(ry))
+            * Matrix4::from_angle_x(Deg(rx))
+            * Matrix4::from_nonuniform_scale(sx, sy, sz)
+    }
+}
+
+/// Per-instance vertex attribute
+#[derive(Copy, Clone, Debug)]
+pub struct Instance {
+    pub instance_model: [[f32; 4]; 4],
+}
+implement_vertex!(Instance, instance_model);
+
+/// Transforms of all instances, a single identity transform if the scene declares none
+pub fn instance_transforms(descs: &[InstanceDesc]) -> Vec<Matrix4<f32>> {
+    if descs.is_empty() {
+        vec![Matrix4::from_scale(1.0)]
+    } else {
+        descs.iter().map(InstanceDesc::transform).collect()
+    }
+}
+
+pub fn gen_instance_buffer(
+    display: &impl Facade,
+    transforms: &[Matrix4<f32>],
+) -> VertexBuffer<Instance> {
+    let instances = transforms
+        .iter()
+        .map(|&transform| Instance {
+            instance_model: transform.into(),
+        })
+        .collect::<Vec<_>>();
+    VertexBuffer::new(display, &instances).unwrap()
+}
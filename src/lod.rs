@@ -0,0 +1,133 @@
+//! Adaptive density: the strokes of objects far from the camera are thinned out so the painting
+//! keeps about the same stroke density on screen. Objects go through discrete levels, each drawing
+//! half the strokes of the level before, picked by the per-point `Point::lod` value.
+
+use cgmath::{prelude::*, Matrix4, Point3};
+
+use crate::point_gen::Point;
+
+/// Coarsest level, which draws a sixteenth of the strokes
+const MAX_LOD_LEVEL: u32 = 4;
+
+/// How far past a level boundary, in levels, an object moves before its level changes, so the
+/// strokes aren't thinned out again every frame while the camera hovers around a boundary
+const LOD_HYSTERESIS: f32 = 0.25;
+
+/// Level of an object at `distance` from the camera that was at level `last`. Projected area
+/// shrinks with the square of the distance, so every level starts `sqrt(2)` times further than
+/// the one before. A `target_coverage` of 1 keeps every stroke up to `reference_distance`, and
+/// each halving of it starts thinning out one level closer.
+pub fn lod_level(last: u32, distance: f32, reference_distance: f32, target_coverage: f32) -> u32 {
+    let level = 2.0 * (distance / reference_distance).log2() - target_coverage.log2();
+    if level < last as f32 - LOD_HYSTERESIS || level > (last + 1) as f32 + LOD_HYSTERESIS {
+        (level.floor().max(0.0) as u32).min(MAX_LOD_LEVEL)
+    } else {
+        last
+    }
+}
+
+/// Level of every object for the camera at `eye`, from the distance to its nearest instance.
+/// `centers` are the objects' `center`s, `last` their levels before, if they had any.
+#[allow(clippy::too_many_arguments)]
+pub fn object_levels(
+    centers: &[Option<Point3<f32>>],
+    transforms: &[Matrix4<f32>],
+    instances: &[Matrix4<f32>],
+    model: Matrix4<f32>,
+    eye: Point3<f32>,
+    reference_distance: f32,
+    target_coverage: f32,
+    last: Option<&[u32]>,
+) -> Vec<u32> {
+    centers
+        .iter()
+        .zip(transforms)
+        .enumerate()
+        .map(|(i, (center, &transform))| {
+            let last = last.map_or(0, |levels| levels[i]);
+            center.map_or(last, |center| {
+                // The nearest instance needs the most strokes
+                let distance = instances
+                    .iter()
+                    .map(|&instance| {
+                        eye.distance((model * instance * transform).transform_point(center))
+                    })
+                    .fold(f32::INFINITY, f32::min);
+                lod_level(last, distance, reference_distance, target_coverage)
+            })
+        })
+        .collect()
+}
+
+/// The points drawn at `level`. A point is kept at every level up to the coarsest that keeps it,
+/// so strokes don't pop in and out as the level changes.
+pub fn lod_points(points: &[Point], level: u32) -> Vec<Point> {
+    if level == 0 {
+        return points.to_vec();
+    }
+    let keep = 0.5f32.powi(level as i32);
+    points.iter().filter(|p| p.lod < keep).copied().collect()
+}
+
+/// Mean position of `points`, which the distance of their object is measured from
+pub fn center(points: &[Point]) -> Option<Point3<f32>> {
+    if points.is_empty() {
+        return None;
+    }
+    let sum = points
+        .iter()
+        .fold(Point3::origin(), |sum, p| sum + p.position().to_vec());
+    Some(sum / points.len() as f32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn levels_keep_nested_halves_of_the_points() {
+        let points = (0..4000)
+            .map(|i| {
+                Point::new(
+                    [i as f32, 0.0, 0.0],
+                    [0.0; 3],
+                    [0.0; 3],
+                    [0.0; 3],
+                    [0.0; 2],
+                    0,
+                )
+            })
+            .enumerate()
+            .map(|(i, p)| p.with_jitter(5, i as u64))
+            .collect::<Vec<_>>();
+
+        let mut last = points.clone();
+        for level in 1..=MAX_LOD_LEVEL {
+            let drawn = lod_points(&points, level);
+            let expected = points.len() as f32 * 0.5f32.powi(level as i32);
+            assert!(
+                (drawn.len() as f32 - expected).abs() < 0.1 * expected,
+                "level {level} draws {} of {}",
+                drawn.len(),
+                points.len()
+            );
+            // Coarser levels only drop points
+            assert!(drawn.iter().all(|p| last.contains(p)));
+            last = drawn;
+        }
+    }
+
+    #[test]
+    fn lower_coverage_thins_out_closer() {
+        // Every stroke up to the reference distance, a level per sqrt(2) further
+        assert_eq!(lod_level(0, 1.0, 1.0, 1.0), 0);
+        assert_eq!(lod_level(0, 1.6, 1.0, 1.0), 1);
+        assert_eq!(lod_level(0, 4.0, 1.0, 1.0), MAX_LOD_LEVEL);
+        // Half the coverage is a level further along
+        assert_eq!(lod_level(0, 1.2, 1.0, 1.0), 0);
+        assert_eq!(lod_level(0, 1.2, 1.0, 0.5), 1);
+        // Moving just past a boundary keeps the level
+        assert_eq!(lod_level(0, 1.45, 1.0, 1.0), 0);
+        assert_eq!(lod_level(1, 1.35, 1.0, 1.0), 1);
+    }
+}
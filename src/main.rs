@@ -1,79 +1,233 @@
+mod animation;
+mod bloom;
+mod brushes;
+mod bundle;
 mod camera;
+mod depth_peel;
+mod diff;
+mod export;
+mod flow;
+mod gltf_file;
+mod instances;
+mod lod;
+mod manifest;
 mod mesh;
 mod objects;
+mod oit;
+mod outline;
+mod picking;
+mod ply;
 mod point_gen;
+mod points_file;
 mod running_average;
+mod stats;
 
 use std::{
-    cmp::Reverse,
-    collections::HashSet,
-    fs,
-    io::Cursor,
+    collections::{BTreeMap, HashMap, HashSet},
+    env, fmt, fs,
+    io::{self, BufReader, Cursor, Read, Seek, SeekFrom},
+    panic,
     path::{Path, PathBuf},
+    process::exit,
     sync::{
         atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering},
-        mpsc::{channel, Receiver, Sender},
+        mpsc::{channel, Receiver, Sender, TryRecvError},
         Arc, Mutex,
     },
     thread,
-    time::{Duration, Instant},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
-use camera::Camera;
-use cgmath::{point3, prelude::*, vec4, Deg, Matrix4, Point3, Vector3, Vector4};
+use animation::Animation;
+use bloom::{draw_bloom, BloomTargets};
+use brushes::{BrushAtlas, BrushError};
+use bundle::Bundle;
+use camera::{Camera, Projection, DEFAULT_FOV};
+use cgmath::{point3, prelude::*, vec4, Deg, Matrix4, Point3, Rad, Vector3};
 use clap::Parser;
-use egui::{SidePanel, Slider};
+use depth_peel::{draw_depth_peeled, DepthPeelTargets};
+use egui::{Align2, Area, Color32, ComboBox, DragValue, Frame, ProgressBar, SidePanel, Slider};
 use egui_glium::EguiGlium;
 use glium::{
-    draw_parameters::DepthTest,
-    framebuffer::SimpleFrameBuffer,
+    backend::Facade,
+    draw_parameters::{DepthTest, PolygonOffset},
+    framebuffer::{SimpleFrameBuffer, ToColorAttachment},
     glutin::{
         dpi::PhysicalSize,
         event::{
-            ElementState, Event, MouseScrollDelta, StartCause, TouchPhase, VirtualKeyCode,
-            WindowEvent,
+            ElementState, Event, MouseButton, MouseScrollDelta, StartCause, TouchPhase,
+            VirtualKeyCode, WindowEvent,
         },
         event_loop::EventLoop,
-        window::WindowBuilder,
-        ContextBuilder,
+        window::{Fullscreen, WindowBuilder},
+        Context, ContextBuilder, CreationError, NotCurrent,
     },
     implement_vertex,
     index::PrimitiveType,
-    program::ProgramCreationInput,
-    texture::{CompressedSrgbTexture2d, SrgbTexture2d},
-    uniform, BackfaceCullingMode, Blend, Depth, Display, DrawParameters, IndexBuffer, Program,
-    Surface, VertexBuffer,
+    program::{ProgramCreationError, ProgramCreationInput},
+    texture::{
+        ClientFormat, CompressedSrgbTexture2d, DepthTexture2d, MipmapsOption, RawImage2d,
+        SrgbTexture2d, Texture2d, UncompressedFloatFormat,
+    },
+    uniform,
+    uniforms::{MagnifySamplerFilter, MinifySamplerFilter, SamplerWrapFunction},
+    BackfaceCullingMode, Blend, BlendingFunction, CapabilitiesSource, Depth, Display,
+    DrawParameters, HeadlessRenderer, IndexBuffer, LinearBlendingFactor, PolygonMode, Program,
+    Rect, Surface, VertexBuffer,
 };
 
-use image::{io::Reader as ImageReader, ImageBuffer, Rgba};
+use export::{ExportSettings, ResolveFilter};
+use flow::FlowField;
+use image::{
+    io::Reader as ImageReader, DynamicImage, ImageBuffer, ImageError, RgbImage, Rgba, RgbaImage,
+};
+use instances::{gen_instance_buffer, instance_transforms, Instance, InstanceDesc};
+use log::{error, info, warn};
+use manifest::{Asset, Manifest};
 use mesh::gen_point_buffers;
-use objects::{gen_models, ModelData};
-use point_gen::{gen_point_list, Point};
-use rayon::slice::ParallelSliceMut;
+use objects::{
+    area_scale, change_scene_density, deserialize_objects, gen_mesh_samplers, gen_model_data,
+    gen_scene_points, is_gltf, load_objects, scene_bounds, ModelData, ModelError, ObjectDesc,
+    SceneModel, ShapeOverride,
+};
+use oit::{draw_weighted_blended, OitPass, OitTargets};
+use outline::{draw_outline_geometry, OutlineTargets};
+use picking::Pick;
+use point_gen::{FlatColors, Point, Sampling, StrokeMaps, GENERATOR_VERSION};
+use points_file::{PointsError, PointsHeader};
+use rayon::{
+    iter::{IntoParallelIterator, IntoParallelRefIterator, ParallelIterator},
+    ThreadPoolBuilder,
+};
+use rfd::FileDialog;
 use running_average::RunningAverage;
-use serde::Deserialize;
-use tobj::Model;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use stats::{FrameStats, StatsLog};
 
 #[derive(Parser, Debug)]
 struct Args {
     /// The path to the obj file to view
     scene: PathBuf,
+    /// Show the frame rate and draw and sort times even without the side panel (toggled with O)
+    #[arg(long)]
+    fps_overlay: bool,
+    /// Write a JSON manifest describing every exported frame to this path
+    #[arg(long)]
+    manifest: Option<PathBuf>,
+    /// Render this scene and SCENE with the camera of SCENE, write their difference to `--output`
+    /// and exit
+    #[arg(long, value_name = "OTHER_SCENE")]
+    diff: Option<PathBuf>,
+    /// Render SCENE with each of these parameter presets, TOML files of scene settings that
+    /// replace the scene's, write their difference to `--output` and exit
+    #[arg(
+        long,
+        num_args = 2,
+        value_names = ["PRESET_A", "PRESET_B"],
+        conflicts_with = "diff"
+    )]
+    diff_presets: Option<Vec<PathBuf>>,
+    /// Use the strokes saved in this file instead of generating them
+    #[arg(long, value_name = "FILE")]
+    points: Option<PathBuf>,
+    /// Save the generated strokes to this file, to be loaded again with `--points`
+    #[arg(long, value_name = "FILE")]
+    save_points: Option<PathBuf>,
+    /// Write the generated strokes to this PLY file and exit without opening a window
+    #[arg(long, value_name = "FILE")]
+    dump_points: Option<PathBuf>,
+    /// Stroke density used instead of the scene's `stroke_density`
+    #[arg(long)]
+    density: Option<u32>,
+    /// Write the strokes, textures, camera and parameters to this directory as a bundle for a
+    /// WebGL viewer and exit
+    #[arg(long, value_name = "DIR")]
+    bundle: Option<PathBuf>,
+    /// Where to write the difference image of `--diff` (default `diff.png`). Without `--diff`,
+    /// renders one painted frame there and exits, or the linear paint layer at full float
+    /// precision for a `.exr` path
+    #[arg(long)]
+    output: Option<PathBuf>,
+    /// Width of the window, and of the frames rendered without showing it (default from the
+    /// scene's `[window]` table, or 1280)
+    #[arg(long)]
+    width: Option<u32>,
+    /// Height of the window, and of the frames rendered without showing it (default from the
+    /// scene's `[window]` table, or 720)
+    #[arg(long)]
+    height: Option<u32>,
+    /// Compare a render of SCENE to its reference in this directory and exit, with a nonzero exit
+    /// code if they differ
+    #[arg(long, value_name = "DIR")]
+    reference: Option<PathBuf>,
+    /// Write a new reference to the `--reference` directory instead of comparing
+    #[arg(long, requires = "reference")]
+    update_reference: bool,
+    /// Largest per channel difference (0 to 1) accepted by `--reference`
+    #[arg(long, requires = "reference", default_value_t = 0.02)]
+    tolerance: f32,
+    /// Drop to `--idle-fps` after this many seconds without input
+    #[arg(long, value_name = "SECONDS")]
+    idle_timeout: Option<f32>,
+    /// Frame rate while idle, 0 only redraws on input
+    #[arg(long, value_name = "FPS", default_value_t = 2.0)]
+    idle_fps: f32,
+    /// Write this many frames of a full turn of the model to the `--output` directory and exit
+    #[arg(long, value_name = "N", requires = "output")]
+    turntable_frames: Option<u32>,
+    /// Number of threads used to sort the strokes (defaults to one per core)
+    #[arg(long, value_name = "N")]
+    sort_threads: Option<usize>,
+    /// Always generate the strokes instead of loading them from the cache of earlier runs
+    #[arg(long)]
+    no_cache: bool,
+    /// Append the draw, sort and fixed update times, frame time and stroke count of every frame
+    /// to this CSV file
+    #[arg(long, value_name = "FILE")]
+    stats_csv: Option<PathBuf>,
 }
 
-const BRUSHES_PNG: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/brushes.png"));
+/// Directory in the system temporary directory generated strokes are cached in
+const POINTS_CACHE_DIR: &str = "paint_render_points";
+
+/// Number of cached stroke files kept, the least recently used are removed past it
+const POINTS_CACHE_FILES: usize = 16;
+
+/// Camera depth range when the scene doesn't set one
+const DEFAULT_NEAR: f32 = 0.1;
+const DEFAULT_FAR: f32 = 10.0;
+
+/// Speed of the `WASD`/`QE` camera movement in world units per second
+const MOVE_SPEED: f32 = 1.5;
+/// Speed of the up/down arrow zoom, as a fraction of the distance to the camera target per second
+const ZOOM_SPEED: f32 = 1.0;
+/// Time in seconds the zoom takes to speed up or slow down most of the way with smooth zoom
+const ZOOM_EASING: f32 = 0.15;
+/// Turntable speed in degrees per second when the scene doesn't set one
+const DEFAULT_TURNTABLE_SPEED: f32 = 30.0;
+
+/// Camera rotation relative to the model, in degrees, before the strokes are sorted again when
+/// the scene doesn't set one
+const DEFAULT_SORT_ANGLE_THRESHOLD: f32 = 0.5;
+/// Camera movement relative to the model, as a fraction of its distance to the model, before the
+/// strokes are sorted again when the scene doesn't set one
+const DEFAULT_SORT_DISTANCE_THRESHOLD: f32 = 0.01;
+
+/// Cursor distance in pixels within which a left button press and release picks instead of orbiting
+const CLICK_DISTANCE: f32 = 4.0;
+
+/// How long a toast stays on screen, fading out over its last half
+const TOAST_DURATION: Duration = Duration::from_millis(1500);
+
 const CANVAS_PNG: &[u8] = include_bytes!("../res/textures/postprocess/canvas.png");
 
 mod shaders {
 
+    /// Concatenates shader sources after the version directive, so shaders can share snippets
     macro_rules! include_shader {
-        ($file: expr) => {
-            concat!(
-                "#version 330\n",
-                "#define PR_NUM_BRUSHES ",
-                env!("PR_NUM_BRUSHES"),
-                "\n",
-                include_str!($file)
-            )
+        ($($file: expr),+) => {
+            concat!("#version 330\n", $(include_str!($file)),+)
         };
     }
 
@@ -85,7 +239,22 @@ mod shaders {
 
     pub const POINT_VERT: &str = include_shader!("./shaders/point.vert");
     pub const POINT_GEOM: &str = include_shader!("./shaders/point.geom");
-    pub const POINT_FRAG: &str = include_shader!("./shaders/point.frag");
+    pub const POINT_FRAG: &str =
+        include_shader!("./shaders/stroke_common.glsl", "./shaders/point.frag");
+    pub const POINT_DOT_FRAG: &str =
+        include_shader!("./shaders/stroke_common.glsl", "./shaders/point_dot.frag");
+
+    pub const SOLID_FRAG: &str = include_shader!("./shaders/solid.frag");
+    pub const NORMAL_FRAG: &str = include_shader!("./shaders/normal.frag");
+
+    pub const PEEL_COMPOSITE_FRAG: &str = include_shader!("./shaders/peel_composite.frag");
+    pub const OIT_RESOLVE_FRAG: &str = include_shader!("./shaders/oit_resolve.frag");
+
+    pub const BLOOM_THRESHOLD_FRAG: &str = include_shader!("./shaders/bloom_threshold.frag");
+    pub const BLOOM_BLUR_FRAG: &str = include_shader!("./shaders/bloom_blur.frag");
+
+    pub const OUTLINE_GEOMETRY_VERT: &str = include_shader!("./shaders/outline_geometry.vert");
+    pub const OUTLINE_GEOMETRY_FRAG: &str = include_shader!("./shaders/outline_geometry.frag");
 }
 
 #[derive(Debug)]
@@ -96,59 +265,806 @@ struct DebugInfo {
     sort_time: AtomicU64,
     /// Fixed time in microseconds
     fixed_time: AtomicU64,
+    /// Time between the starts of the last two sorts in microseconds
+    sort_period: AtomicU64,
+    /// Number of sorts finished, so a frame knows whether `sort_time` is new
+    sorts: AtomicU64,
+}
+
+/// Factor a model scale of `scale` grows surface areas by, so a scaled up model gets more strokes
+/// rather than sparser ones
+fn model_area_scale(scale: Vector3<f32>) -> f32 {
+    area_scale(Matrix4::from_nonuniform_scale(scale.x, scale.y, scale.z))
+}
+
+/// Failure to load a scene or one of the assets it references
+#[derive(Debug)]
+enum LoadError {
+    Io(PathBuf, io::Error),
+    Toml(PathBuf, toml::de::Error),
+    Image(PathBuf, ImageError),
+    /// Image that couldn't be written
+    Write(PathBuf, ImageError),
+    Model(PathBuf, ModelError),
+    Points(PathBuf, PointsError),
+    Brushes(PathBuf, BrushError),
+    /// Scene file with a setting out of its range
+    Invalid(PathBuf, String),
+    /// Shader program that failed to compile or link
+    Shader(&'static str, ProgramCreationError),
+    /// OpenGL texture or buffer that couldn't be created
+    Gl(&'static str, String),
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoadError::Io(path, e) => write!(f, "Failed to read '{}': {e}", path.display()),
+            LoadError::Toml(path, e) => write!(f, "Failed to parse '{}': {e}", path.display()),
+            LoadError::Image(path, e) => {
+                write!(f, "Failed to load image '{}': {e}", path.display())
+            }
+            LoadError::Write(path, e) => write!(f, "Failed to write '{}': {e}", path.display()),
+            LoadError::Model(path, e) => {
+                write!(f, "Failed to load model '{}': {e}", path.display())
+            }
+            LoadError::Points(path, e) => {
+                write!(f, "Failed to load points '{}': {e}", path.display())
+            }
+            LoadError::Brushes(path, e) => {
+                write!(f, "Failed to load brushes '{}': {e}", path.display())
+            }
+            LoadError::Invalid(path, e) => write!(f, "Invalid scene '{}': {e}", path.display()),
+            LoadError::Shader(name, e) => write!(f, "Failed to build the {name} shader: {e}"),
+            LoadError::Gl(what, e) => write!(f, "Failed to create the {what}: {e}"),
+        }
+    }
+}
+
+/// RGB color of a scene file
+type SceneColor = (f32, f32, f32);
+
+/// Initial window of a scene, from its `[window]` table
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+struct WindowDesc {
+    width: Option<u32>,
+    height: Option<u32>,
+    /// Start in borderless fullscreen
+    fullscreen: Option<bool>,
 }
 
-#[derive(Debug, Deserialize)]
+/// Writes the `f32`s of a serialized scene with their shortest decimal representation rather than
+/// that of their `f64` conversion, so `0.04` isn't saved as `0.03999999910593033`
+fn shorten_floats(value: &mut toml::Value) {
+    match value {
+        toml::Value::Float(float) => *float = (*float as f32).to_string().parse().unwrap(),
+        toml::Value::Array(array) => array.iter_mut().for_each(shorten_floats),
+        toml::Value::Table(table) => table
+            .iter_mut()
+            .for_each(|(_, value)| shorten_floats(value)),
+        _ => {}
+    }
+}
+
+impl WindowDesc {
+    fn is_default(&self) -> bool {
+        self.width.is_none() && self.height.is_none() && self.fullscreen.is_none()
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
 struct Scene {
-    obj_file: PathBuf,
+    /// The OBJ files of the scene, from `obj_file`
+    #[serde(rename = "obj_file", deserialize_with = "deserialize_objects")]
+    objects: Vec<ObjectDesc>,
     albedo_texture: PathBuf,
     stroke_density: u32,
     brush_size: f32,
     quantization: i32,
     background: (f32, f32, f32),
+    background_gradient: Option<(SceneColor, SceneColor, GradientStyle)>,
+    paint_background: Option<(f32, f32, f32)>,
     saturation: Option<f32>,
-    position: Option<Vector3<f32>>,
-    camera_position: Option<Point3<f32>>,
+    /// Multiplier of the paint colors before tone mapping
+    exposure: Option<f32>,
+    tonemap: Option<Tonemap>,
+    /// Rotation of the hues in degrees
+    hue_shift: Option<f32>,
+    lift: Option<SceneColor>,
+    gamma: Option<SceneColor>,
+    gain: Option<SceneColor>,
+    position: Option<[f32; 3]>,
+    camera_position: Option<[f32; 3]>,
+    /// Direction the camera looks in, towards the origin by default
+    camera_direction: Option<[f32; 3]>,
+    projection: Option<SceneProjection>,
+    ortho_height: Option<f32>,
+    /// Vertical field of view in degrees
+    fov: Option<f32>,
+    near: Option<f32>,
+    far: Option<f32>,
+    scale: Option<[f32; 3]>,
+    max_points: Option<usize>,
+    seed: Option<u64>,
+    sampling: Option<Sampling>,
+    stroke_mask: Option<PathBuf>,
+    importance_map: Option<PathBuf>,
+    importance_strength: Option<f32>,
+    density_curvature_weight: Option<f32>,
+    flow_from_albedo: Option<bool>,
+    /// Paint each stroke with the albedo at its center
+    flat_stroke_color: Option<bool>,
+    flow_smoothing: Option<u32>,
+    stroke_style: Option<StrokeStyle>,
+    brush_jitter: Option<f32>,
+    stroke_rotation_jitter: Option<f32>,
+    brush_size_jitter: Option<f32>,
+    view_angle_size: Option<f32>,
+    view_angle_min_size: Option<f32>,
+    facing_fade: Option<f32>,
+    cull_backfaces: Option<bool>,
+    /// Paint the shaded mesh beneath the strokes
+    underpainting: Option<bool>,
+    underpainting_opacity: Option<f32>,
+    depth_peel_layers: Option<u32>,
+    weighted_blending: Option<bool>,
+    sort_hz: Option<u32>,
+    sort_angle_threshold: Option<f32>,
+    sort_distance_threshold: Option<f32>,
+    turntable_speed: Option<f32>,
+    smudge: Option<u32>,
+    bloom_intensity: Option<f32>,
+    bloom_threshold: Option<f32>,
+    outline_thickness: Option<f32>,
+    outline_threshold: Option<f32>,
+    outline_color: Option<SceneColor>,
+    canvas_texture: Option<PathBuf>,
+    /// Directory of brush images used instead of the built-in ones
+    brush_dir: Option<PathBuf>,
+    canvas_strength: Option<f32>,
+    canvas_scale: Option<f32>,
+    vignette_radius: Option<f32>,
+    vignette_softness: Option<f32>,
+    blend_mode: Option<BlendMode>,
+    adaptive_density: Option<f32>,
+    stroke_length: Option<f32>,
+    length_jitter: Option<f32>,
+    haze_color: Option<(f32, f32, f32)>,
+    haze_start: Option<f32>,
+    haze_end: Option<f32>,
+    /// Color distant strokes fade to, the background by default
+    fog_color: Option<(f32, f32, f32)>,
+    fog_start: Option<f32>,
+    fog_end: Option<f32>,
+    grain_scale: Option<f32>,
+    grain_strength: Option<f32>,
+    light_dir: Option<[f32; 3]>,
+    light_color: Option<(f32, f32, f32)>,
+    ambient: Option<f32>,
+    uv_channel: Option<usize>,
+    #[serde(default, rename = "instance", skip_serializing_if = "Vec::is_empty")]
+    instances: Vec<InstanceDesc>,
+    #[serde(default, rename = "shape", skip_serializing_if = "HashMap::is_empty")]
+    shapes: HashMap<String, ShapeOverride>,
+    animation: Option<Animation>,
+    #[serde(default, skip_serializing_if = "WindowDesc::is_default")]
+    window: WindowDesc,
+    /// Strokes to load instead of generating them, set with `--points`
+    #[serde(skip)]
+    points_file: Option<PathBuf>,
+    /// Directory generated strokes are cached in, unless `--no-cache` is given
+    #[serde(skip)]
+    points_cache: Option<PathBuf>,
+}
+
+/// A scene file with the files it references resolved
+struct LoadedScene {
+    /// The scene, with every asset path absolute
+    scene: Scene,
+    /// Absolute directory of the scene file, which its relative paths are resolved against
+    dir: PathBuf,
+}
+
+impl Scene {
+    /// Loads the scene file at `path`, resolving the files it references against its directory.
+    /// The asset paths of the loaded scene are absolute, and fail to load if they don't exist.
+    fn load(path: &Path) -> Result<LoadedScene, LoadError> {
+        Self::load_with_preset(path, None)
+    }
+
+    /// Loads the scene file at `path` like `load`, with the settings of the `preset` file
+    /// replacing the scene's. Paths in the preset are relative to the scene's directory.
+    fn load_with_preset(path: &Path, preset: Option<&Path>) -> Result<LoadedScene, LoadError> {
+        let read_table = |path: &Path| {
+            let source =
+                fs::read_to_string(path).map_err(|e| LoadError::Io(path.to_path_buf(), e))?;
+            toml::from_str::<toml::Table>(&source)
+                .map_err(|e| LoadError::Toml(path.to_path_buf(), e))
+        };
+        let mut table = read_table(path)?;
+        if let Some(preset) = preset {
+            table.extend(read_table(preset)?);
+        }
+        let scene: Scene = toml::Value::Table(table)
+            .try_into()
+            .map_err(|e| LoadError::Toml(preset.unwrap_or(path).to_path_buf(), e))?;
+        scene
+            .validate_camera()
+            .and_then(|_| scene.validate_scale())
+            .map_err(|e| LoadError::Invalid(preset.unwrap_or(path).to_path_buf(), e))?;
+
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        let dir = fs::canonicalize(dir).map_err(|e| LoadError::Io(dir.to_path_buf(), e))?;
+        // Absolute paths are kept as they are by the join
+        let resolve = |path: &Path| {
+            let joined = dir.join(path);
+            fs::canonicalize(&joined).map_err(|e| LoadError::Io(joined, e))
+        };
+        let resolve_optional = |path: &Option<PathBuf>| path.as_deref().map(resolve).transpose();
+
+        let objects = scene
+            .objects
+            .into_iter()
+            .map(|object| {
+                Ok(ObjectDesc {
+                    file: resolve(&object.file)?,
+                    albedo_texture: resolve_optional(&object.albedo_texture)?,
+                    ..object
+                })
+            })
+            .collect::<Result<_, LoadError>>()?;
+        let scene = Scene {
+            objects,
+            albedo_texture: resolve(&scene.albedo_texture)?,
+            stroke_mask: resolve_optional(&scene.stroke_mask)?,
+            importance_map: resolve_optional(&scene.importance_map)?,
+            canvas_texture: resolve_optional(&scene.canvas_texture)?,
+            brush_dir: resolve_optional(&scene.brush_dir)?,
+            ..scene
+        };
+        Ok(LoadedScene { scene, dir })
+    }
+
+    /// Checks that the field of view and depth range give a valid projection
+    fn validate_camera(&self) -> Result<(), String> {
+        let fov = self.fov.unwrap_or(DEFAULT_FOV.0);
+        if fov.is_nan() || fov <= 0.0 || fov >= 180.0 {
+            return Err(format!("fov is {fov}°, it must be between 0° and 180°"));
+        }
+        let near = self.near.unwrap_or(DEFAULT_NEAR);
+        let far = self.far.unwrap_or(DEFAULT_FAR);
+        if near.is_nan() || near <= 0.0 {
+            return Err(format!("near is {near}, it must be greater than 0"));
+        }
+        if far.is_nan() || far <= near {
+            return Err(format!(
+                "far is {far}, it must be greater than near ({near})"
+            ));
+        }
+        Ok(())
+    }
+
+    /// Checks that every component of `scale` is positive, a zero or negative one would collapse
+    /// or mirror the model and leave no surface area to place strokes on
+    fn validate_scale(&self) -> Result<(), String> {
+        match self.scale {
+            Some(scale) if scale.iter().any(|s| s.is_nan() || *s <= 0.0) => Err(format!(
+                "scale is {scale:?}, its components must be greater than 0"
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// Writes the current tuning back to the scene file at `path`: `params`, the backgrounds, the
+    /// stroke density and the camera. Everything else, including the paths, is kept as the file
+    /// has it, and settings still at the values the file loads to aren't added.
+    fn save_tuning(
+        path: &Path,
+        state: &State,
+        data: &DrawData,
+        stroke_density: u32,
+    ) -> io::Result<()> {
+        let source = fs::read_to_string(path)?;
+        let mut scene: Scene =
+            toml::from_str(&source).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+        data.params.update_scene(&mut scene);
+        scene.stroke_density = stroke_density;
+        let [r, g, b] = data.background;
+        scene.background = (r, g, b);
+        if data.paint_background != scene.paint_background() {
+            let [r, g, b] = data.paint_background;
+            scene.paint_background = Some((r, g, b));
+        }
+        {
+            let camera = state.camera.lock().unwrap();
+            scene.camera_position = Some(camera.position().into());
+            scene.camera_direction = Some(camera.direction().into());
+            // Only written when changed, like the other settings
+            let fov = Deg::from(camera.fov()).0;
+            if (fov - scene.fov.unwrap_or(DEFAULT_FOV.0)).abs() > 1.0e-3 {
+                scene.fov = Some(fov);
+            }
+            if camera.near() != scene.near.unwrap_or(DEFAULT_NEAR) {
+                scene.near = Some(camera.near());
+            }
+            if camera.far() != scene.far.unwrap_or(DEFAULT_FAR) {
+                scene.far = Some(camera.far());
+            }
+        }
+
+        let mut value = toml::Value::try_from(&scene)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        shorten_floats(&mut value);
+        let source =
+            toml::to_string(&value).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        fs::write(path, source)
+    }
+
+    fn paint_background(&self) -> [f32; 3] {
+        let (r, g, b) = self.paint_background.unwrap_or(self.background);
+        [r, g, b]
+    }
+
+    /// Paths of every file the scene references, with the external buffers of its glTF files
+    fn asset_paths(&self) -> Vec<PathBuf> {
+        let objects = self.objects.iter().flat_map(|object| {
+            // A glTF file that fails to parse fails to load too
+            let buffers = if is_gltf(&object.file) {
+                gltf_file::buffer_paths(&object.file).unwrap_or_default()
+            } else {
+                vec![]
+            };
+            [Some(object.file.clone()), object.albedo_texture.clone()]
+                .into_iter()
+                .flatten()
+                .chain(buffers)
+        });
+        [
+            Some(&self.albedo_texture),
+            self.stroke_mask.as_ref(),
+            self.importance_map.as_ref(),
+            self.canvas_texture.as_ref(),
+        ]
+        .into_iter()
+        .flatten()
+        .cloned()
+        .chain(objects)
+        .collect()
+    }
+
+    fn model_scale(&self) -> Vector3<f32> {
+        Vector3::from(self.scale.unwrap_or([1.0, 1.0, 1.0]))
+    }
+
+    fn model_matrix(&self) -> Matrix4<f32> {
+        let scale = self.model_scale();
+        Matrix4::from_translation(self.position.map_or(Vector3::zero(), Vector3::from))
+            * Matrix4::from_nonuniform_scale(scale.x, scale.y, scale.z)
+    }
+
+    /// Factor the model scale grows surface areas by, which the stroke density is multiplied by
+    fn area_scale(&self) -> f32 {
+        model_area_scale(self.model_scale())
+    }
+
+    /// Whether the points are generated from the decoded albedo
+    fn needs_albedo(&self) -> bool {
+        self.flow_from_albedo.unwrap_or(false) || self.flat_stroke_color.unwrap_or(false)
+    }
+
+    /// Distance from the initial camera to the model origin
+    fn camera_distance(&self) -> f32 {
+        let camera_pos = self
+            .camera_position
+            .map_or(point3(2.0, 2.0, 2.0), Point3::from);
+        camera_pos.distance(self.position.map_or(Point3::origin(), Point3::from))
+    }
+
+    fn camera(&self, aspect: f32) -> Camera {
+        let camera_pos = self
+            .camera_position
+            .map_or(point3(2.0, 2.0, 2.0), Point3::from);
+        let fov = self.fov.map_or(DEFAULT_FOV, Deg);
+        let mut camera = Camera::new(
+            camera_pos,
+            self.camera_direction
+                .map_or(Point3::origin() - camera_pos, Vector3::from),
+            fov,
+            aspect,
+            self.near.unwrap_or(DEFAULT_NEAR),
+            self.far.unwrap_or(DEFAULT_FAR),
+        );
+        if self.projection == Some(SceneProjection::Ortho) {
+            // Defaults to the height the perspective view shows at the model
+            let height = self
+                .ortho_height
+                .unwrap_or_else(|| 2.0 * self.camera_distance() * (Rad::from(fov) / 2.0).tan());
+            camera.set_projection(Projection::Orthographic { height });
+        }
+        camera
+    }
+}
+
+/// Sorted points of every model and the instance transforms in draw order
+type SortedPoints = (Vec<Vec<Point>>, Vec<Matrix4<f32>>);
+
+/// Messages to the point generation/sort thread
+enum PointUpdate {
+    /// Regenerate the points with the current stroke density
+    Regenerate,
+    /// Switch to the models with their shape settings, stroke maps and instance transforms of a
+    /// newly loaded scene
+    Scene(Vec<SceneModel>, Box<StrokeMaps>, Vec<Matrix4<f32>>),
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum SceneProjection {
+    Perspective,
+    Ortho,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum StrokeStyle {
+    /// Textured brush quads expanded by the geometry shader
+    Brush,
+    /// Round `GL_POINTS` sized with `gl_PointSize`
+    Point,
+}
+
+impl StrokeStyle {
+    const ALL: [StrokeStyle; 2] = [StrokeStyle::Brush, StrokeStyle::Point];
+
+    fn name(self) -> &'static str {
+        match self {
+            StrokeStyle::Brush => "Brush",
+            StrokeStyle::Point => "Point",
+        }
+    }
+}
+
+/// How strokes are blended onto the paint layer when they are sorted instead of depth peeled
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum BlendMode {
+    /// Strokes are painted over each other
+    Alpha,
+    /// Strokes darken what is below them, like layered glazes
+    Multiply,
+}
+
+impl BlendMode {
+    const ALL: [BlendMode; 2] = [BlendMode::Alpha, BlendMode::Multiply];
+
+    fn name(self) -> &'static str {
+        match self {
+            BlendMode::Alpha => "Alpha",
+            BlendMode::Multiply => "Multiply",
+        }
+    }
+
+    /// The stroke order that blends correctly in this mode
+    fn sort_order(self) -> SortOrder {
+        match self {
+            BlendMode::Alpha => SortOrder::FarToNear,
+            BlendMode::Multiply => SortOrder::NearToFar,
+        }
+    }
+}
+
+/// Shape of the two color background gradient
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum GradientStyle {
+    /// From the first color at the top to the second at the bottom
+    Vertical,
+    /// From the first color at the center to the second at the corners
+    Radial,
+}
+
+impl GradientStyle {
+    const ALL: [GradientStyle; 2] = [GradientStyle::Vertical, GradientStyle::Radial];
+
+    fn name(self) -> &'static str {
+        match self {
+            GradientStyle::Vertical => "Vertical",
+            GradientStyle::Radial => "Radial",
+        }
+    }
+}
+
+/// Curve that maps the exposed paint colors into the displayable range
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum Tonemap {
+    /// Colors are only clamped
+    None,
+    /// `c / (1 + c)`, soft highlights that never reach white
+    Reinhard,
+    /// Fit of the ACES filmic curve, with more contrast than Reinhard
+    Aces,
+}
+
+impl Tonemap {
+    const ALL: [Tonemap; 3] = [Tonemap::None, Tonemap::Reinhard, Tonemap::Aces];
+
+    fn name(self) -> &'static str {
+        match self {
+            Tonemap::None => "None",
+            Tonemap::Reinhard => "Reinhard",
+            Tonemap::Aces => "ACES",
+        }
+    }
+}
+
+/// Order in which the sorted strokes are drawn
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize)]
+enum SortOrder {
+    FarToNear,
+    NearToFar,
+}
+
+impl SortOrder {
+    const ALL: [SortOrder; 2] = [SortOrder::FarToNear, SortOrder::NearToFar];
+
+    fn name(self) -> &'static str {
+        match self {
+            SortOrder::FarToNear => "far→near",
+            SortOrder::NearToFar => "near→far",
+        }
+    }
+
+    fn reversed(self) -> Self {
+        match self {
+            SortOrder::FarToNear => SortOrder::NearToFar,
+            SortOrder::NearToFar => SortOrder::FarToNear,
+        }
+    }
 }
 
 #[derive(Debug, Copy, Clone)]
 enum ViewState {
     Raster,
     Full,
+    /// Only the mesh edges, including hidden ones, to inspect the triangulation
+    Wireframe,
+    /// The mesh colored by its normals, to spot flipped or missing ones
+    Normals,
 }
 
 #[derive(Debug)]
 struct State {
     view_state: Mutex<ViewState>,
     wheel_delta: Mutex<Option<(f32, f32)>>,
+    /// Cursor movement while dragging with the left button, taken by the fixed update thread
+    drag_delta: Mutex<Option<(f32, f32)>>,
     camera: Mutex<Camera>,
     keys: Mutex<HashSet<VirtualKeyCode>>,
     model: Mutex<Matrix4<f32>>,
+    /// Set when `model` is changed outside of the fixed update thread so the points get re-sorted
+    model_changed: AtomicBool,
+    /// Spin the model around the vertical axis at `turntable_speed`
+    turntable: AtomicBool,
+    /// Turntable speed in degrees per second
+    turntable_speed: Mutex<f32>,
+    /// Ease the arrow key zoom in and out instead of starting and stopping it dead
+    smooth_zoom: AtomicBool,
     enable_gui: AtomicBool,
+    /// Show the frame rate and draw and sort times while the side panel is hidden
+    fps_overlay: AtomicBool,
     debug_info: DebugInfo,
     stroke_density: AtomicU32,
+    /// Upper bound on the number of generated points
+    max_points: Mutex<Option<usize>>,
+    /// Seed of the point generation, random points every time without one
+    seed: Mutex<Option<u64>>,
+    sampling: Mutex<Sampling>,
+    /// Target rate of the sort thread, independent of the draw rate
+    sort_hz: AtomicU32,
+    /// Degrees the camera turns relative to the model before the strokes are sorted again
+    sort_angle_threshold: Mutex<f32>,
+    /// Fraction of its distance to the model the camera moves before the strokes are sorted again
+    sort_distance_threshold: Mutex<f32>,
+    /// Size of the sort thread pool, 0 for one thread per core
+    sort_threads: usize,
+    /// Effective stroke order, kept in sync with `Params::sort_order`
+    sort_order: Mutex<SortOrder>,
+    /// Whether the sort thread sorts the strokes, false while they are drawn order independently
+    sorting: AtomicBool,
+    /// Target coverage of adaptive density, `None` while it is off. Kept in sync with `Params`,
+    /// see `lod`
+    adaptive_density: Mutex<Option<f32>>,
+    /// Camera distance up to which objects keep all their strokes at a target coverage of 1
+    lod_reference_distance: Mutex<f32>,
+    /// Factor the model scale grows surface areas by, the stroke density is multiplied by it
+    area_scale: Mutex<f32>,
+    /// Message shown by `show_toast` and when it was shown
+    toast: Mutex<Option<(String, Instant)>>,
+}
+
+impl State {
+    /// Whether egui has anything to paint this frame
+    fn gui_visible(&self) -> bool {
+        self.enable_gui.load(Ordering::Relaxed)
+            || self.fps_overlay.load(Ordering::Relaxed)
+            || self.toast_visible()
+    }
+
+    fn toast_visible(&self) -> bool {
+        self.toast
+            .lock()
+            .unwrap()
+            .as_ref()
+            .is_some_and(|(_, shown)| shown.elapsed() < TOAST_DURATION)
+    }
+
+    /// Briefly shows `message` on screen, even when the side panel is hidden
+    fn show_toast(&self, message: String) {
+        *self.toast.lock().unwrap() = Some((message, Instant::now()));
+    }
 }
 
 struct DrawData {
     models: Vec<ModelData>,
+    /// Transforms of every instance of the models, in scene order
+    instance_transforms: Vec<Matrix4<f32>>,
+    /// Instance transforms ordered by the sort thread
+    instance_buffer: VertexBuffer<Instance>,
+    stroke_maps: StrokeMaps,
+    /// Displayed where no stroke covers the painting
     background: [f32; 3],
+    /// Color the strokes are painted over
+    paint_background: [f32; 3],
+    /// A placeholder until `pending_albedo` is decoded
     albedo_texture: CompressedSrgbTexture2d,
+    pending_albedo: Option<PendingImage>,
+    /// Albedo of each object that has its own, instead of `albedo_texture`
+    object_albedos: Vec<Option<CompressedSrgbTexture2d>>,
+    /// Diffuse textures of the materials of the models, by path, added as they are decoded
+    material_albedos: HashMap<PathBuf, CompressedSrgbTexture2d>,
+    /// Material textures still decoding, drawn with `albedo_texture` until they are
+    pending_materials: Vec<PendingImage>,
+    /// Smallest and largest corner of the box around the models, before the model matrix
+    bounds: Option<(Point3<f32>, Point3<f32>)>,
     canvas_texture: CompressedSrgbTexture2d,
     post_process_texture: SrgbTexture2d,
     color_program: Program,
     point_program: Program,
+    point_dot_program: Program,
+    solid_program: Program,
+    normal_program: Program,
+    peel_composite_program: Program,
+    oit_resolve_program: Program,
     post_process_program: Program,
+    depth_peel_targets: DepthPeelTargets,
+    oit_targets: OitTargets,
+    bloom_threshold_program: Program,
+    bloom_blur_program: Program,
+    bloom_targets: BloomTargets,
+    outline_geometry_program: Program,
+    outline_targets: OutlineTargets,
     brush_stroke: CompressedSrgbTexture2d,
+    /// One texel per brush with its `[u, v, width, height]` rectangle in `brush_stroke`
+    brush_rects: Texture2d,
     post_process_quad: (VertexBuffer<PostProcessVert>, IndexBuffer<u8>),
     params: Params,
 }
 
+#[derive(Debug, Serialize)]
 struct Params {
     quantization: i32,
     brush_size: f32,
     saturation: f32,
+    /// Multiplier of the paint colors before `tonemap`
+    exposure: f32,
+    tonemap: Tonemap,
+    /// Rotation of the hues in degrees, around the gray axis
+    hue_shift: f32,
+    /// Per-channel color grading after tone mapping, `lift` raises the shadows, `gain` scales the
+    /// highlights and `gamma` bends the midtones
+    lift: [f32; 3],
+    gamma: [f32; 3],
+    gain: [f32; 3],
     enable_canvas: bool,
+    /// How much (0 to 1) of the canvas texture shows through the paint
+    canvas_strength: f32,
+    /// Number of times the canvas texture repeats across the frame
+    canvas_scale: f32,
     enable_brush_tbn: bool,
+    stroke_style: StrokeStyle,
+    /// Point size in pixels for `StrokeStyle::Point`
+    point_size: f32,
+    /// Amount of per-point random rotation and size variation of brush strokes
+    brush_jitter: f32,
+    /// Amount of per-point random rotation only, the larger of this and `brush_jitter` is used
+    stroke_rotation_jitter: f32,
+    /// Strokes are scaled by a random factor in `1 ± brush_size_jitter`
+    brush_size_jitter: f32,
+    /// How much strokes shrink as the surface turns away from the camera
+    view_angle_size: f32,
+    /// Smallest stroke size from `view_angle_size`, as a fraction of `brush_size`
+    view_angle_min_size: f32,
+    /// Strokes fade out as the surface turns away from the camera, over this much of the cosine
+    /// between the normal and the view direction. Back facing strokes aren't drawn, 0 disables it
+    facing_fade: f32,
+    /// Skip strokes whose normal faces away from the camera, off for thin or double sided surfaces
+    cull_backfaces: bool,
+    /// Paint the shaded, albedo textured mesh at `underpainting_opacity` before the strokes
+    enable_underpainting: bool,
+    underpainting_opacity: f32,
+    /// Number of depth peeled layers, 0 uses sorted alpha blending instead
+    depth_peel_layers: u32,
+    /// Draw the strokes unsorted with weighted blended transparency, unless depth peeling
+    weighted_blending: bool,
+    /// Radius in pixels of the Kuwahara smudge filter, 0 disables it
+    smudge_radius: u32,
+    /// Add a glow around the parts of the painting brighter than `bloom_threshold`
+    enable_bloom: bool,
+    bloom_threshold: f32,
+    bloom_intensity: f32,
+    /// Draw dark lines along the silhouettes and creases of the mesh
+    enable_outline: bool,
+    /// Width in pixels of the outline
+    outline_thickness: f32,
+    /// Edge strength above which the outline is drawn, lower values also outline softer creases
+    outline_threshold: f32,
+    outline_color: [f32; 3],
+    /// Darken the corners of the frame outside `vignette_radius`
+    enable_vignette: bool,
+    /// Distance from the center, relative to the corners, where the vignette starts
+    vignette_radius: f32,
+    /// Distance over which the vignette fades to black
+    vignette_softness: f32,
+    blend_mode: BlendMode,
+    /// Stroke order chosen by the user instead of the one of `blend_mode`
+    sort_override: Option<SortOrder>,
+    /// Draw fewer strokes on objects far from the camera to keep the on-screen stroke density
+    /// about constant, see `lod`
+    adaptive_density: bool,
+    /// On-screen stroke density relative to the density at the starting camera distance
+    target_coverage: f32,
+    /// Length of brush strokes along their direction relative to their width
+    stroke_length: f32,
+    /// Amount of per-stroke random length variation
+    length_jitter: f32,
+    /// Desaturate and tint distant strokes towards `haze_color`
+    enable_haze: bool,
+    haze_color: [f32; 3],
+    /// Camera distance where the haze starts
+    haze_start: f32,
+    /// Camera distance where the haze is strongest
+    haze_end: f32,
+    /// Fade distant strokes towards `fog_color`
+    enable_fog: bool,
+    fog_color: [f32; 3],
+    /// Camera distance where the fog starts
+    fog_start: f32,
+    /// Camera distance where strokes are fully faded to `fog_color`
+    fog_end: f32,
+    /// Orient strokes along the albedo flow, if the points have one
+    enable_flow: bool,
+    /// Paint each stroke with its own flat color, if the points have one
+    flat_color: bool,
+    /// Size in world units of the patches of the stroke opacity grain
+    grain_scale: f32,
+    /// How much (0 to 1) the grain lightens strokes, 0 disables it
+    grain_strength: f32,
+    /// Show a gradient between `gradient_colors` instead of the flat background
+    background_gradient: Option<GradientStyle>,
+    gradient_colors: [[f32; 3]; 2],
+    /// Direction towards the light shading the strokes, not necessarily normalized
+    light_dir: [f32; 3],
+    light_color: [f32; 3],
+    /// Light reaching surfaces facing away from the light
+    ambient: f32,
+    /// Draw the mesh edges over the shaded model in `ViewState::Raster` and `ViewState::Normals`
+    wire_overlay: bool,
+    wire_color: [f32; 3],
 }
 
 #[derive(Copy, Clone)]
@@ -162,47 +1078,111 @@ fn main() {
 
     let args = Args::parse();
 
-    let scene: Scene = toml::from_str(&fs::read_to_string(&args.scene).unwrap()).unwrap();
-    let scene_base_dir = args.scene.parent().unwrap();
+    let preset = |i: usize| {
+        args.diff_presets
+            .as_ref()
+            .map(|presets| presets[i].as_path())
+    };
+    let load_scene = |path: &Path, preset| {
+        Scene::load_with_preset(path, preset).unwrap_or_else(|e| {
+            error!("{e}");
+            exit(1);
+        })
+    };
+    let LoadedScene {
+        mut scene,
+        dir: mut scene_dir,
+    } = load_scene(&args.scene, preset(0));
+    scene.points_file = args.points.clone();
+    let points_cache = (!args.no_cache).then(|| env::temp_dir().join(POINTS_CACHE_DIR));
+    scene.points_cache = points_cache.clone();
+    if let Some(density) = args.density {
+        scene.stroke_density = density;
+    }
+    // References are rendered with their saved strokes so they can be reproduced exactly
+    let reference = args
+        .reference
+        .as_ref()
+        .map(|dir| Reference::new(dir, &args.scene));
+    if let Some(reference) = &reference {
+        if !args.update_reference {
+            scene.points_file = Some(reference.points.clone());
+        }
+    }
 
-    let event_loop = EventLoop::new();
-    let wb = WindowBuilder::new().with_inner_size(PhysicalSize::new(2880, 1800));
-    let cb = ContextBuilder::new().with_depth_buffer(24);
-    let display = Display::new(wb, cb, &event_loop).unwrap();
+    if let Some(path) = &args.dump_points {
+        let points = scene_points(&scene).unwrap_or_else(|e| {
+            error!("{e}");
+            exit(1);
+        });
+        match ply::write_ply(path, &points) {
+            Ok(()) => info!("Wrote {} points to '{}'", points.len(), path.display()),
+            Err(e) => {
+                error!("Failed to write points to '{}': {e}", path.display());
+                exit(1);
+            }
+        }
+        return;
+    }
 
-    // Shader programs
-    let mut data = init_draw_data(&display, &scene, scene_base_dir);
+    // The scene compared to with `--diff` or `--diff-presets`
+    let other_scene = match (&args.diff, preset(1)) {
+        (Some(other_scene), _) => Some(load_scene(other_scene, None).scene),
+        (None, Some(preset)) => Some(load_scene(&args.scene, Some(preset)).scene),
+        (None, None) => None,
+    };
 
-    // Camera
+    // Render offscreen without a window and exit
+    let headless = args.diff.is_some()
+        || args.diff_presets.is_some()
+        || args.output.is_some()
+        || args.reference.is_some()
+        || args.bundle.is_some();
 
-    let aspect = display.get_framebuffer_dimensions().0 as f32
-        / display.get_framebuffer_dimensions().1 as f32;
+    let width = args.width.or(scene.window.width).unwrap_or(1280);
+    let height = args.height.or(scene.window.height).unwrap_or(720);
 
-    let camera_pos = scene.camera_position.unwrap_or(point3(2.0, 2.0, 2.0));
+    if headless {
+        // No window is created, every frame is rendered offscreen at exactly `width` by `height`
+        let (_event_loop, renderer) = headless_renderer(width, height).unwrap_or_else(|e| {
+            error!("Failed to create a headless OpenGL context: {e}");
+            exit(1);
+        });
+        let (mut data, state) = init_renderer(&renderer, &args, &scene, width, height, true);
+        run_headless(
+            &args,
+            &state,
+            &renderer,
+            &mut data,
+            &scene,
+            other_scene.as_ref(),
+            reference.as_ref(),
+        );
+        return;
+    }
 
-    let state = Arc::new(State {
-        view_state: Mutex::new(ViewState::Full),
-        camera: Mutex::new(Camera::new(
-            camera_pos,
-            Point3::origin() - camera_pos,
-            Deg(100.0),
-            aspect,
-            0.1,
-            10.0,
-        )),
-        wheel_delta: Mutex::new(None),
-        keys: Mutex::new(HashSet::new()),
-        model: Mutex::new(Matrix4::from_translation(
-            scene.position.unwrap_or(Vector3::zero()),
-        )),
-        enable_gui: AtomicBool::new(true),
-        debug_info: DebugInfo {
-            draw_time: AtomicU64::new(0),
-            sort_time: AtomicU64::new(0),
-            fixed_time: AtomicU64::new(0),
-        },
-        stroke_density: AtomicU32::new(scene.stroke_density),
+    let event_loop = EventLoop::new();
+    let fullscreen = scene.window.fullscreen.unwrap_or(false);
+    let wb = WindowBuilder::new()
+        .with_inner_size(PhysicalSize::new(width, height))
+        .with_fullscreen(fullscreen.then_some(Fullscreen::Borderless(None)));
+    let cb = ContextBuilder::new().with_depth_buffer(24);
+    let display = Display::new(wb, cb, &event_loop).unwrap_or_else(|e| {
+        error!("Failed to create an OpenGL context: {e}");
+        exit(1);
     });
+    let (framebuffer_width, framebuffer_height) = display.get_framebuffer_dimensions();
+    let (mut data, state) = init_renderer(
+        &display,
+        &args,
+        &scene,
+        framebuffer_width,
+        framebuffer_height,
+        false,
+    );
+
+    let mut model_scale = scene.model_scale();
+    let mut uniform_scale = model_scale.x == model_scale.y && model_scale.y == model_scale.z;
 
     let mut egui_glium = EguiGlium::new(&display, &event_loop);
 
@@ -213,7 +1193,9 @@ fn main() {
     fixed_update(
         state.clone(),
         data.models.iter().map(|p| p.points.clone()).collect(),
-        data.models.iter().map(|m| m.model.clone()).collect(),
+        data.models.iter().map(ModelData::scene_model).collect(),
+        data.stroke_maps.clone(),
+        data.instance_transforms.clone(),
         tx,
         point_update_rx,
     );
@@ -222,22 +1204,87 @@ fn main() {
     let mut draw_time_average = RunningAverage::<f64, 32>::new();
     let mut fixed_time_average = RunningAverage::<f64, 32>::new();
     let mut true_frame_time_average = RunningAverage::<f64, 32>::new();
+    let mut sort_period_average = RunningAverage::<f64, 32>::new();
 
     let mut true_frame_time_start = Instant::now();
     let mut true_frame_time = Duration::ZERO;
 
+    let mut stats_log = args.stats_csv.as_ref().and_then(|path| {
+        StatsLog::open(path)
+            .map_err(|e| error!("Failed to open '{}': {e}", path.display()))
+            .ok()
+    });
+    let mut logged_sorts = 0;
+
     let mut point_density = state.stroke_density.load(Ordering::Relaxed);
+    let mut max_points = scene.max_points;
+
+    let mut cursor_position = (0.0, 0.0);
+    // Where the left button was pressed, a release close to it is a click rather than a drag
+    let mut drag_start: Option<(f32, f32)> = None;
+    let mut pick: Option<Pick> = None;
+
+    let mut export_settings = ExportSettings::default();
+    // Set with `P` to save the painted view next to the scene file
+    let mut screenshot_requested = false;
+    let mut save_scene_requested = false;
+    // Set when the timings change abruptly, so the averages don't mix in stale frames
+    let mut reset_timings = false;
+
+    let mut scene_path = args.scene.clone();
+    let mut animation_time = 0.0;
+    let mut animation_playing = scene.animation.is_some();
+    let mut current_scene = scene;
+    let mut load_scene_request: Option<PathBuf> = None;
+    let mut load_error: Option<String> = None;
+
+    // Quantization restored when toggling it back on with `K`
+    let mut saved_quantization = if data.params.quantization != 0 {
+        data.params.quantization
+    } else {
+        8
+    };
+
+    let idle_timeout = args.idle_timeout.map(Duration::from_secs_f32);
+    let mut last_input = Instant::now();
+    let mut idle = false;
 
     event_loop.run(move |ev, _, control_flow| {
         match ev {
             Event::WindowEvent { event, .. } => {
+                last_input = Instant::now();
+                if idle {
+                    // Draw right away instead of waiting for the next idle frame
+                    idle = false;
+                    control_flow.set_wait_until(last_input);
+                }
+
                 let response = egui_glium.on_event(&event);
+                if response.consumed
+                    && matches!(
+                        event,
+                        WindowEvent::MouseInput {
+                            state: ElementState::Released,
+                            button: MouseButton::Left,
+                            ..
+                        }
+                    )
+                {
+                    // The drag ended over the UI overlay
+                    drag_start = None;
+                }
                 if !response.consumed {
                     match event {
                         WindowEvent::Resized(size) => {
+                            // Minimized
+                            if size.width == 0 || size.height == 0 {
+                                return;
+                            }
                             let aspect = size.width as f32 / size.height as f32;
-                            let mut camera = state.camera.lock().unwrap();
-                            camera.set_aspect(aspect);
+                            state.camera.lock().unwrap().set_aspect(aspect);
+                            if let Err(e) = data.resize(&display, size.width, size.height) {
+                                error!("{e}");
+                            }
                             return;
                         }
                         WindowEvent::CloseRequested => {
@@ -252,13 +1299,84 @@ fn main() {
                                         let mut view = state.view_state.lock().unwrap();
                                         *view = match *view {
                                             ViewState::Full => ViewState::Raster,
-                                            ViewState::Raster => ViewState::Full,
+                                            ViewState::Raster => ViewState::Normals,
+                                            ViewState::Normals => ViewState::Wireframe,
+                                            ViewState::Wireframe => ViewState::Full,
                                         };
+                                        reset_timings = true;
                                     }
                                     VirtualKeyCode::G => {
                                         let v = state.enable_gui.load(Ordering::Acquire);
                                         state.enable_gui.store(!v, Ordering::Release);
                                     }
+                                    VirtualKeyCode::O => {
+                                        let overlay = !state.fps_overlay.load(Ordering::Relaxed);
+                                        state.fps_overlay.store(overlay, Ordering::Relaxed);
+                                        state.show_toast(format!(
+                                            "FPS overlay: {}",
+                                            on_off(overlay)
+                                        ));
+                                    }
+                                    VirtualKeyCode::F11 => {
+                                        let gl_window = display.gl_window();
+                                        let window = gl_window.window();
+                                        window.set_fullscreen(match window.fullscreen() {
+                                            Some(_) => None,
+                                            None => Some(Fullscreen::Borderless(None)),
+                                        });
+                                    }
+                                    VirtualKeyCode::F5 => {
+                                        load_scene_request = Some(scene_path.clone());
+                                    }
+                                    VirtualKeyCode::P => {
+                                        screenshot_requested = true;
+                                    }
+                                    VirtualKeyCode::R => {
+                                        data.params.sort_override =
+                                            Some(data.params.sort_order().reversed());
+                                    }
+                                    VirtualKeyCode::T => {
+                                        let turntable = !state.turntable.load(Ordering::Relaxed);
+                                        state.turntable.store(turntable, Ordering::Relaxed);
+                                        state.show_toast(format!(
+                                            "Turntable: {}",
+                                            on_off(turntable)
+                                        ));
+                                    }
+                                    VirtualKeyCode::B => {
+                                        data.params.enable_brush_tbn =
+                                            !data.params.enable_brush_tbn;
+                                        state.show_toast(format!(
+                                            "Brush TBN: {}",
+                                            on_off(data.params.enable_brush_tbn)
+                                        ));
+                                    }
+                                    VirtualKeyCode::C => {
+                                        data.params.enable_canvas = !data.params.enable_canvas;
+                                        state.show_toast(format!(
+                                            "Canvas: {}",
+                                            on_off(data.params.enable_canvas)
+                                        ));
+                                    }
+                                    VirtualKeyCode::F => {
+                                        if let Some((center, radius)) =
+                                            data.bounding_sphere(*state.model.lock().unwrap())
+                                        {
+                                            state.camera.lock().unwrap().frame(center, radius);
+                                        }
+                                    }
+                                    VirtualKeyCode::K => {
+                                        if data.params.quantization != 0 {
+                                            saved_quantization = data.params.quantization;
+                                            data.params.quantization = 0;
+                                            state.show_toast("Quantization: off".to_string());
+                                        } else {
+                                            data.params.quantization = saved_quantization;
+                                            state.show_toast(format!(
+                                                "Quantization: {saved_quantization}"
+                                            ));
+                                        }
+                                    }
                                     _ => (),
                                 }
                                 state.keys.lock().unwrap().insert(key);
@@ -267,6 +1385,59 @@ fn main() {
                             }
                             return;
                         }
+                        WindowEvent::CursorMoved { position, .. } => {
+                            let position = (position.x as f32, position.y as f32);
+                            if drag_start.is_some() {
+                                let mut drag_delta = state.drag_delta.lock().unwrap();
+                                let (dx, dy) = drag_delta.unwrap_or((0.0, 0.0));
+                                *drag_delta = Some((
+                                    dx + position.0 - cursor_position.0,
+                                    dy + position.1 - cursor_position.1,
+                                ));
+                            }
+                            cursor_position = position;
+                            return;
+                        }
+                        WindowEvent::MouseInput {
+                            state: ElementState::Pressed,
+                            button: MouseButton::Left,
+                            ..
+                        } => {
+                            drag_start = Some(cursor_position);
+                            return;
+                        }
+                        WindowEvent::MouseInput {
+                            state: ElementState::Released,
+                            button: MouseButton::Left,
+                            ..
+                        } => {
+                            let Some(start) = drag_start.take() else {
+                                return;
+                            };
+                            let moved =
+                                (cursor_position.0 - start.0).hypot(cursor_position.1 - start.1);
+                            if moved > CLICK_DISTANCE {
+                                return;
+                            }
+                            let model = *state.model.lock().unwrap();
+                            let camera = state.camera.lock().unwrap();
+                            pick = picking::pick(
+                                &data.models,
+                                &data.instance_transforms,
+                                model,
+                                &camera,
+                                cursor_position,
+                                display.get_framebuffer_dimensions(),
+                            );
+                            match &pick {
+                                Some(pick) => info!(
+                                    "Picked model {} ({}) at {:?}, uv {:?}",
+                                    pick.model_index, pick.model_name, pick.position, pick.uv
+                                ),
+                                None => info!("Picked nothing"),
+                            }
+                            return;
+                        }
                         WindowEvent::MouseWheel {
                             phase: TouchPhase::Ended,
                             ..
@@ -301,13 +1472,54 @@ fn main() {
             _ => return,
         }
 
-        let next_frame_time = Instant::now() + Duration::from_nanos(16_666_667);
-        control_flow.set_wait_until(next_frame_time);
+        // Held keys and scrolling keep moving the camera without sending new events, and the
+        // loading progress moves on its own
+        let active = !state.keys.lock().unwrap().is_empty()
+            || state.wheel_delta.lock().unwrap().is_some()
+            || state.drag_delta.lock().unwrap().is_some()
+            || state.turntable.load(Ordering::Relaxed)
+            || animation_playing
+            || data.pending_albedo.is_some()
+            || !data.pending_materials.is_empty();
+        if active {
+            last_input = Instant::now();
+        }
+        idle = idle_timeout.is_some_and(|timeout| last_input.elapsed() > timeout);
+
+        if !idle {
+            let next_frame_time = Instant::now() + Duration::from_nanos(16_666_667);
+            control_flow.set_wait_until(next_frame_time);
+        } else if args.idle_fps > 0.0 {
+            let next_frame_time = Instant::now() + Duration::from_secs_f32(1.0 / args.idle_fps);
+            control_flow.set_wait_until(next_frame_time);
+        } else {
+            control_flow.set_wait();
+        }
 
         let start = Instant::now();
 
+        let mut export_requested = false;
+
+        if let (Some(animation), true) = (&current_scene.animation, animation_playing) {
+            animation_time += true_frame_time.as_secs_f32();
+            let duration = animation.duration();
+            if animation.looping && duration > 0.0 {
+                animation_time %= duration;
+            }
+            data.params.apply_animation(animation, animation_time);
+        }
+
         // UI
-        if state.enable_gui.load(Ordering::Relaxed) {
+        if reset_timings {
+            reset_timings = false;
+            sort_time_average.reset();
+            fixed_time_average.reset();
+            draw_time_average.reset();
+            true_frame_time_average.reset();
+            sort_period_average.reset();
+        }
+        let enable_gui = state.enable_gui.load(Ordering::Relaxed);
+        if state.gui_visible() {
             sort_time_average
                 .add(state.debug_info.sort_time.load(Ordering::Relaxed) as f64 / 1000.0);
             fixed_time_average
@@ -316,9 +1528,123 @@ fn main() {
                 .add(state.debug_info.draw_time.load(Ordering::Relaxed) as f64 / 1000.0);
 
             true_frame_time_average.add(true_frame_time.as_secs_f64());
+            sort_period_average
+                .add(state.debug_info.sort_period.load(Ordering::Relaxed) as f64 / 1_000_000.0);
 
             egui_glium.run(&display, |egui_ctx| {
+                if let Some((message, shown)) = &*state.toast.lock().unwrap() {
+                    let remaining = TOAST_DURATION.saturating_sub(shown.elapsed());
+                    let opacity =
+                        (2.0 * remaining.as_secs_f32() / TOAST_DURATION.as_secs_f32()).min(1.0);
+                    if opacity > 0.0 {
+                        Area::new("toast")
+                            .anchor(Align2::CENTER_BOTTOM, [0.0, -32.0])
+                            .show(egui_ctx, |ui| {
+                                Frame::none()
+                                    .fill(Color32::from_black_alpha((180.0 * opacity) as u8))
+                                    .inner_margin(8.0)
+                                    .rounding(4.0)
+                                    .show(ui, |ui| {
+                                        ui.colored_label(
+                                            Color32::WHITE.linear_multiply(opacity),
+                                            message,
+                                        );
+                                    });
+                            });
+                    }
+                }
+
+                if !enable_gui {
+                    if state.fps_overlay.load(Ordering::Relaxed) {
+                        Area::new("fps_overlay")
+                            .anchor(Align2::RIGHT_TOP, [-8.0, 8.0])
+                            .show(egui_ctx, |ui| {
+                                ui.label(format!(
+                                    "FPS: {:.3} fps",
+                                    1.0 / true_frame_time_average.average()
+                                ));
+                                ui.label(format!("Draw: {:.3} ms", draw_time_average.average()));
+                                ui.label(format!("Sort: {:.3} ms", sort_time_average.average()));
+                            });
+                    }
+                    return;
+                }
+
                 SidePanel::left("my_side_panel").show(egui_ctx, |ui| {
+                    ui.heading("Scene");
+                    ui.label(scene_path.display().to_string());
+                    ui.horizontal(|ui| {
+                        if ui.button("Open Scene…").clicked() {
+                            load_scene_request = FileDialog::new()
+                                .add_filter("Scene", &["toml"])
+                                .set_directory(scene_path.parent().unwrap_or(Path::new(".")))
+                                .pick_file();
+                        }
+                        if ui
+                            .button("Save Scene")
+                            .on_hover_text(
+                                "Write the current settings and camera to the scene file",
+                            )
+                            .clicked()
+                        {
+                            save_scene_requested = true;
+                        }
+                    });
+                    if let Some(load_error) = &load_error {
+                        ui.colored_label(Color32::RED, load_error);
+                    }
+                    if let Some(animation) = &current_scene.animation {
+                        ui.horizontal(|ui| {
+                            ui.checkbox(&mut animation_playing, "Play Animation");
+                            if ui.button("Restart").clicked() {
+                                animation_time = 0.0;
+                                data.params.apply_animation(animation, animation_time);
+                            }
+                        });
+                        let scrubbed = ui.add(
+                            Slider::new(&mut animation_time, 0.0..=animation.duration())
+                                .text("Time (s)"),
+                        );
+                        if scrubbed.changed() {
+                            data.params.apply_animation(animation, animation_time);
+                        }
+                    }
+                    for pending in data.pending_albedo.iter().chain(&data.pending_materials) {
+                        ui.add(
+                            ProgressBar::new(pending.progress())
+                                .show_percentage()
+                                .text(format!("Loading '{}'", pending.path.display())),
+                        );
+                    }
+                    ui.collapsing("Shapes", |ui| {
+                        for model in &data.models {
+                            ui.label(format!(
+                                "{} ({} triangles, {} strokes)",
+                                model.model.name,
+                                model.model.mesh.indices.len() / 3,
+                                model.points.len()
+                            ));
+                        }
+                    });
+
+                    match *state.view_state.lock().unwrap() {
+                        ViewState::Raster | ViewState::Normals => {
+                            ui.heading("Raster");
+                            ui.horizontal(|ui| {
+                                ui.checkbox(&mut data.params.wire_overlay, "Wireframe Overlay");
+                                ui.color_edit_button_rgb(&mut data.params.wire_color);
+                            });
+                        }
+                        ViewState::Wireframe => {
+                            ui.heading("Wireframe");
+                            ui.horizontal(|ui| {
+                                ui.label("Wire Color");
+                                ui.color_edit_button_rgb(&mut data.params.wire_color);
+                            });
+                        }
+                        ViewState::Full => {}
+                    }
+
                     ui.heading("Painting");
                     ui.horizontal(|ui| {
                         let pd = ui.add(
@@ -328,7 +1654,21 @@ fn main() {
                         );
                         if pd.changed() {
                             state.stroke_density.store(point_density, Ordering::Relaxed);
-                            point_update_tx.send(()).unwrap();
+                            point_update_tx.send(PointUpdate::Regenerate).unwrap();
+                            reset_timings = true;
+                        }
+                    });
+                    ui.horizontal(|ui| {
+                        let mut limit_points = max_points.is_some();
+                        let mut limit = max_points.unwrap_or(100_000);
+                        let mut changed = ui.checkbox(&mut limit_points, "Max Points").changed();
+                        if limit_points {
+                            changed |= ui.add(DragValue::new(&mut limit).speed(100.0)).changed();
+                        }
+                        if changed {
+                            max_points = limit_points.then_some(limit);
+                            *state.max_points.lock().unwrap() = max_points;
+                            point_update_tx.send(PointUpdate::Regenerate).unwrap();
                         }
                     });
 
@@ -337,53 +1677,569 @@ fn main() {
                             .text("Quantization")
                             .clamp_to_range(false),
                     );
-                    ui.add(
-                        Slider::new(&mut data.params.brush_size, 0.01..=0.08)
+                    ui.horizontal(|ui| {
+                        ui.label("Light");
+                        for component in &mut data.params.light_dir {
+                            ui.add(
+                                DragValue::new(component)
+                                    .speed(0.01)
+                                    .clamp_range(-1.0..=1.0),
+                            );
+                        }
+                        ui.color_edit_button_rgb(&mut data.params.light_color);
+                    });
+                    ui.add(Slider::new(&mut data.params.ambient, 0.0..=1.0).text("Ambient"));
+                    ui.add(
+                        Slider::new(&mut data.params.brush_size, 0.01..=0.08)
                             .text("Brush Size")
                             .clamp_to_range(false),
                     );
+                    ui.add(
+                        Slider::new(&mut data.params.brush_jitter, 0.0..=1.0).text("Brush Jitter"),
+                    );
+                    ui.add(
+                        Slider::new(&mut data.params.stroke_rotation_jitter, 0.0..=1.0)
+                            .text("Rotation Jitter"),
+                    );
+                    ui.add(
+                        Slider::new(&mut data.params.brush_size_jitter, 0.0..=1.0)
+                            .text("Size Jitter"),
+                    );
+                    ui.add(
+                        Slider::new(&mut data.params.stroke_length, 0.25..=4.0)
+                            .text("Stroke Length"),
+                    );
+                    ui.add(
+                        Slider::new(&mut data.params.length_jitter, 0.0..=1.0)
+                            .text("Length Jitter"),
+                    );
+                    ui.add(
+                        Slider::new(&mut data.params.view_angle_size, 0.0..=1.0)
+                            .text("View Angle Size"),
+                    );
+                    ui.add(
+                        Slider::new(&mut data.params.view_angle_min_size, 0.0..=1.0)
+                            .text("View Angle Min Size"),
+                    );
+                    ui.add(
+                        Slider::new(&mut data.params.facing_fade, 0.0..=1.0).text("Facing Fade"),
+                    );
+                    ui.checkbox(&mut data.params.cull_backfaces, "Cull Back Facing Strokes");
+                    ui.add(
+                        Slider::new(&mut data.params.depth_peel_layers, 0..=8)
+                            .text("Depth Peel Layers"),
+                    );
+                    ui.checkbox(
+                        &mut data.params.weighted_blending,
+                        "Weighted Blending (unsorted)",
+                    );
+                    ui.horizontal(|ui| {
+                        ui.checkbox(&mut data.params.adaptive_density, "Adaptive Density");
+                        if data.params.adaptive_density {
+                            ui.add(
+                                Slider::new(&mut data.params.target_coverage, 0.05..=1.0)
+                                    .text("Target Coverage"),
+                            );
+                        }
+                    });
+                    ComboBox::from_label("Stroke Style")
+                        .selected_text(data.params.stroke_style.name())
+                        .show_ui(ui, |ui| {
+                            for style in StrokeStyle::ALL {
+                                ui.selectable_value(
+                                    &mut data.params.stroke_style,
+                                    style,
+                                    style.name(),
+                                );
+                            }
+                        });
+                    ComboBox::from_label("Blend Mode")
+                        .selected_text(data.params.blend_mode.name())
+                        .show_ui(ui, |ui| {
+                            for mode in BlendMode::ALL {
+                                ui.selectable_value(&mut data.params.blend_mode, mode, mode.name());
+                            }
+                        });
+                    ComboBox::from_label("Sort Override")
+                        .selected_text(data.params.sort_override.map_or("Auto", SortOrder::name))
+                        .show_ui(ui, |ui| {
+                            ui.selectable_value(&mut data.params.sort_override, None, "Auto");
+                            for order in SortOrder::ALL {
+                                ui.selectable_value(
+                                    &mut data.params.sort_override,
+                                    Some(order),
+                                    order.name(),
+                                );
+                            }
+                        });
+                    ui.label(format!("sort: {}", data.params.sort_order().name()));
+                    if data.params.stroke_style == StrokeStyle::Point {
+                        ui.add(
+                            Slider::new(&mut data.params.point_size, 1.0..=32.0)
+                                .text("Point Size")
+                                .clamp_to_range(false),
+                        );
+                    }
                     ui.horizontal(|ui| {
                         ui.color_edit_button_rgb(&mut data.background);
                         ui.label("Background Color");
                     });
+                    ComboBox::from_label("Background Gradient")
+                        .selected_text(
+                            data.params
+                                .background_gradient
+                                .map_or("Flat", GradientStyle::name),
+                        )
+                        .show_ui(ui, |ui| {
+                            ui.selectable_value(&mut data.params.background_gradient, None, "Flat");
+                            for style in GradientStyle::ALL {
+                                ui.selectable_value(
+                                    &mut data.params.background_gradient,
+                                    Some(style),
+                                    style.name(),
+                                );
+                            }
+                        });
+                    if data.params.background_gradient.is_some() {
+                        ui.horizontal(|ui| {
+                            let [a, b] = &mut data.params.gradient_colors;
+                            ui.color_edit_button_rgb(a);
+                            ui.color_edit_button_rgb(b);
+                            ui.label("Gradient Colors");
+                        });
+                    }
+                    ui.horizontal(|ui| {
+                        ui.color_edit_button_rgb(&mut data.paint_background);
+                        ui.label("Paint Background Color");
+                    });
+
+                    ui.checkbox(&mut data.params.enable_underpainting, "Underpainting");
+                    if data.params.enable_underpainting {
+                        ui.add(
+                            Slider::new(&mut data.params.underpainting_opacity, 0.0..=1.0)
+                                .text("Underpainting Opacity"),
+                        );
+                    }
+
+                    if data.stroke_maps.flow.is_some() {
+                        ui.checkbox(&mut data.params.enable_flow, "Follow Albedo Flow");
+                    }
+                    if data.stroke_maps.albedo.is_some() {
+                        ui.checkbox(&mut data.params.flat_color, "Flat Stroke Color");
+                    }
+                    ui.add(Slider::new(&mut data.params.grain_strength, 0.0..=1.0).text("Grain"));
+                    if data.params.grain_strength > 0.0 {
+                        ui.add(
+                            Slider::new(&mut data.params.grain_scale, 0.01..=2.0)
+                                .text("Grain Scale")
+                                .logarithmic(true)
+                                .clamp_to_range(false),
+                        );
+                    }
+
+                    ui.horizontal(|ui| {
+                        ui.checkbox(&mut data.params.enable_haze, "Haze");
+                        ui.color_edit_button_rgb(&mut data.params.haze_color);
+                    });
+                    if data.params.enable_haze {
+                        ui.add(
+                            Slider::new(&mut data.params.haze_start, 0.0..=20.0)
+                                .text("Haze Start")
+                                .clamp_to_range(false),
+                        );
+                        ui.add(
+                            Slider::new(&mut data.params.haze_end, 0.0..=20.0)
+                                .text("Haze End")
+                                .clamp_to_range(false),
+                        );
+                    }
+                    ui.horizontal(|ui| {
+                        ui.checkbox(&mut data.params.enable_fog, "Fog");
+                        ui.color_edit_button_rgb(&mut data.params.fog_color);
+                    });
+                    if data.params.enable_fog {
+                        ui.add(
+                            Slider::new(&mut data.params.fog_start, 0.0..=20.0)
+                                .text("Fog Start")
+                                .clamp_to_range(false),
+                        );
+                        ui.add(
+                            Slider::new(&mut data.params.fog_end, 0.0..=20.0)
+                                .text("Fog End")
+                                .clamp_to_range(false),
+                        );
+                    }
+
+                    ui.collapsing("Camera", |ui| {
+                        let mut camera = state.camera.lock().unwrap();
+                        let mut moved = false;
+                        let drag_point = |ui: &mut egui::Ui, label, point: &mut Point3<f32>| {
+                            ui.horizontal(|ui| {
+                                let mut changed = false;
+                                for axis in [&mut point.x, &mut point.y, &mut point.z] {
+                                    changed |= ui.add(DragValue::new(axis).speed(0.01)).changed();
+                                }
+                                ui.label(label);
+                                changed
+                            })
+                            .inner
+                        };
+                        let mut position = camera.position();
+                        if drag_point(ui, "Position", &mut position) {
+                            camera.set_position(position);
+                            moved = true;
+                        }
+                        let mut target = camera.target();
+                        if drag_point(ui, "Target", &mut target) {
+                            camera.set_target(target);
+                            moved = true;
+                        }
+                        ui.horizontal(|ui| {
+                            // Seen from the same distance. Top is slightly tilted since the camera
+                            // can't look straight down.
+                            for (name, direction) in [
+                                ("Front", Vector3::unit_z()),
+                                ("Side", Vector3::unit_x()),
+                                ("Top", Vector3::new(0.0, 1.0, 0.1)),
+                                ("3/4", Vector3::new(1.0, 1.0, 1.0)),
+                            ] {
+                                if ui.button(name).clicked() {
+                                    let target = camera.target();
+                                    let distance = camera.position().distance(target);
+                                    camera.set_position(target + direction.normalize() * distance);
+                                    moved = true;
+                                }
+                            }
+                        });
+                        if moved {
+                            state.model_changed.store(true, Ordering::Relaxed);
+                        }
+
+                        let mut fov = Deg::from(camera.fov()).0;
+                        if ui
+                            .add(Slider::new(&mut fov, 10.0..=150.0).text("FOV").suffix("°"))
+                            .changed()
+                        {
+                            camera.set_fov(Deg(fov));
+                            state.model_changed.store(true, Ordering::Relaxed);
+                        }
+                        let (mut near, mut far) = (camera.near(), camera.far());
+                        let near_changed = ui
+                            .add(
+                                Slider::new(&mut near, 0.001..=10.0)
+                                    .text("Near")
+                                    .logarithmic(true),
+                            )
+                            .changed();
+                        let far_changed = ui
+                            .add(
+                                Slider::new(&mut far, 0.1..=1000.0)
+                                    .text("Far")
+                                    .logarithmic(true),
+                            )
+                            .changed();
+                        if near_changed || far_changed {
+                            // An empty depth range would draw nothing
+                            camera.set_near_far(near, far.max(near * 1.01));
+                            state.model_changed.store(true, Ordering::Relaxed);
+                        }
+                    });
+
+                    ui.heading("Model");
+                    ui.checkbox(&mut uniform_scale, "Uniform Scale");
+                    let mut new_scale = model_scale;
+                    if uniform_scale {
+                        ui.horizontal(|ui| {
+                            ui.add(
+                                DragValue::new(&mut new_scale.x)
+                                    .speed(0.01)
+                                    .clamp_range(0.01..=f32::MAX),
+                            );
+                            ui.label("Scale");
+                        });
+                        new_scale.y = new_scale.x;
+                        new_scale.z = new_scale.x;
+                    } else {
+                        ui.horizontal(|ui| {
+                            for axis in [&mut new_scale.x, &mut new_scale.y, &mut new_scale.z] {
+                                ui.add(
+                                    DragValue::new(axis)
+                                        .speed(0.01)
+                                        .clamp_range(0.01..=f32::MAX),
+                                );
+                            }
+                            ui.label("Scale");
+                        });
+                    }
+                    if new_scale != model_scale {
+                        let mut model = state.model.lock().unwrap();
+                        *model = *model
+                            * Matrix4::from_nonuniform_scale(
+                                new_scale.x / model_scale.x,
+                                new_scale.y / model_scale.y,
+                                new_scale.z / model_scale.z,
+                            );
+                        model_scale = new_scale;
+                        state.model_changed.store(true, Ordering::Relaxed);
+                        // Keeps the strokes as dense on the resized surfaces
+                        *state.area_scale.lock().unwrap() = model_area_scale(model_scale);
+                        point_update_tx.send(PointUpdate::Regenerate).unwrap();
+                    }
+                    ui.horizontal(|ui| {
+                        let mut turntable = state.turntable.load(Ordering::Relaxed);
+                        if ui.checkbox(&mut turntable, "Turntable").changed() {
+                            state.turntable.store(turntable, Ordering::Relaxed);
+                        }
+                        ui.add(
+                            DragValue::new(&mut *state.turntable_speed.lock().unwrap())
+                                .speed(1.0)
+                                .suffix(" °/s"),
+                        );
+                    });
+                    let mut smooth_zoom = state.smooth_zoom.load(Ordering::Relaxed);
+                    if ui.checkbox(&mut smooth_zoom, "Smooth Zoom").changed() {
+                        state.smooth_zoom.store(smooth_zoom, Ordering::Relaxed);
+                    }
 
                     ui.heading("Post Processing");
+                    ui.add(
+                        Slider::new(&mut data.params.exposure, 0.1..=8.0)
+                            .text("Exposure")
+                            .logarithmic(true)
+                            .clamp_to_range(false),
+                    );
+                    ComboBox::from_label("Tone Mapping")
+                        .selected_text(data.params.tonemap.name())
+                        .show_ui(ui, |ui| {
+                            for tonemap in Tonemap::ALL {
+                                ui.selectable_value(
+                                    &mut data.params.tonemap,
+                                    tonemap,
+                                    tonemap.name(),
+                                );
+                            }
+                        });
+                    for (label, grade, speed, range) in [
+                        ("Lift", &mut data.params.lift, 0.005, -0.5..=0.5),
+                        ("Gamma", &mut data.params.gamma, 0.01, 0.2..=5.0),
+                        ("Gain", &mut data.params.gain, 0.01, 0.0..=4.0),
+                    ] {
+                        ui.horizontal(|ui| {
+                            ui.label(label);
+                            for component in grade {
+                                ui.add(
+                                    DragValue::new(component)
+                                        .speed(speed)
+                                        .clamp_range(range.clone()),
+                                );
+                            }
+                        });
+                    }
+                    ui.add(
+                        Slider::new(&mut data.params.hue_shift, -180.0..=180.0).text("Hue Shift"),
+                    );
                     ui.add(
                         Slider::new(&mut data.params.saturation, 0.0..=2.0)
                             .text("Saturation")
                             .clamp_to_range(false),
                     );
+                    ui.add(Slider::new(&mut data.params.smudge_radius, 0..=10).text("Smudge"));
+                    ui.checkbox(&mut data.params.enable_bloom, "Bloom");
+                    if data.params.enable_bloom {
+                        ui.add(
+                            Slider::new(&mut data.params.bloom_threshold, 0.0..=1.0)
+                                .text("Bloom Threshold"),
+                        );
+                        ui.add(
+                            Slider::new(&mut data.params.bloom_intensity, 0.0..=2.0)
+                                .text("Bloom Intensity")
+                                .clamp_to_range(false),
+                        );
+                    }
+                    ui.checkbox(&mut data.params.enable_outline, "Outline");
+                    if data.params.enable_outline {
+                        ui.horizontal(|ui| {
+                            ui.add(
+                                Slider::new(&mut data.params.outline_thickness, 0.5..=6.0)
+                                    .text("Outline Thickness"),
+                            );
+                            ui.color_edit_button_rgb(&mut data.params.outline_color);
+                        });
+                        ui.add(
+                            Slider::new(&mut data.params.outline_threshold, 0.05..=2.0)
+                                .text("Outline Threshold")
+                                .logarithmic(true),
+                        );
+                    }
                     ui.checkbox(&mut data.params.enable_canvas, "Enable Canvas");
+                    if data.params.enable_canvas {
+                        ui.add(
+                            Slider::new(&mut data.params.canvas_strength, 0.0..=1.0)
+                                .text("Canvas Strength"),
+                        );
+                        ui.add(
+                            Slider::new(&mut data.params.canvas_scale, 0.25..=8.0)
+                                .text("Canvas Scale")
+                                .logarithmic(true),
+                        );
+                    }
+                    ui.checkbox(&mut data.params.enable_vignette, "Vignette");
+                    if data.params.enable_vignette {
+                        ui.add(
+                            Slider::new(&mut data.params.vignette_radius, 0.0..=1.5)
+                                .text("Vignette Radius"),
+                        );
+                        ui.add(
+                            Slider::new(&mut data.params.vignette_softness, 0.01..=1.5)
+                                .text("Vignette Softness"),
+                        );
+                    }
                     ui.checkbox(&mut data.params.enable_brush_tbn, "Enable Brush TBN");
 
+                    ui.heading("Export");
+                    ui.add(
+                        Slider::new(&mut export_settings.supersample, 1..=4).text("Supersample"),
+                    );
+                    ComboBox::from_label("Resolve Filter")
+                        .selected_text(export_settings.filter.name())
+                        .show_ui(ui, |ui| {
+                            for filter in ResolveFilter::ALL {
+                                ui.selectable_value(
+                                    &mut export_settings.filter,
+                                    filter,
+                                    filter.name(),
+                                );
+                            }
+                        });
+                    if ui.button("Export PNG").clicked() {
+                        export_requested = true;
+                    }
+
                     ui.heading("Speed");
 
-                    ui.label(format!("Draw time: {:.3} ms", draw_time_average.average()));
+                    {
+                        let strokes: usize = data.models.iter().map(|m| m.points.len()).sum();
+                        let bytes: usize = data
+                            .models
+                            .iter()
+                            .map(|m| m.point_buffers.0.get_size())
+                            .sum();
+                        ui.label(format!(
+                            "Strokes: {strokes} ({:.1} MiB of vertex buffers)",
+                            bytes as f64 / (1024.0 * 1024.0)
+                        ));
+                        if data.instance_transforms.len() > 1 {
+                            ui.label(format!(
+                                "Drawn {} times, {} strokes in total",
+                                data.instance_transforms.len(),
+                                strokes * data.instance_transforms.len()
+                            ));
+                        }
+                    }
+
+                    // Mean ± standard deviation, with the range on hover
+                    let time_label = |ui: &mut egui::Ui, name, average: &RunningAverage<_, 32>| {
+                        ui.label(format!(
+                            "{name} time: {:.3} ± {:.3} ms",
+                            average.average(),
+                            average.std_dev()
+                        ))
+                        .on_hover_text(format!(
+                            "min {:.3} ms, max {:.3} ms over the last {} frames",
+                            average.min(),
+                            average.max(),
+                            average.len()
+                        ));
+                    };
+                    time_label(ui, "Draw", &draw_time_average);
+                    time_label(ui, "Fixed", &fixed_time_average);
+                    time_label(ui, "Sort", &sort_time_average);
+
+                    ui.horizontal(|ui| {
+                        let mut sort_hz = state.sort_hz.load(Ordering::Relaxed);
+                        if ui
+                            .add(Slider::new(&mut sort_hz, 1..=120).text("Sort Rate (Hz)"))
+                            .changed()
+                        {
+                            state.sort_hz.store(sort_hz, Ordering::Relaxed);
+                        }
+                    });
+                    ui.add(
+                        Slider::new(&mut *state.sort_angle_threshold.lock().unwrap(), 0.0..=5.0)
+                            .text("Re-sort Angle (°)"),
+                    );
+                    ui.add(
+                        Slider::new(
+                            &mut *state.sort_distance_threshold.lock().unwrap(),
+                            0.0..=0.1,
+                        )
+                        .text("Re-sort Distance"),
+                    );
 
                     ui.label(format!(
-                        "Fixed time: {:.3} ms",
-                        fixed_time_average.average()
+                        "Effective sort rate: {:.1} Hz",
+                        1.0 / sort_period_average.average()
                     ));
 
-                    ui.label(format!("Sort time: {:.3} ms", sort_time_average.average()));
-
                     ui.label(format!(
-                        "FPS: {:.3} fps",
-                        1.0 / true_frame_time_average.average()
+                        "FPS: {:.3} fps{}",
+                        1.0 / true_frame_time_average.average(),
+                        if true_frame_time_average.is_full() {
+                            ""
+                        } else {
+                            " (measuring)"
+                        }
                     ));
+
+                    ui.heading("Picking");
+
+                    match &pick {
+                        Some(pick) => {
+                            ui.label(format!("Model: {} ({})", pick.model_name, pick.model_index));
+                            ui.label(format!(
+                                "Position: ({:.3}, {:.3}, {:.3})",
+                                pick.position.x, pick.position.y, pick.position.z
+                            ));
+                            match pick.uv {
+                                Some(uv) => ui.label(format!("UV: ({:.3}, {:.3})", uv[0], uv[1])),
+                                None => ui.label("UV: none"),
+                            };
+                        }
+                        None => {
+                            ui.label("Click the model to pick a point");
+                        }
+                    }
                 });
             });
         }
 
+        {
+            let sort_order = data.params.sort_order();
+            let mut state_sort_order = state.sort_order.lock().unwrap();
+            if *state_sort_order != sort_order {
+                *state_sort_order = sort_order;
+                state.model_changed.store(true, Ordering::Relaxed);
+            }
+            state
+                .sorting
+                .store(!data.params.order_independent(), Ordering::Relaxed);
+            *state.adaptive_density.lock().unwrap() = data
+                .params
+                .adaptive_density
+                .then_some(data.params.target_coverage);
+        }
+
         {
             let mut last_points = None;
             while let Ok(points) = rx.try_recv() {
                 last_points = Some(points);
             }
-            if let Some(points) = last_points {
-                for (i, points) in points.into_iter().enumerate() {
-                    data.models[i].point_buffers = gen_point_buffers(&display, &points);
-                    data.models[i].points = points;
+            if let Some((points, instances)) = last_points {
+                data.instance_buffer = gen_instance_buffer(&display, &instances);
+                // Points sorted before a scene reload may not match the current models
+                for (model, points) in data.models.iter_mut().zip(points) {
+                    model.point_buffers = gen_point_buffers(&display, &points);
+                    model.points = points;
                 }
             }
         }
@@ -393,218 +2249,1852 @@ fn main() {
             .draw_time
             .store(start.elapsed().as_micros() as u64, Ordering::Release);
 
+        if let Err(e) = data.finish_albedo(&display, false) {
+            error!("{e}");
+            load_error = Some(e.to_string());
+        }
+
+        if let Some(path) = load_scene_request.take() {
+            info!("Loading scene '{}'", path.display());
+            match Scene::load(&path).and_then(|mut loaded| {
+                loaded.scene.points_cache = points_cache.clone();
+                data.load_scene(&display, &loaded.scene)?;
+                Ok(loaded)
+            }) {
+                Ok(LoadedScene { scene, dir }) => {
+                    let (width, height) = display.get_framebuffer_dimensions();
+                    *state.camera.lock().unwrap() = scene.camera(width as f32 / height as f32);
+                    *state.model.lock().unwrap() = scene.model_matrix();
+                    model_scale = scene.model_scale();
+                    *state.area_scale.lock().unwrap() = scene.area_scale();
+                    state.model_changed.store(true, Ordering::Relaxed);
+
+                    max_points = scene.max_points;
+                    *state.max_points.lock().unwrap() = max_points;
+                    *state.seed.lock().unwrap() = scene.seed;
+                    *state.sampling.lock().unwrap() = scene.sampling.unwrap_or_default();
+
+                    state
+                        .sort_hz
+                        .store(scene.sort_hz.unwrap_or(60), Ordering::Relaxed);
+                    *state.sort_angle_threshold.lock().unwrap() = scene
+                        .sort_angle_threshold
+                        .unwrap_or(DEFAULT_SORT_ANGLE_THRESHOLD);
+                    *state.sort_distance_threshold.lock().unwrap() = scene
+                        .sort_distance_threshold
+                        .unwrap_or(DEFAULT_SORT_DISTANCE_THRESHOLD);
+                    *state.lod_reference_distance.lock().unwrap() = scene.camera_distance();
+                    state
+                        .turntable
+                        .store(scene.turntable_speed.is_some(), Ordering::Relaxed);
+                    *state.turntable_speed.lock().unwrap() =
+                        scene.turntable_speed.unwrap_or(DEFAULT_TURNTABLE_SPEED);
+
+                    point_density = scene.stroke_density;
+                    state.stroke_density.store(point_density, Ordering::Relaxed);
+                    reset_timings = true;
+                    point_update_tx
+                        .send(PointUpdate::Scene(
+                            data.models.iter().map(ModelData::scene_model).collect(),
+                            Box::new(data.stroke_maps.clone()),
+                            data.instance_transforms.clone(),
+                        ))
+                        .unwrap();
+                    pick = None;
+
+                    animation_time = 0.0;
+                    animation_playing = scene.animation.is_some();
+                    scene_path = path;
+                    scene_dir = dir;
+                    current_scene = scene;
+                    load_error = None;
+                }
+                Err(e) => {
+                    error!("{e}");
+                    load_error = Some(e.to_string());
+                }
+            }
+        }
+
+        if screenshot_requested {
+            screenshot_requested = false;
+            if let Err(e) = data.finish_albedo(&display, true) {
+                error!("{e}");
+                load_error = Some(e.to_string());
+            }
+            let timestamp = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_millis();
+            // Milliseconds so screenshots taken in the same second don't overwrite each other
+            let path = scene_dir.join(format!("paint_render_{timestamp}.png"));
+            // Renders offscreen, so the UI overlay isn't captured whatever the view
+            match export_frame(&state, &display, &data, &path, ExportSettings::default()) {
+                Ok(()) => {
+                    info!("Saved screenshot to '{}'", path.display());
+                    state.show_toast(format!("Saved {}", path.display()));
+                }
+                Err(e) => {
+                    error!("Failed to save screenshot: {e}");
+                    state.show_toast("Failed to save screenshot".to_string());
+                }
+            }
+        }
+
+        if save_scene_requested {
+            save_scene_requested = false;
+            match Scene::save_tuning(&scene_path, &state, &data, point_density) {
+                Ok(()) => {
+                    info!("Saved scene to '{}'", scene_path.display());
+                    state.show_toast(format!("Saved {}", scene_path.display()));
+                }
+                Err(e) => {
+                    error!("Failed to save scene to '{}': {e}", scene_path.display());
+                    state.show_toast("Failed to save scene".to_string());
+                }
+            }
+        }
+
+        if export_requested {
+            if let Err(e) = data.finish_albedo(&display, true) {
+                error!("{e}");
+                load_error = Some(e.to_string());
+            }
+            let timestamp = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs();
+            let path = PathBuf::from(format!("paint_render_{timestamp}.png"));
+            match export_frame(&state, &display, &data, &path, export_settings) {
+                Ok(()) => info!("Exported frame to '{}'", path.display()),
+                Err(e) => error!("{e}"),
+            }
+
+            if let Some(manifest_path) = &args.manifest {
+                let assets = current_scene
+                    .asset_paths()
+                    .into_iter()
+                    .map(|path| Asset::hash(&path, &scene_dir))
+                    .collect::<Result<Vec<_>, _>>();
+                let result = assets.and_then(|assets| {
+                    let manifest = Manifest {
+                        crate_version: env!("CARGO_PKG_VERSION"),
+                        scene_file: &scene_path,
+                        image: &path,
+                        assets,
+                        stroke_density: state.stroke_density.load(Ordering::Relaxed),
+                        max_points,
+                        seed: current_scene.seed,
+                        background: data.background,
+                        params: &data.params,
+                        model_matrix: (*state.model.lock().unwrap()).into(),
+                        camera: (&*state.camera.lock().unwrap()).into(),
+                        export: export_settings,
+                    };
+                    manifest::write_manifest(manifest_path, &manifest)
+                });
+                match result {
+                    Ok(()) => info!("Wrote manifest to '{}'", manifest_path.display()),
+                    Err(e) => error!(
+                        "Failed to write manifest to '{}': {e}",
+                        manifest_path.display()
+                    ),
+                }
+            }
+        }
+
         draw(&state, &display, &data, &mut egui_glium);
 
         true_frame_time = true_frame_time_start.elapsed();
         true_frame_time_start = Instant::now();
+
+        if let Some(log) = &mut stats_log {
+            // Frames between sorts have no sort time of their own
+            let sorts = state.debug_info.sorts.load(Ordering::Relaxed);
+            let sort =
+                (sorts != logged_sorts).then(|| state.debug_info.sort_time.load(Ordering::Relaxed));
+            logged_sorts = sorts;
+            let stats = FrameStats {
+                draw: state.debug_info.draw_time.load(Ordering::Relaxed),
+                sort,
+                fixed: state.debug_info.fixed_time.load(Ordering::Relaxed),
+                frame: true_frame_time.as_micros() as u64,
+                // Adaptive density draws fewer strokes than the models have
+                points: data.models.iter().map(|m| m.point_buffers.0.len()).sum(),
+            };
+            if let Err(e) = log.write_frame(&stats) {
+                error!("Failed to write frame stats, stopping: {e}");
+                stats_log = None;
+            }
+        }
     });
 }
 
 fn image_to_texture(
-    display: &Display,
+    display: &impl Facade,
     image: ImageBuffer<Rgba<u8>, Vec<u8>>,
-) -> CompressedSrgbTexture2d {
+) -> Result<CompressedSrgbTexture2d, LoadError> {
     let image_dimensions = image.dimensions();
     let image =
         glium::texture::RawImage2d::from_raw_rgba_reversed(&image.into_raw(), image_dimensions);
-    CompressedSrgbTexture2d::new(display, image).unwrap()
+    CompressedSrgbTexture2d::new(display, image)
+        .map_err(|e| LoadError::Gl("texture", e.to_string()))
 }
 
-fn init_draw_data(display: &Display, scene: &Scene, scene_base_dir: &Path) -> DrawData {
-    let color_program =
-        Program::from_source(display, shaders::COLOR_VERT, shaders::COLOR_FRAG, None).unwrap();
-
-    let point_program = Program::new(
+/// The texture coordinates of the brushes in `atlas`, as a row of one float texel per brush
+fn brush_rects_texture(display: &impl Facade, atlas: &BrushAtlas) -> Result<Texture2d, LoadError> {
+    let rects = atlas.uv_rects();
+    let image = RawImage2d {
+        data: rects.iter().flatten().copied().collect::<Vec<_>>().into(),
+        width: rects.len() as u32,
+        height: 1,
+        format: ClientFormat::F32F32F32F32,
+    };
+    Texture2d::with_format(
         display,
-        ProgramCreationInput::SourceCode {
-            vertex_shader: shaders::POINT_VERT,
-            fragment_shader: shaders::POINT_FRAG,
-            geometry_shader: Some(shaders::POINT_GEOM),
-            tessellation_control_shader: None,
-            tessellation_evaluation_shader: None,
-            transform_feedback_varyings: None,
-            outputs_srgb: false,
-            uses_point_size: true,
-        },
+        image,
+        UncompressedFloatFormat::F32F32F32F32,
+        MipmapsOption::NoMipmap,
     )
-    .unwrap();
-
-    let post_process_program =
-        Program::from_source(display, shaders::POST_VERT, shaders::POST_FRAG, None).unwrap();
-
-    let brush_stroke = ImageReader::new(Cursor::new(BRUSHES_PNG))
-        .with_guessed_format()
-        .unwrap()
-        .decode()
-        .unwrap()
-        .into_rgba8();
-    let brush_stroke = image_to_texture(display, brush_stroke);
-
-    let albedo_texture = image::open(scene_base_dir.join(&scene.albedo_texture))
-        .unwrap()
-        .into_rgba8();
-    let albedo_texture = image_to_texture(display, albedo_texture);
+    .map_err(|e| LoadError::Gl("brush rects texture", e.to_string()))
+}
 
-    let canvas_texture = ImageReader::new(Cursor::new(CANVAS_PNG))
+/// Decodes an image built into the executable, `name` is used in errors
+fn decode_builtin(name: &str, bytes: &[u8]) -> Result<RgbaImage, LoadError> {
+    ImageReader::new(Cursor::new(bytes))
         .with_guessed_format()
-        .unwrap()
+        .map_err(|e| LoadError::Io(name.into(), e))?
         .decode()
-        .unwrap()
-        .into_rgba8();
-    let canvas_texture = image_to_texture(display, canvas_texture);
-
-    let models = gen_models(
-        scene_base_dir.join(&scene.obj_file),
-        scene.stroke_density as f32,
-        display,
-    );
+        .map(DynamicImage::into_rgba8)
+        .map_err(|e| LoadError::Image(name.into(), e))
+}
 
-    let post_process_texture = SrgbTexture2d::empty(
+/// 1x1 mid gray texture shown in place of textures that aren't loaded
+fn placeholder_texture(display: &impl Facade) -> Result<CompressedSrgbTexture2d, LoadError> {
+    image_to_texture(
         display,
-        display.get_framebuffer_dimensions().0,
-        display.get_framebuffer_dimensions().1,
+        ImageBuffer::from_pixel(1, 1, Rgba([128, 128, 128, 255])),
     )
-    .unwrap();
-
-    let params = Params {
-        quantization: scene.quantization,
-        brush_size: scene.brush_size,
-        enable_canvas: true,
-        saturation: scene.saturation.unwrap_or(1.0),
-        enable_brush_tbn: true,
-    };
+}
 
-    let post_quad_vert = vec![
-        PostProcessVert {
-            // BL
-            position: [-1.0, -1.0],
-        },
-        PostProcessVert {
-            // BR
-            position: [1.0, -1.0],
-        },
-        PostProcessVert {
-            // TR
-            position: [1.0, 1.0],
-        },
-        PostProcessVert {
-            // TL
-            position: [-1.0, 1.0],
-        },
-    ];
+/// An OpenGL context for rendering offscreen at `width` by `height`, with the event loop it was
+/// created through. The context comes from the display server when there is one, otherwise from
+/// OSMesa's software renderer, so rendering works on a machine without a display too. The event
+/// loop has to outlive the renderer.
+fn headless_renderer(
+    width: u32,
+    height: u32,
+) -> Result<(Option<EventLoop<()>>, HeadlessRenderer), String> {
+    let size = PhysicalSize::new(width, height);
+    let builder = ContextBuilder::new().with_depth_buffer(24);
+    // winit panics rather than returning an error when it can't reach a display server
+    let hook = panic::take_hook();
+    panic::set_hook(Box::new(|_| {}));
+    let event_loop = panic::catch_unwind(EventLoop::new).ok();
+    panic::set_hook(hook);
 
-    // TL ---- TR
-    // |  ^     ^
-    // v   \    |
-    // BL >--- BR
+    let context = match &event_loop {
+        Some(event_loop) => builder
+            .build_headless(event_loop, size)
+            .map_err(|e| e.to_string()),
+        None => build_osmesa(builder, size).map_err(|e| {
+            format!("{e} (without a display server, rendering needs OSMesa, e.g. libosmesa6)")
+        }),
+    }?;
+    let renderer = HeadlessRenderer::new(context).map_err(|e| e.to_string())?;
+    Ok((event_loop, renderer))
+}
 
-    let post_quad_indices = vec![0u8, 1, 3, 1, 2, 3];
+#[cfg(any(
+    target_os = "linux",
+    target_os = "dragonfly",
+    target_os = "freebsd",
+    target_os = "netbsd",
+    target_os = "openbsd",
+))]
+fn build_osmesa(
+    builder: ContextBuilder<NotCurrent>,
+    size: PhysicalSize<u32>,
+) -> Result<Context<NotCurrent>, CreationError> {
+    use glium::glutin::platform::unix::HeadlessContextExt;
+    builder.build_osmesa(size)
+}
 
-    let post_quad_vertex_buffer = VertexBuffer::new(display, &post_quad_vert).unwrap();
-    let post_quad_index_buffer =
-        IndexBuffer::new(display, PrimitiveType::TrianglesList, &post_quad_indices).unwrap();
+#[cfg(not(any(
+    target_os = "linux",
+    target_os = "dragonfly",
+    target_os = "freebsd",
+    target_os = "netbsd",
+    target_os = "openbsd",
+)))]
+fn build_osmesa(
+    _builder: ContextBuilder<NotCurrent>,
+    _size: PhysicalSize<u32>,
+) -> Result<Context<NotCurrent>, CreationError> {
+    Err(CreationError::NotSupported(
+        "OSMesa is not available on this platform".to_string(),
+    ))
+}
 
-    DrawData {
-        color_program,
-        point_program,
-        brush_stroke,
-        albedo_texture,
-        canvas_texture,
-        models,
-        post_process_quad: (post_quad_vertex_buffer, post_quad_index_buffer),
-        post_process_texture,
-        post_process_program,
-        params,
-        background: [scene.background.0, scene.background.1, scene.background.2],
-    }
+/// Everything in `DrawData` that depends on the scene file
+struct SceneResources {
+    models: Vec<ModelData>,
+    instance_transforms: Vec<Matrix4<f32>>,
+    stroke_maps: StrokeMaps,
+    albedo_texture: CompressedSrgbTexture2d,
+    pending_albedo: Option<PendingImage>,
+    object_albedos: Vec<Option<CompressedSrgbTexture2d>>,
+    pending_materials: Vec<PendingImage>,
+    bounds: Option<(Point3<f32>, Point3<f32>)>,
+    canvas_texture: CompressedSrgbTexture2d,
+    brush_stroke: CompressedSrgbTexture2d,
+    brush_rects: Texture2d,
 }
 
-fn fixed_update(
-    state: Arc<State>,
-    mut points_m: Vec<Vec<Point>>,
-    models: Vec<Model>,
-    points_sender: Sender<Vec<Vec<Point>>>,
-    point_update_rx: Receiver<()>,
-) {
-    let latest = Arc::new(Mutex::new(
-        None::<(Matrix4<f32>, Matrix4<f32>, Matrix4<f32>, bool)>,
-    ));
+fn open_image(path: PathBuf) -> Result<DynamicImage, LoadError> {
+    image::open(&path).map_err(|e| LoadError::Image(path, e))
+}
+
+/// An image being decoded on a background thread
+struct PendingImage {
+    path: PathBuf,
+    receiver: Receiver<Result<RgbaImage, LoadError>>,
+    /// Bytes of the file the decoder has read so far
+    bytes_read: Arc<AtomicU64>,
+    /// Size of the file in bytes
+    size: u64,
+}
+
+impl PendingImage {
+    /// An image that is already decoded
+    fn ready(path: PathBuf, image: RgbaImage) -> Self {
+        let (sender, receiver) = channel();
+        sender.send(Ok(image)).unwrap();
+        Self {
+            path,
+            receiver,
+            bytes_read: Arc::new(AtomicU64::new(1)),
+            size: 1,
+        }
+    }
+
+    /// The decoded image, blocking until it is with `wait`, or `None` while it is still decoding
+    fn poll(&self, wait: bool) -> Option<Result<RgbaImage, LoadError>> {
+        let image = if wait {
+            self.receiver.recv().ok()
+        } else {
+            match self.receiver.try_recv() {
+                Ok(image) => Some(image),
+                Err(TryRecvError::Empty) => return None,
+                Err(TryRecvError::Disconnected) => None,
+            }
+        };
+        // The decoding thread panicked
+        Some(image.unwrap_or_else(|| {
+            Err(LoadError::Io(
+                self.path.clone(),
+                io::Error::other("decoding stopped without a result"),
+            ))
+        }))
+    }
+
+    /// Fraction of the file decoded so far. Decoders read the file as they go, so this follows
+    /// the decoding closely.
+    fn progress(&self) -> f32 {
+        let bytes_read = self.bytes_read.load(Ordering::Relaxed);
+        (bytes_read as f64 / self.size.max(1) as f64).min(1.0) as f32
+    }
+}
+
+/// Reads from `inner`, keeping count of how far into it the reads got
+struct CountingReader<R> {
+    inner: R,
+    position: Arc<AtomicU64>,
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.position.fetch_add(read as u64, Ordering::Relaxed);
+        Ok(read)
+    }
+}
+
+impl<R: Seek> Seek for CountingReader<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let position = self.inner.seek(pos)?;
+        self.position.store(position, Ordering::Relaxed);
+        Ok(position)
+    }
+}
+
+/// Starts decoding the image at `path` on a background thread. Errors opening the file are
+/// returned right away, decoding errors once the image is received.
+fn decode_in_background(path: PathBuf) -> Result<PendingImage, LoadError> {
+    let bytes_read = Arc::new(AtomicU64::new(0));
+    let (reader, size) = fs::File::open(&path)
+        .and_then(|file| {
+            let size = file.metadata()?.len();
+            let file = CountingReader {
+                inner: file,
+                position: bytes_read.clone(),
+            };
+            let reader = ImageReader::new(BufReader::new(file)).with_guessed_format()?;
+            Ok((reader, size))
+        })
+        .map_err(|e| LoadError::Io(path.clone(), e))?;
+
+    info!("Decoding '{}' in the background", path.display());
+    let (sender, receiver) = channel();
+    let thread_path = path.clone();
+    thread::spawn(move || {
+        let start = Instant::now();
+        let image = reader
+            .decode()
+            .map(DynamicImage::into_rgba8)
+            .map_err(|e| LoadError::Image(thread_path.clone(), e));
+        if let Ok(image) = &image {
+            info!(
+                "Decoded '{}' ({}x{}, {:?})",
+                thread_path.display(),
+                image.width(),
+                image.height(),
+                start.elapsed()
+            );
+        }
+        // The receiver is gone if another scene was loaded in the meantime
+        let _ = sender.send(image);
+    });
+
+    Ok(PendingImage {
+        path,
+        receiver,
+        bytes_read,
+        size,
+    })
+}
+
+/// Header describing the strokes of `models` generated for `scene`
+fn points_header(
+    scene: &Scene,
+    shapes: impl Iterator<Item = String>,
+) -> Result<PointsHeader, PointsError> {
+    Ok(PointsHeader {
+        stroke_density: scene.stroke_density,
+        max_points: scene.max_points,
+        seed: scene.seed,
+        obj_sha256: scene
+            .objects
+            .iter()
+            .map(|object| Ok(manifest::sha256(&object.file)?))
+            .collect::<Result<_, PointsError>>()?,
+        shapes: shapes.collect(),
+    })
+}
+
+/// Loads the strokes saved in `points_file`, refusing them if they were saved for another mesh
+fn load_points(
+    points_file: &Path,
+    scene: &Scene,
+    models: &[SceneModel],
+) -> Result<Vec<Vec<Point>>, PointsError> {
+    let (header, points) = points_file::read_points(points_file)?;
+    let expected = points_header(scene, models.iter().map(|m| m.model.name.clone()))?;
+
+    if header.obj_sha256 != expected.obj_sha256 {
+        return Err(PointsError::Mismatch(
+            "they were generated on other OBJ files".to_string(),
+        ));
+    }
+    if header.shapes != expected.shapes || points.len() > models.len() {
+        return Err(PointsError::Mismatch(format!(
+            "they were generated for the shapes {:?}, the scene has {:?}",
+            header.shapes, expected.shapes
+        )));
+    }
+    if header.stroke_density != expected.stroke_density
+        || header.max_points != expected.max_points
+        || header.seed != expected.seed
+    {
+        warn!(
+            "'{}' was generated with a stroke density of {}, max points {:?} and seed {:?}",
+            points_file.display(),
+            header.stroke_density,
+            header.max_points,
+            header.seed
+        );
+    }
+
+    info!(
+        "Loaded {} points from '{}'",
+        points.iter().map(Vec::len).sum::<usize>(),
+        points_file.display()
+    );
+    Ok(points)
+}
+
+/// Stroke orientations along the edges of the scene's albedo
+fn albedo_flow(scene: &Scene, albedo: &RgbaImage) -> FlowField {
+    let start = Instant::now();
+    let flow = FlowField::from_image(albedo, scene.flow_smoothing.unwrap_or(4));
+    info!("Computed the albedo flow ({:?})", start.elapsed());
+    flow
+}
+
+/// The albedos of the objects and materials of `models` the flat stroke colors are sampled from,
+/// besides the scene's `albedo`
+fn load_flat_colors(
+    scene: &Scene,
+    models: &[SceneModel],
+    albedo: &RgbaImage,
+) -> Result<FlatColors, LoadError> {
+    let objects = scene
+        .objects
+        .iter()
+        .map(|object| {
+            object
+                .albedo_texture
+                .as_ref()
+                .map(|path| open_image(path.clone()).map(DynamicImage::into_rgba8))
+                .transpose()
+        })
+        .collect::<Result<Vec<_>, LoadError>>()?;
+
+    let mut materials = BTreeMap::new();
+    for m in models {
+        let Some(path) = &m.material_albedo else {
+            continue;
+        };
+        if objects[m.object].is_some() || materials.contains_key(path) {
+            continue;
+        }
+        match open_image(path.clone()) {
+            Ok(image) => {
+                materials.insert(path.clone(), image.into_rgba8());
+            }
+            Err(e) => warn!("{e}, using the scene's albedo for {}", m.model.name),
+        }
+    }
+
+    Ok(FlatColors {
+        scene: albedo.clone(),
+        objects,
+        materials,
+    })
+}
+
+/// The stroke maps of `scene`, with `albedo` decoded if the flow or the flat stroke colors need it
+fn load_stroke_maps(
+    scene: &Scene,
+    models: &[SceneModel],
+    albedo: Option<&RgbaImage>,
+) -> Result<StrokeMaps, LoadError> {
+    let open_map = |path: &Option<PathBuf>| {
+        path.as_ref()
+            .map(|path| open_image(path.clone()))
+            .transpose()
+            .map(|image| image.map(DynamicImage::into_luma8))
+    };
+    Ok(StrokeMaps {
+        mask: open_map(&scene.stroke_mask)?,
+        importance: open_map(&scene.importance_map)?.map(|importance| {
+            (
+                importance,
+                scene.importance_strength.unwrap_or(1.0).clamp(0.0, 1.0),
+            )
+        }),
+        flow: albedo
+            .filter(|_| scene.flow_from_albedo.unwrap_or(false))
+            .map(|albedo| albedo_flow(scene, albedo)),
+        albedo: albedo
+            .filter(|_| scene.flat_stroke_color.unwrap_or(false))
+            .map(|albedo| load_flat_colors(scene, models, albedo))
+            .transpose()?,
+        curvature_weight: scene
+            .density_curvature_weight
+            .unwrap_or(0.0)
+            .clamp(0.0, 1.0),
+        brush_names: brushes::brush_names(scene.brush_dir.as_deref())
+            .map_err(|e| LoadError::Io(scene.brush_dir.clone().unwrap_or_default(), e))?,
+    })
+}
+
+/// The point lists of `models`, from the scene's points file if it has one
+fn load_or_gen_points(
+    scene: &Scene,
+    models: &[SceneModel],
+    stroke_maps: &StrokeMaps,
+) -> Result<Vec<Vec<Point>>, LoadError> {
+    if let Some(points_file) = &scene.points_file {
+        return load_points(points_file, scene, models)
+            .map_err(|e| LoadError::Points(points_file.clone(), e));
+    }
+
+    let cache_file = scene.points_cache.as_ref().and_then(|dir| {
+        points_cache_key(scene, stroke_maps)
+            .map(|key| dir.join(format!("{key}.points")))
+            .map_err(|e| warn!("Not caching the strokes: {e}"))
+            .ok()
+    });
+    if let Some(cache_file) = &cache_file {
+        match points_file::read_points(cache_file) {
+            Ok((_, points)) if points.len() == models.len() => {
+                // Keeps the file from being removed as one of the least recently used
+                let touched = fs::File::options()
+                    .write(true)
+                    .open(cache_file)
+                    .and_then(|file| file.set_modified(SystemTime::now()));
+                if let Err(e) = touched {
+                    warn!("Failed to touch '{}': {e}", cache_file.display());
+                }
+                info!(
+                    "Loaded {} cached points from '{}'",
+                    points.iter().map(Vec::len).sum::<usize>(),
+                    cache_file.display()
+                );
+                return Ok(points);
+            }
+            Ok(_) => warn!("Ignoring '{}', it has other shapes", cache_file.display()),
+            Err(PointsError::Io(e)) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => warn!("Ignoring '{}': {e}", cache_file.display()),
+        }
+    }
+
+    let points = gen_scene_points(
+        models,
+        &gen_mesh_samplers(models),
+        scene.stroke_density as f32 * scene.area_scale(),
+        scene.sampling.unwrap_or_default(),
+        stroke_maps,
+        scene.max_points,
+        scene.seed,
+    );
+
+    if let Some(cache_file) = &cache_file {
+        let cache_dir = cache_file.parent().unwrap();
+        let result = fs::create_dir_all(cache_dir)
+            .map_err(PointsError::from)
+            .and_then(|()| {
+                let header = points_header(scene, models.iter().map(|m| m.model.name.clone()))?;
+                points_file::write_points(cache_file, &header, &points)
+            });
+        if let Err(e) = result {
+            warn!(
+                "Failed to cache the points in '{}': {e}",
+                cache_file.display()
+            );
+        }
+        if let Err(e) = prune_points_cache(cache_dir, POINTS_CACHE_FILES) {
+            warn!("Failed to clean up '{}': {e}", cache_dir.display());
+        }
+    }
+    Ok(points)
+}
+
+/// Removes the least recently modified stroke files of the cache in `dir` past the `keep` newest
+fn prune_points_cache(dir: &Path, keep: usize) -> io::Result<()> {
+    let mut files = vec![];
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path
+            .extension()
+            .is_some_and(|extension| extension == "points")
+        {
+            files.push((fs::metadata(&path)?.modified()?, path));
+        }
+    }
+    if files.len() <= keep {
+        return Ok(());
+    }
+    files.sort_by(|(a, _), (b, _)| b.cmp(a));
+    for (_, path) in &files[keep..] {
+        fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+/// Hash of everything the strokes of `scene` are generated from: the settings of the scene that
+/// change them and the contents of its assets, so a cached file never has stale strokes while
+/// tuning how they're drawn keeps the cache.
+fn points_cache_key(scene: &Scene, stroke_maps: &StrokeMaps) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+    hasher.update(points_file::VERSION.to_le_bytes());
+    hasher.update(GENERATOR_VERSION.to_le_bytes());
+    // Through a `Value` so the shapes are hashed in a stable order
+    let settings = serde_json::json!({
+        "objects": scene.objects,
+        "stroke_density": scene.stroke_density,
+        "scale": scene.scale,
+        "max_points": scene.max_points,
+        "seed": scene.seed,
+        "sampling": scene.sampling,
+        "uv_channel": scene.uv_channel,
+        "shapes": scene.shapes,
+        "importance_strength": scene.importance_strength,
+        "density_curvature_weight": scene.density_curvature_weight,
+        "flow_from_albedo": scene.flow_from_albedo,
+        "flow_smoothing": scene.flow_smoothing,
+        "flat_stroke_color": scene.flat_stroke_color,
+    });
+    hasher.update(serde_json::to_vec(&settings)?);
+    for path in scene.asset_paths() {
+        hasher.update(manifest::sha256(&path)?);
+    }
+    // The flat colors are sampled from the materials' albedos too
+    if let Some(colors) = &stroke_maps.albedo {
+        for path in colors.materials.keys() {
+            hasher.update(manifest::sha256(path)?);
+        }
+    }
+    hasher.update(stroke_maps.brush_names.join(","));
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Every stroke of `scene` placed in the scene, loaded without an OpenGL context
+fn scene_points(scene: &Scene) -> Result<Vec<Point>, LoadError> {
+    let albedo = if scene.needs_albedo() {
+        Some(open_image(scene.albedo_texture.clone())?.into_rgba8())
+    } else {
+        None
+    };
+    let models = load_objects(
+        &scene.objects,
+        scene.uv_channel.unwrap_or(0),
+        &scene.shapes,
+        scene.stroke_density,
+    )
+    .map_err(|(model_file, e)| LoadError::Model(model_file, e))?;
+    let stroke_maps = load_stroke_maps(scene, &models, albedo.as_ref())?;
+    let points = load_or_gen_points(scene, &models, &stroke_maps)?;
+    Ok(models
+        .iter()
+        .zip(points)
+        .flat_map(|(m, points)| points.into_iter().map(|p| p.transformed(m.transform)))
+        .collect())
+}
+
+fn load_scene_resources(display: &impl Facade, scene: &Scene) -> Result<SceneResources, LoadError> {
+    let models = load_objects(
+        &scene.objects,
+        scene.uv_channel.unwrap_or(0),
+        &scene.shapes,
+        scene.stroke_density,
+    )
+    .map_err(|(model_file, e)| LoadError::Model(model_file, e))?;
+
+    let albedo_path = scene.albedo_texture.clone();
+    // The flow and flat colors are needed to generate the points, otherwise large textures are
+    // decoded while the rest of the scene loads
+    let (pending_albedo, stroke_maps) = if scene.needs_albedo() {
+        let albedo = open_image(albedo_path.clone())?.into_rgba8();
+        let stroke_maps = load_stroke_maps(scene, &models, Some(&albedo))?;
+        (PendingImage::ready(albedo_path, albedo), stroke_maps)
+    } else {
+        (
+            decode_in_background(albedo_path)?,
+            load_stroke_maps(scene, &models, None)?,
+        )
+    };
+
+    // The flat colors already decoded the object albedos
+    let flat_colors = stroke_maps.albedo.as_ref();
+    let object_albedos = scene
+        .objects
+        .iter()
+        .enumerate()
+        .map(|(i, object)| {
+            object
+                .albedo_texture
+                .as_ref()
+                .map(|path| {
+                    let image = match flat_colors.and_then(|colors| colors.objects[i].clone()) {
+                        Some(image) => image,
+                        None => open_image(path.clone())?.into_rgba8(),
+                    };
+                    image_to_texture(display, image)
+                })
+                .transpose()
+        })
+        .collect::<Result<Vec<_>, LoadError>>()?;
+
+    let canvas_texture = match &scene.canvas_texture {
+        Some(path) => open_image(path.clone())?.into_rgba8(),
+        None => decode_builtin("canvas.png", CANVAS_PNG)?,
+    };
+    let canvas_texture = image_to_texture(display, canvas_texture)?;
+
+    let brush_atlas = brushes::load_atlas(scene.brush_dir.as_deref()).map_err(|e| {
+        LoadError::Brushes(
+            scene
+                .brush_dir
+                .clone()
+                .unwrap_or_else(|| "brushes.png".into()),
+            e,
+        )
+    })?;
+    let brush_rects = brush_rects_texture(display, &brush_atlas)?;
+    let brush_stroke = image_to_texture(display, brush_atlas.image)?;
+
+    let points = load_or_gen_points(scene, &models, &stroke_maps)?;
+    let bounds = scene_bounds(&models);
+
+    // Material textures are only drawn with, so they are decoded while the scene is shown
+    let mut pending_materials: Vec<PendingImage> = vec![];
+    for m in &models {
+        let Some(path) = &m.material_albedo else {
+            continue;
+        };
+        // Objects with their own albedo don't use their materials'
+        if scene.objects[m.object].albedo_texture.is_some()
+            || pending_materials
+                .iter()
+                .any(|pending| &pending.path == path)
+        {
+            continue;
+        }
+        let decoded = flat_colors.and_then(|colors| colors.materials.get(path).cloned());
+        // MTL files often point at textures that weren't exported with them
+        match decoded.map_or_else(
+            || decode_in_background(path.clone()),
+            |image| Ok(PendingImage::ready(path.clone(), image)),
+        ) {
+            Ok(pending) => pending_materials.push(pending),
+            Err(e) => warn!("{e}, using the scene's albedo for {}", m.model.name),
+        }
+    }
+
+    let models = gen_model_data(models, points, display);
+
+    let instance_transforms = instance_transforms(&scene.instances);
+    let num_points = models.iter().map(|m| m.points.len()).sum::<usize>();
+    info!(
+        "{} instances, {} effective points",
+        instance_transforms.len(),
+        num_points * instance_transforms.len()
+    );
+
+    Ok(SceneResources {
+        models,
+        instance_transforms,
+        stroke_maps,
+        albedo_texture: placeholder_texture(display)?,
+        pending_albedo: Some(pending_albedo),
+        object_albedos,
+        pending_materials,
+        bounds,
+        canvas_texture,
+        brush_stroke,
+        brush_rects,
+    })
+}
+
+impl DrawData {
+    /// Reallocates the offscreen targets for a `width` by `height` framebuffer, if they aren't that
+    /// size already
+    fn resize(&mut self, display: &impl Facade, width: u32, height: u32) -> Result<(), LoadError> {
+        // Moving the window or a resize back and forth can report the current size
+        if self.post_process_texture.dimensions() == (width, height) {
+            return Ok(());
+        }
+        self.post_process_texture = SrgbTexture2d::empty(display, width, height)
+            .map_err(|e| LoadError::Gl("post process texture", e.to_string()))?;
+        self.depth_peel_targets = DepthPeelTargets::new(display, width, height)
+            .map_err(|e| LoadError::Gl("depth peel targets", e.to_string()))?;
+        self.oit_targets = OitTargets::new(display, width, height)
+            .map_err(|e| LoadError::Gl("weighted blending targets", e.to_string()))?;
+        self.bloom_targets = BloomTargets::new(display, width, height);
+        self.outline_targets = OutlineTargets::new(display, width, height);
+        Ok(())
+    }
+
+    /// Replaces the scene dependent resources with those of `scene`, keeping the shader programs
+    /// and built-in textures. The old textures and buffers are dropped (and freed) here.
+    ///
+    /// On error nothing is replaced.
+    fn load_scene(&mut self, display: &impl Facade, scene: &Scene) -> Result<(), LoadError> {
+        let SceneResources {
+            models,
+            instance_transforms,
+            stroke_maps,
+            albedo_texture,
+            pending_albedo,
+            object_albedos,
+            pending_materials,
+            bounds,
+            canvas_texture,
+            brush_stroke,
+            brush_rects,
+        } = load_scene_resources(display, scene)?;
+
+        self.models = models;
+        self.instance_buffer = gen_instance_buffer(display, &instance_transforms);
+        self.instance_transforms = instance_transforms;
+        self.stroke_maps = stroke_maps;
+        self.albedo_texture = albedo_texture;
+        self.pending_albedo = pending_albedo;
+        self.object_albedos = object_albedos;
+        self.material_albedos = HashMap::new();
+        self.pending_materials = pending_materials;
+        self.bounds = bounds;
+        self.canvas_texture = canvas_texture;
+        self.brush_stroke = brush_stroke;
+        self.brush_rects = brush_rects;
+        self.params = Params::from_scene(scene);
+        self.background = [scene.background.0, scene.background.1, scene.background.2];
+        self.paint_background = scene.paint_background();
+        Ok(())
+    }
+
+    /// Replaces the placeholder albedo texture and adds the material textures once they are
+    /// decoded. With `wait` this blocks until they are, otherwise it returns right away for those
+    /// still decoding.
+    ///
+    /// On error the placeholder is kept. Strokes of materials whose texture fails to decode keep
+    /// the scene's albedo.
+    fn finish_albedo(&mut self, display: &impl Facade, wait: bool) -> Result<(), LoadError> {
+        let mut result = Ok(());
+        let material_albedos = &mut self.material_albedos;
+        self.pending_materials
+            .retain(|pending| match pending.poll(wait) {
+                None => true,
+                Some(Ok(image)) => {
+                    match image_to_texture(display, image) {
+                        Ok(texture) => {
+                            material_albedos.insert(pending.path.clone(), texture);
+                        }
+                        Err(e) => result = Err(e),
+                    }
+                    false
+                }
+                Some(Err(e)) => {
+                    warn!("{e}, using the scene's albedo for its material");
+                    false
+                }
+            });
+
+        if let Some(image) = self.pending_albedo.as_ref().and_then(|p| p.poll(wait)) {
+            self.pending_albedo = None;
+            self.albedo_texture = image_to_texture(display, image?)?;
+        }
+        result
+    }
+
+    /// Center and radius of a sphere around every instance of the models placed by `model`
+    fn bounding_sphere(&self, model: Matrix4<f32>) -> Option<(Point3<f32>, f32)> {
+        let (min, max) = self.bounds?;
+        let corners = (0..8).map(|i| {
+            point3(
+                if i & 1 == 0 { min.x } else { max.x },
+                if i & 2 == 0 { min.y } else { max.y },
+                if i & 4 == 0 { min.z } else { max.z },
+            )
+        });
+        let points = self
+            .instance_transforms
+            .iter()
+            .flat_map(|&instance| {
+                corners
+                    .clone()
+                    .map(move |corner| (model * instance).transform_point(corner))
+            })
+            .collect::<Vec<_>>();
+        let center = Point3::centroid(&points);
+        let radius = points
+            .iter()
+            .map(|p| p.distance(center))
+            .fold(0.0, f32::max);
+        Some((center, radius))
+    }
+
+    /// Albedo texture of the strokes of `model`: its object's, then its material's, then the
+    /// scene's
+    fn albedo(&self, model: &ModelData) -> &CompressedSrgbTexture2d {
+        self.object_albedos
+            .get(model.object)
+            .and_then(Option::as_ref)
+            .or_else(|| {
+                let path = model.material_albedo.as_ref()?;
+                self.material_albedos.get(path)
+            })
+            .unwrap_or(&self.albedo_texture)
+    }
+}
+
+impl Params {
+    /// Unit direction towards the light, straight up if `light_dir` is zero
+    fn light_dir(&self) -> [f32; 3] {
+        let light_dir = Vector3::from(self.light_dir);
+        if light_dir.magnitude2() > 0.0 {
+            light_dir.normalize().into()
+        } else {
+            Vector3::unit_y().into()
+        }
+    }
+
+    fn from_scene(scene: &Scene) -> Self {
+        let mut params = Self {
+            quantization: scene.quantization,
+            brush_size: scene.brush_size,
+            enable_canvas: true,
+            canvas_strength: scene.canvas_strength.unwrap_or(1.0),
+            canvas_scale: scene.canvas_scale.unwrap_or(1.0),
+            saturation: scene.saturation.unwrap_or(1.0),
+            exposure: scene.exposure.unwrap_or(1.0),
+            tonemap: scene.tonemap.unwrap_or(Tonemap::None),
+            hue_shift: scene.hue_shift.unwrap_or(0.0),
+            lift: {
+                let (r, g, b) = scene.lift.unwrap_or((0.0, 0.0, 0.0));
+                [r, g, b]
+            },
+            gamma: {
+                let (r, g, b) = scene.gamma.unwrap_or((1.0, 1.0, 1.0));
+                [r, g, b]
+            },
+            gain: {
+                let (r, g, b) = scene.gain.unwrap_or((1.0, 1.0, 1.0));
+                [r, g, b]
+            },
+            enable_brush_tbn: true,
+            stroke_style: scene.stroke_style.unwrap_or(StrokeStyle::Brush),
+            point_size: 8.0,
+            brush_jitter: scene.brush_jitter.unwrap_or(0.0),
+            stroke_rotation_jitter: scene.stroke_rotation_jitter.unwrap_or(0.0),
+            brush_size_jitter: scene.brush_size_jitter.unwrap_or(0.0),
+            view_angle_size: scene.view_angle_size.unwrap_or(0.0),
+            view_angle_min_size: scene.view_angle_min_size.unwrap_or(0.25),
+            facing_fade: scene.facing_fade.unwrap_or(0.0),
+            cull_backfaces: scene.cull_backfaces.unwrap_or(true),
+            enable_underpainting: scene.underpainting.unwrap_or(false),
+            underpainting_opacity: scene.underpainting_opacity.unwrap_or(1.0).clamp(0.0, 1.0),
+            depth_peel_layers: scene.depth_peel_layers.unwrap_or(0),
+            weighted_blending: scene.weighted_blending.unwrap_or(false),
+            smudge_radius: scene.smudge.unwrap_or(0),
+            enable_bloom: scene.bloom_intensity.is_some(),
+            bloom_threshold: scene.bloom_threshold.unwrap_or(0.8),
+            bloom_intensity: scene.bloom_intensity.unwrap_or(0.5),
+            enable_outline: scene.outline_thickness.is_some(),
+            outline_thickness: scene.outline_thickness.unwrap_or(1.5),
+            outline_threshold: scene.outline_threshold.unwrap_or(0.5),
+            outline_color: {
+                let (r, g, b) = scene.outline_color.unwrap_or((0.05, 0.04, 0.03));
+                [r, g, b]
+            },
+            enable_vignette: scene.vignette_radius.is_some(),
+            vignette_radius: scene.vignette_radius.unwrap_or(0.75),
+            vignette_softness: scene.vignette_softness.unwrap_or(0.5),
+            blend_mode: scene.blend_mode.unwrap_or(BlendMode::Alpha),
+            sort_override: None,
+            adaptive_density: scene.adaptive_density.is_some(),
+            target_coverage: scene.adaptive_density.unwrap_or(1.0),
+            stroke_length: scene.stroke_length.unwrap_or(1.0),
+            length_jitter: scene.length_jitter.unwrap_or(0.0),
+            enable_haze: scene.haze_color.is_some(),
+            haze_color: scene
+                .haze_color
+                .map_or([0.6, 0.7, 0.8], |(r, g, b)| [r, g, b]),
+            haze_start: scene.haze_start.unwrap_or(scene.camera_distance()),
+            haze_end: scene.haze_end.unwrap_or(3.0 * scene.camera_distance()),
+            enable_fog: scene.fog_color.is_some() || scene.fog_end.is_some(),
+            fog_color: {
+                let (r, g, b) = scene.fog_color.unwrap_or(scene.background);
+                [r, g, b]
+            },
+            fog_start: scene.fog_start.unwrap_or(scene.camera_distance()),
+            fog_end: scene.fog_end.unwrap_or(4.0 * scene.camera_distance()),
+            enable_flow: scene.flow_from_albedo.unwrap_or(false),
+            flat_color: scene.flat_stroke_color.unwrap_or(false),
+            grain_scale: scene.grain_scale.unwrap_or(0.2),
+            grain_strength: scene.grain_strength.unwrap_or(0.0),
+            light_dir: scene.light_dir.unwrap_or([-1.0, 1.0, 1.0]),
+            light_color: scene
+                .light_color
+                .map_or([1.0, 1.0, 1.0], |(r, g, b)| [r, g, b]),
+            ambient: scene.ambient.unwrap_or(0.2),
+            background_gradient: scene.background_gradient.map(|(_, _, style)| style),
+            gradient_colors: scene
+                .background_gradient
+                .map_or([[0.0, 0.0, 0.0]; 2], |((r0, g0, b0), (r1, g1, b1), _)| {
+                    [[r0, g0, b0], [r1, g1, b1]]
+                }),
+            wire_overlay: false,
+            wire_color: [0.0, 0.0, 0.0],
+        };
+        if let Some(animation) = &scene.animation {
+            params.apply_animation(animation, 0.0);
+        }
+        params
+    }
+
+    /// Sets the fields of `scene` to these parameters, leaving those this already matches the
+    /// scene's values for, so the defaults it leaves out stay out
+    fn update_scene(&self, scene: &mut Scene) {
+        fn update<T: PartialEq>(field: &mut Option<T>, value: T, loaded: T) {
+            if value != loaded {
+                *field = Some(value);
+            }
+        }
+        // Scene fields that also enable the effect
+        fn update_enabled<T: PartialEq>(field: &mut Option<T>, enabled: bool, value: T, loaded: T) {
+            if !enabled {
+                *field = None;
+            } else if field.is_none() || value != loaded {
+                *field = Some(value);
+            }
+        }
+        let color = |[r, g, b]: [f32; 3]| (r, g, b);
+
+        let loaded = Params::from_scene(scene);
+        scene.quantization = self.quantization;
+        scene.brush_size = self.brush_size;
+        update(
+            &mut scene.canvas_strength,
+            self.canvas_strength,
+            loaded.canvas_strength,
+        );
+        update(
+            &mut scene.canvas_scale,
+            self.canvas_scale,
+            loaded.canvas_scale,
+        );
+        update(&mut scene.saturation, self.saturation, loaded.saturation);
+        update(&mut scene.exposure, self.exposure, loaded.exposure);
+        update(&mut scene.tonemap, self.tonemap, loaded.tonemap);
+        update(&mut scene.hue_shift, self.hue_shift, loaded.hue_shift);
+        update(&mut scene.lift, color(self.lift), color(loaded.lift));
+        update(&mut scene.gamma, color(self.gamma), color(loaded.gamma));
+        update(&mut scene.gain, color(self.gain), color(loaded.gain));
+        update(
+            &mut scene.stroke_style,
+            self.stroke_style,
+            loaded.stroke_style,
+        );
+        update(
+            &mut scene.brush_jitter,
+            self.brush_jitter,
+            loaded.brush_jitter,
+        );
+        update(
+            &mut scene.stroke_rotation_jitter,
+            self.stroke_rotation_jitter,
+            loaded.stroke_rotation_jitter,
+        );
+        update(
+            &mut scene.brush_size_jitter,
+            self.brush_size_jitter,
+            loaded.brush_size_jitter,
+        );
+        update(
+            &mut scene.view_angle_size,
+            self.view_angle_size,
+            loaded.view_angle_size,
+        );
+        update(
+            &mut scene.view_angle_min_size,
+            self.view_angle_min_size,
+            loaded.view_angle_min_size,
+        );
+        update(&mut scene.facing_fade, self.facing_fade, loaded.facing_fade);
+        update(
+            &mut scene.cull_backfaces,
+            self.cull_backfaces,
+            loaded.cull_backfaces,
+        );
+        update(
+            &mut scene.underpainting,
+            self.enable_underpainting,
+            loaded.enable_underpainting,
+        );
+        update(
+            &mut scene.underpainting_opacity,
+            self.underpainting_opacity,
+            loaded.underpainting_opacity,
+        );
+        update(
+            &mut scene.depth_peel_layers,
+            self.depth_peel_layers,
+            loaded.depth_peel_layers,
+        );
+        update(
+            &mut scene.weighted_blending,
+            self.weighted_blending,
+            loaded.weighted_blending,
+        );
+        update(&mut scene.smudge, self.smudge_radius, loaded.smudge_radius);
+        update_enabled(
+            &mut scene.bloom_intensity,
+            self.enable_bloom,
+            self.bloom_intensity,
+            loaded.bloom_intensity,
+        );
+        update(
+            &mut scene.bloom_threshold,
+            self.bloom_threshold,
+            loaded.bloom_threshold,
+        );
+        update_enabled(
+            &mut scene.outline_thickness,
+            self.enable_outline,
+            self.outline_thickness,
+            loaded.outline_thickness,
+        );
+        update(
+            &mut scene.outline_threshold,
+            self.outline_threshold,
+            loaded.outline_threshold,
+        );
+        update(
+            &mut scene.outline_color,
+            color(self.outline_color),
+            color(loaded.outline_color),
+        );
+        update_enabled(
+            &mut scene.vignette_radius,
+            self.enable_vignette,
+            self.vignette_radius,
+            loaded.vignette_radius,
+        );
+        update(
+            &mut scene.vignette_softness,
+            self.vignette_softness,
+            loaded.vignette_softness,
+        );
+        update(&mut scene.blend_mode, self.blend_mode, loaded.blend_mode);
+        update_enabled(
+            &mut scene.adaptive_density,
+            self.adaptive_density,
+            self.target_coverage,
+            loaded.target_coverage,
+        );
+        update(
+            &mut scene.stroke_length,
+            self.stroke_length,
+            loaded.stroke_length,
+        );
+        update(
+            &mut scene.length_jitter,
+            self.length_jitter,
+            loaded.length_jitter,
+        );
+        update_enabled(
+            &mut scene.haze_color,
+            self.enable_haze,
+            color(self.haze_color),
+            color(loaded.haze_color),
+        );
+        update(&mut scene.haze_start, self.haze_start, loaded.haze_start);
+        update(&mut scene.haze_end, self.haze_end, loaded.haze_end);
+        if !self.enable_fog {
+            scene.fog_color = None;
+            scene.fog_end = None;
+        } else {
+            // Writing the color enables the fog if neither field is set yet
+            if (scene.fog_color.is_none() && scene.fog_end.is_none())
+                || self.fog_color != loaded.fog_color
+            {
+                scene.fog_color = Some(color(self.fog_color));
+            }
+            update(&mut scene.fog_end, self.fog_end, loaded.fog_end);
+        }
+        update(&mut scene.fog_start, self.fog_start, loaded.fog_start);
+        update(
+            &mut scene.flow_from_albedo,
+            self.enable_flow,
+            loaded.enable_flow,
+        );
+        update(
+            &mut scene.flat_stroke_color,
+            self.flat_color,
+            loaded.flat_color,
+        );
+        update(&mut scene.grain_scale, self.grain_scale, loaded.grain_scale);
+        update(
+            &mut scene.grain_strength,
+            self.grain_strength,
+            loaded.grain_strength,
+        );
+        update(&mut scene.light_dir, self.light_dir, loaded.light_dir);
+        update(
+            &mut scene.light_color,
+            color(self.light_color),
+            color(loaded.light_color),
+        );
+        update(&mut scene.ambient, self.ambient, loaded.ambient);
+        scene.background_gradient = self.background_gradient.map(|style| {
+            let [start, end] = self.gradient_colors.map(color);
+            (start, end, style)
+        });
+    }
+
+    /// Sets the animated parameters to their values at `time`
+    fn apply_animation(&mut self, animation: &Animation, time: f32) {
+        if let Some(quantization) = animation
+            .quantization
+            .as_ref()
+            .and_then(|curve| curve.evaluate(time))
+        {
+            self.quantization = quantization.round() as i32;
+        }
+        if let Some(brush_size) = animation
+            .brush_size
+            .as_ref()
+            .and_then(|curve| curve.evaluate(time))
+        {
+            self.brush_size = brush_size;
+        }
+    }
+
+    /// Whether the strokes are drawn without needing to be sorted
+    fn order_independent(&self) -> bool {
+        self.depth_peel_layers > 0 || self.weighted_blending
+    }
+
+    /// The stroke order in effect, the override if set or else the blend mode's
+    fn sort_order(&self) -> SortOrder {
+        self.sort_override
+            .unwrap_or_else(|| self.blend_mode.sort_order())
+    }
+}
+
+/// Builds everything drawn for `scene`, with offscreen targets for a `width` by `height`
+/// framebuffer
+fn init_draw_data(
+    display: &impl Facade,
+    scene: &Scene,
+    width: u32,
+    height: u32,
+) -> Result<DrawData, LoadError> {
+    let color_program =
+        Program::from_source(display, shaders::COLOR_VERT, shaders::COLOR_FRAG, None)
+            .map_err(|e| LoadError::Shader("color", e))?;
+
+    let point_program = Program::new(
+        display,
+        ProgramCreationInput::SourceCode {
+            vertex_shader: shaders::POINT_VERT,
+            fragment_shader: shaders::POINT_FRAG,
+            geometry_shader: Some(shaders::POINT_GEOM),
+            tessellation_control_shader: None,
+            tessellation_evaluation_shader: None,
+            transform_feedback_varyings: None,
+            outputs_srgb: false,
+            uses_point_size: true,
+        },
+    )
+    .map_err(|e| LoadError::Shader("point", e))?;
+
+    let point_dot_program = Program::new(
+        display,
+        ProgramCreationInput::SourceCode {
+            vertex_shader: shaders::POINT_VERT,
+            fragment_shader: shaders::POINT_DOT_FRAG,
+            geometry_shader: None,
+            tessellation_control_shader: None,
+            tessellation_evaluation_shader: None,
+            transform_feedback_varyings: None,
+            outputs_srgb: false,
+            uses_point_size: true,
+        },
+    )
+    .map_err(|e| LoadError::Shader("point dot", e))?;
+
+    let solid_program =
+        Program::from_source(display, shaders::COLOR_VERT, shaders::SOLID_FRAG, None)
+            .map_err(|e| LoadError::Shader("solid", e))?;
+
+    let normal_program =
+        Program::from_source(display, shaders::COLOR_VERT, shaders::NORMAL_FRAG, None)
+            .map_err(|e| LoadError::Shader("normal", e))?;
+
+    let peel_composite_program = Program::from_source(
+        display,
+        shaders::POST_VERT,
+        shaders::PEEL_COMPOSITE_FRAG,
+        None,
+    )
+    .map_err(|e| LoadError::Shader("peel composite", e))?;
+
+    let oit_resolve_program =
+        Program::from_source(display, shaders::POST_VERT, shaders::OIT_RESOLVE_FRAG, None)
+            .map_err(|e| LoadError::Shader("transparency resolve", e))?;
+
+    let post_process_program =
+        Program::from_source(display, shaders::POST_VERT, shaders::POST_FRAG, None)
+            .map_err(|e| LoadError::Shader("post process", e))?;
+
+    let SceneResources {
+        models,
+        instance_transforms,
+        stroke_maps,
+        albedo_texture,
+        pending_albedo,
+        object_albedos,
+        pending_materials,
+        bounds,
+        canvas_texture,
+        brush_stroke,
+        brush_rects,
+    } = load_scene_resources(display, scene)?;
+
+    let instance_buffer = gen_instance_buffer(display, &instance_transforms);
+
+    let post_process_texture = SrgbTexture2d::empty(display, width, height)
+        .map_err(|e| LoadError::Gl("post process texture", e.to_string()))?;
+
+    let depth_peel_targets = DepthPeelTargets::new(display, width, height)
+        .map_err(|e| LoadError::Gl("depth peel targets", e.to_string()))?;
+
+    let oit_targets = OitTargets::new(display, width, height)
+        .map_err(|e| LoadError::Gl("weighted blending targets", e.to_string()))?;
+
+    let bloom_threshold_program = Program::from_source(
+        display,
+        shaders::POST_VERT,
+        shaders::BLOOM_THRESHOLD_FRAG,
+        None,
+    )
+    .map_err(|e| LoadError::Shader("bloom threshold", e))?;
+    let bloom_blur_program =
+        Program::from_source(display, shaders::POST_VERT, shaders::BLOOM_BLUR_FRAG, None)
+            .map_err(|e| LoadError::Shader("bloom blur", e))?;
+    let bloom_targets = BloomTargets::new(display, width, height);
+
+    let outline_geometry_program = Program::from_source(
+        display,
+        shaders::OUTLINE_GEOMETRY_VERT,
+        shaders::OUTLINE_GEOMETRY_FRAG,
+        None,
+    )
+    .map_err(|e| LoadError::Shader("outline geometry", e))?;
+    let outline_targets = OutlineTargets::new(display, width, height);
+
+    let params = Params::from_scene(scene);
+
+    let post_quad_vert = vec![
+        PostProcessVert {
+            // BL
+            position: [-1.0, -1.0],
+        },
+        PostProcessVert {
+            // BR
+            position: [1.0, -1.0],
+        },
+        PostProcessVert {
+            // TR
+            position: [1.0, 1.0],
+        },
+        PostProcessVert {
+            // TL
+            position: [-1.0, 1.0],
+        },
+    ];
+
+    // TL ---- TR
+    // |  ^     ^
+    // v   \    |
+    // BL >--- BR
+
+    let post_quad_indices = vec![0u8, 1, 3, 1, 2, 3];
+
+    let post_quad_vertex_buffer = VertexBuffer::new(display, &post_quad_vert)
+        .map_err(|e| LoadError::Gl("post process quad", e.to_string()))?;
+    let post_quad_index_buffer =
+        IndexBuffer::new(display, PrimitiveType::TrianglesList, &post_quad_indices)
+            .map_err(|e| LoadError::Gl("post process quad", e.to_string()))?;
+
+    Ok(DrawData {
+        color_program,
+        point_program,
+        point_dot_program,
+        solid_program,
+        normal_program,
+        peel_composite_program,
+        oit_resolve_program,
+        depth_peel_targets,
+        oit_targets,
+        bloom_threshold_program,
+        bloom_blur_program,
+        bloom_targets,
+        outline_geometry_program,
+        outline_targets,
+        brush_stroke,
+        brush_rects,
+        albedo_texture,
+        pending_albedo,
+        object_albedos,
+        material_albedos: HashMap::new(),
+        pending_materials,
+        bounds,
+        canvas_texture,
+        models,
+        instance_transforms,
+        instance_buffer,
+        stroke_maps,
+        post_process_quad: (post_quad_vertex_buffer, post_quad_index_buffer),
+        post_process_texture,
+        post_process_program,
+        params,
+        background: [scene.background.0, scene.background.1, scene.background.2],
+        paint_background: scene.paint_background(),
+    })
+}
+
+/// Points per bucket of `sort_by_key`, few enough that sorting within a bucket is cheap
+const POINTS_PER_BUCKET: usize = 16;
+
+/// Sorts `points` by increasing `key`. The keys and indices of the points are distributed into
+/// buckets over the range of keys first, so only points in the same bucket are compared and the
+/// points themselves are only moved once. NaN keys are totally ordered and end up in the first
+/// bucket.
+fn sort_by_key(points: &mut [Point], key: impl Fn(&Point) -> f32 + Sync) {
+    if points.is_empty() {
+        return;
+    }
+
+    let keys: Vec<f32> = points.par_iter().map(&key).collect();
+    let (min, max) = keys
+        .iter()
+        .filter(|k| k.is_finite())
+        .fold((f32::INFINITY, f32::NEG_INFINITY), |(min, max), &k| {
+            (min.min(k), max.max(k))
+        });
+    let buckets = (points.len() / POINTS_PER_BUCKET).max(1);
+    let scale = if max > min {
+        buckets as f32 / (max - min)
+    } else {
+        0.0
+    };
+    // The float to int cast saturates, and is 0 for NaN
+    let bucket = |k: f32| (((k - min) * scale) as usize).min(buckets - 1);
+
+    let mut starts = vec![0; buckets + 1];
+    for &k in &keys {
+        starts[bucket(k) + 1] += 1;
+    }
+    for i in 1..=buckets {
+        starts[i] += starts[i - 1];
+    }
+
+    let mut next = starts.clone();
+    let mut bucketed = vec![(0.0, 0); points.len()];
+    for (i, &k) in keys.iter().enumerate() {
+        let b = bucket(k);
+        bucketed[next[b]] = (k, i as u32);
+        next[b] += 1;
+    }
+
+    let mut rest = bucketed.as_mut_slice();
+    let mut bucket_slices = Vec::with_capacity(buckets);
+    for range in starts.windows(2) {
+        let (bucket, tail) = rest.split_at_mut(range[1] - range[0]);
+        bucket_slices.push(bucket);
+        rest = tail;
+    }
+    bucket_slices
+        .into_par_iter()
+        .for_each(|bucket| bucket.sort_unstable_by(|a, b| a.0.total_cmp(&b.0)));
+
+    let sorted = bucketed
+        .par_iter()
+        .map(|&(_, i)| points[i as usize])
+        .collect::<Vec<_>>();
+    points.copy_from_slice(&sorted);
+}
+
+/// Sorts the points of every model by depth and returns the instance transforms in draw order.
+/// `transforms` are the object transforms of the models.
+///
+/// Points and instances are ordered by `sort_order`. Instances share one point order,
+/// so they are only sorted coarsely by their origin and drawn one after another.
+fn sort_points(
+    points_m: &mut [Vec<Point>],
+    transforms: &[Matrix4<f32>],
+    instances: &[Matrix4<f32>],
+    model: Matrix4<f32>,
+    view: Matrix4<f32>,
+    sort_order: SortOrder,
+) -> Vec<Matrix4<f32>> {
+    // The view space z grows towards the camera and orders points the same as their projected
+    // depth in both projections, without a division that breaks behind the camera
+    let sign = if sort_order == SortOrder::NearToFar {
+        -1.0
+    } else {
+        1.0
+    };
+
+    for (points, &transform) in points_m.iter_mut().zip(transforms) {
+        let row_z = (view * model * transform).row(2);
+        sort_by_key(points, |p| sign * row_z.dot(p.position().to_homogeneous()));
+    }
+
+    let mut sorted_instances = instances.to_vec();
+    let row_z = (view * model).row(2);
+    sorted_instances.sort_by(|a, b| {
+        let depth = |instance: &Matrix4<f32>| sign * row_z.dot(instance.w);
+        depth(a).total_cmp(&depth(b))
+    });
+    sorted_instances
+}
+
+/// Model, view and perspective matrices and order the points are sorted for
+type SortInputs = (Matrix4<f32>, Matrix4<f32>, Matrix4<f32>, SortOrder);
+
+/// Whether the camera moved relative to the model since the points were sorted for `last` by
+/// more than `angle` or `distance`, a fraction of its distance to the model. Any change of the
+/// projection or order needs a new sort.
+fn needs_resort(last: SortInputs, current: SortInputs, angle: Deg<f32>, distance: f32) -> bool {
+    let (last_model, last_view, last_perspective, last_order) = last;
+    let (model, view, perspective, order) = current;
+    if last_perspective != perspective || last_order != order {
+        return true;
+    }
+
+    // Camera position and direction in model space
+    let pose = |model: Matrix4<f32>, view: Matrix4<f32>| {
+        (view * model).invert().map(|to_model| {
+            (
+                (to_model * vec4(0.0, 0.0, 0.0, 1.0)).truncate(),
+                (to_model * vec4(0.0, 0.0, -1.0, 0.0)).truncate(),
+            )
+        })
+    };
+    match (pose(last_model, last_view), pose(model, view)) {
+        (Some((last_eye, last_direction)), Some((eye, direction))) => {
+            Deg::from(last_direction.angle(direction)) > angle
+                || last_eye.distance(eye) > distance * last_eye.magnitude()
+        }
+        _ => true,
+    }
+}
+
+/// Runs the point thread, which regenerates the points when the scene or density changes and
+/// sends them to be drawn, and the camera thread.
+///
+/// By default the points are sorted for the latest camera whenever it moves past the sort
+/// thresholds. Depth peeling and weighted blending, which scenes opt into, draw the strokes in any
+/// order, so the points are then only sent when they change.
+fn fixed_update(
+    state: Arc<State>,
+    mut points_m: Vec<Vec<Point>>,
+    mut models: Vec<SceneModel>,
+    mut stroke_maps: StrokeMaps,
+    mut instances: Vec<Matrix4<f32>>,
+    points_sender: Sender<SortedPoints>,
+    point_update_rx: Receiver<PointUpdate>,
+) {
+    let latest = Arc::new(Mutex::new(None::<SortInputs>));
 
     {
         let latest = latest.clone();
         let state = state.clone();
+        let mut last_sort_start = None;
+        // What the points were last sorted for, a small camera movement from it isn't worth
+        // sorting again
+        let mut last_sorted = None;
+        // Built on the first regeneration of each scene, so later density changes only
+        // re-sample the points
+        let mut samplers = None;
+        // Density of `points_m` if they are unpruned random points, which can be made denser or
+        // sparser without generating them again. Seeded points are always generated from
+        // scratch so the same settings give the same strokes.
+        let incremental = |state: &State| {
+            *state.sampling.lock().unwrap() == Sampling::Random
+                && state.max_points.lock().unwrap().is_none()
+                && state.seed.lock().unwrap().is_none()
+        };
+        let mut points_density = incremental(&state).then(|| {
+            state.stroke_density.load(Ordering::Relaxed) as f32 * *state.area_scale.lock().unwrap()
+        });
+        // With adaptive density `points_m` keeps every stroke and the ones drawn at the current
+        // levels of the objects are sorted instead
+        let mut lod_levels = None::<Vec<u32>>;
+        let mut lod_points_m = vec![];
+        let mut lod_centers = points_m
+            .iter()
+            .map(|points| lod::center(points))
+            .collect::<Vec<_>>();
+        // Sorting on the global pool is slower to tune but still correct
+        let sort_pool = ThreadPoolBuilder::new()
+            .num_threads(state.sort_threads)
+            .thread_name(|i| format!("sort-{i}"))
+            .build()
+            .map_err(|e| error!("Failed to create the sort thread pool, using the global one: {e}"))
+            .ok();
         thread::spawn(move || loop {
             let mut regen_points = false;
-            while let Ok(()) = point_update_rx.try_recv() {
+            while let Ok(update) = point_update_rx.try_recv() {
+                if let PointUpdate::Scene(new_models, new_stroke_maps, new_instances) = update {
+                    samplers = None;
+                    points_density = None;
+                    models = new_models;
+                    stroke_maps = *new_stroke_maps;
+                    instances = new_instances;
+                }
                 regen_points = true;
             }
 
             if regen_points {
-                let stroke_density = state.stroke_density.load(Ordering::Relaxed);
-                let mut points = vec![];
-                for model in &models {
-                    points.extend(gen_point_list(&model, stroke_density as f32));
+                let stroke_density = state.stroke_density.load(Ordering::Relaxed) as f32
+                    * *state.area_scale.lock().unwrap();
+                let max_points = *state.max_points.lock().unwrap();
+                let seed = *state.seed.lock().unwrap();
+                let sampling = *state.sampling.lock().unwrap();
+                let samplers = samplers.get_or_insert_with(|| gen_mesh_samplers(&models));
+                let incremental = incremental(&state);
+                match points_density {
+                    // Keeps the existing strokes so they don't pop. Other settings changed if the
+                    // density didn't.
+                    Some(from_density) if incremental && from_density != stroke_density => {
+                        change_scene_density(
+                            &mut points_m,
+                            &models,
+                            samplers,
+                            from_density,
+                            stroke_density,
+                            &stroke_maps,
+                        );
+                    }
+                    _ => {
+                        points_m = gen_scene_points(
+                            &models,
+                            samplers,
+                            stroke_density,
+                            sampling,
+                            &stroke_maps,
+                            max_points,
+                            seed,
+                        );
+                    }
                 }
-                points_m = vec![points];
+                points_density = incremental.then_some(stroke_density);
+                lod_centers = points_m.iter().map(|points| lod::center(points)).collect();
             }
 
-            let latest = { *latest.lock().unwrap() };
-            let elapsed = if let Some((model, view, perspective, reverse_sort)) = latest {
-                let start = Instant::now();
-                #[derive(PartialOrd, PartialEq)]
-                #[repr(transparent)]
-                struct Ord<T>(T);
-
-                impl std::cmp::Eq for Ord<f32> {}
-
-                impl std::cmp::Ord for Ord<f32> {
-                    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-                        self.partial_cmp(other).unwrap().reverse()
+            let mut lod_changed = false;
+            let adaptive_density = *state.adaptive_density.lock().unwrap();
+            if let Some(target_coverage) = adaptive_density {
+                let eye = { *latest.lock().unwrap() }.and_then(|(model, view, _, _)| {
+                    view.invert()
+                        .map(|to_world| (model, to_world.transform_point(Point3::origin())))
+                });
+                if let Some((model, eye)) = eye {
+                    let levels = lod::object_levels(
+                        &lod_centers,
+                        &models.iter().map(|m| m.transform).collect::<Vec<_>>(),
+                        &instances,
+                        model,
+                        eye,
+                        *state.lod_reference_distance.lock().unwrap(),
+                        target_coverage,
+                        lod_levels.as_deref(),
+                    );
+                    if regen_points || lod_levels.as_ref() != Some(&levels) {
+                        lod_points_m = points_m
+                            .iter()
+                            .zip(&levels)
+                            .map(|(points, &level)| lod::lod_points(points, level))
+                            .collect();
+                        info!(
+                            "LOD levels {levels:?}, {} of {} points",
+                            lod_points_m.iter().map(Vec::len).sum::<usize>(),
+                            points_m.iter().map(Vec::len).sum::<usize>()
+                        );
+                        lod_levels = Some(levels);
+                        lod_changed = true;
                     }
                 }
+            } else if lod_levels.is_some() {
+                lod_levels = None;
+                lod_points_m = vec![];
+                lod_changed = true;
+            }
+            let drawn_points = if lod_levels.is_some() {
+                &mut lod_points_m
+            } else {
+                &mut points_m
+            };
 
-                for points in &mut points_m {
-                    if reverse_sort {
-                        points.par_sort_by_cached_key(|p| {
-                            let p: Vector4<f32> = perspective
-                                * view
-                                * model
-                                * vec4(p.position[0], p.position[1], p.position[2], 1.0);
-                            Reverse(Ord(p.z / p.w))
-                        });
-                    } else {
-                        points.par_sort_by_cached_key(|p| {
-                            let p: Vector4<f32> = perspective
-                                * view
-                                * model
-                                * vec4(p.position[0], p.position[1], p.position[2], 1.0);
-                            Ord(p.z / p.w)
-                        });
-                    }
+            let sorting = state.sorting.load(Ordering::Relaxed);
+            if !sorting {
+                last_sorted = None;
+            }
+            let latest = { *latest.lock().unwrap() }.filter(|&latest| {
+                // New points are always sorted
+                sorting
+                    && (regen_points
+                        || lod_changed
+                        || last_sorted.is_none_or(|last_sorted| {
+                            needs_resort(
+                                last_sorted,
+                                latest,
+                                Deg(*state.sort_angle_threshold.lock().unwrap()),
+                                *state.sort_distance_threshold.lock().unwrap(),
+                            )
+                        }))
+            });
+            let elapsed = if let Some((model, view, _, sort_order)) = latest {
+                let start = Instant::now();
+                if let Some(last_sort_start) = last_sort_start {
+                    state.debug_info.sort_period.store(
+                        start.duration_since(last_sort_start).as_micros() as u64,
+                        Ordering::Relaxed,
+                    );
                 }
+                last_sort_start = Some(start);
+                last_sorted = latest;
+                let mut sort = || {
+                    sort_points(
+                        drawn_points,
+                        &models.iter().map(|m| m.transform).collect::<Vec<_>>(),
+                        &instances,
+                        model,
+                        view,
+                        sort_order,
+                    )
+                };
+                let sorted_instances = match &sort_pool {
+                    Some(sort_pool) => sort_pool.install(sort),
+                    None => sort(),
+                };
 
-                points_sender.send(points_m.clone()).unwrap();
+                points_sender
+                    .send((drawn_points.clone(), sorted_instances))
+                    .unwrap();
                 let elapsed = start.elapsed();
                 state
                     .debug_info
                     .sort_time
                     .store(elapsed.as_micros() as u64, Ordering::Relaxed);
+                state.debug_info.sorts.fetch_add(1, Ordering::Relaxed);
                 elapsed
             } else {
+                // Order independent transparency draws the strokes in any order
+                if regen_points || lod_changed {
+                    points_sender
+                        .send((drawn_points.clone(), instances.clone()))
+                        .unwrap();
+                }
                 Duration::ZERO
             };
-            // FIXME: this is awful
-            thread::sleep(Duration::from_millis(17).saturating_sub(elapsed));
+            // Polls at `sort_hz` rather than waking on camera changes, which also bounds how often
+            // the points are sorted while the camera keeps moving
+            let sort_hz = state.sort_hz.load(Ordering::Relaxed).max(1);
+            thread::sleep(Duration::from_secs_f64(1.0 / sort_hz as f64).saturating_sub(elapsed));
         });
     }
 
     thread::spawn(move || {
-        let mut reverse_sort = true;
         let mut changed = true;
+        let mut last_step = Instant::now();
+        // -1 to 1, eased towards the held arrow key
+        let mut zoom_velocity = 0.0f32;
         loop {
             let start = Instant::now();
+            // Scales movement so its speed doesn't depend on how long the steps take
+            let step_time = start.duration_since(last_step).as_secs_f32();
+            last_step = start;
             {
                 let wheel_delta = state.wheel_delta.lock().unwrap();
+                let drag_delta = state.drag_delta.lock().unwrap().take();
                 let keys = state.keys.lock().unwrap();
                 let mut model = state.model.lock().unwrap();
                 let mut camera = state.camera.lock().unwrap();
@@ -614,14 +4104,57 @@ fn fixed_update(
                     // Disable update on mouse wheel because it's too slow
                     changed = true;
                 }
+                if state.turntable.load(Ordering::Relaxed) {
+                    let speed = *state.turntable_speed.lock().unwrap();
+                    *model = Matrix4::from_angle_y(Deg(speed * step_time)) * *model;
+                    // The sort thread picks up the new model on its next sort
+                    changed = true;
+                }
+                if let Some((dx, dy)) = drag_delta {
+                    camera.rotate_around(Deg(-0.3 * dx));
+                    camera.rotate_up(Deg(-0.3 * dy));
+                    changed = true;
+                }
+                let mut zoom_input = 0.0;
                 if keys.contains(&VirtualKeyCode::Up) {
-                    camera.zoom(0.01);
+                    zoom_input += 1.0;
                 }
                 if keys.contains(&VirtualKeyCode::Down) {
-                    camera.zoom(-0.01);
+                    zoom_input -= 1.0;
                 }
-                if keys.contains(&VirtualKeyCode::R) {
-                    reverse_sort = !reverse_sort;
+                zoom_velocity = if state.smooth_zoom.load(Ordering::Relaxed) {
+                    let ease = 1.0 - (-step_time / ZOOM_EASING).exp();
+                    zoom_velocity + (zoom_input - zoom_velocity) * ease
+                } else {
+                    zoom_input
+                };
+                if zoom_velocity.abs() < 1.0e-3 {
+                    zoom_velocity = 0.0;
+                } else {
+                    // Proportional to the distance, so the zoom slows down close to the target
+                    let amount = zoom_velocity * ZOOM_SPEED * camera.zoom_scale() * step_time;
+                    camera.zoom(amount);
+                    changed = true;
+                }
+                let forward = camera.direction().normalize();
+                let mut movement = Vector3::zero();
+                for (key, direction) in [
+                    (VirtualKeyCode::W, forward),
+                    (VirtualKeyCode::S, -forward),
+                    (VirtualKeyCode::D, camera.right()),
+                    (VirtualKeyCode::A, -camera.right()),
+                    (VirtualKeyCode::E, Vector3::unit_y()),
+                    (VirtualKeyCode::Q, -Vector3::unit_y()),
+                ] {
+                    if keys.contains(&key) {
+                        movement += direction;
+                    }
+                }
+                if movement != Vector3::zero() {
+                    camera.translate(movement.normalize() * MOVE_SPEED * step_time);
+                    changed = true;
+                }
+                if state.model_changed.swap(false, Ordering::Relaxed) {
                     changed = true;
                 }
                 if changed {
@@ -629,9 +4162,10 @@ fn fixed_update(
                     let model = *model;
                     let view = Matrix4::from(camera.view());
                     let perspective = Matrix4::from(camera.perspective());
+                    let sort_order = *state.sort_order.lock().unwrap();
                     {
                         if let Ok(mut lock) = latest.try_lock() {
-                            *lock = Some((model, view, perspective, reverse_sort));
+                            *lock = Some((model, view, perspective, sort_order));
                         }
                     }
                 }
@@ -646,35 +4180,382 @@ fn fixed_update(
     });
 }
 
-fn draw_model(target: &mut impl Surface, state: &State, data: &DrawData, model: [[f32; 4]; 4]) {
-    let camera_uniforms = {
+/// Draws the models shaded by `program`, a fragment shader for `COLOR_VERT`
+fn draw_model(
+    target: &mut impl Surface,
+    state: &State,
+    data: &DrawData,
+    model: [[f32; 4]; 4],
+    program: &Program,
+) {
+    let camera_uniforms = {
+        let camera = state.camera.lock().unwrap();
+        uniform! {
+            view: camera.view(),
+            perspective: camera.perspective(),
+            model: model,
+        }
+    };
+
+    target.clear_color_and_depth(
+        (
+            data.background[0],
+            data.background[1],
+            data.background[2],
+            1.0,
+        ),
+        1.0,
+    );
+
+    for model in &data.models {
+        let object: [[f32; 4]; 4] = model.transform.into();
+        let (vb, ib) = &model.model_buffers;
+        target
+            .draw(
+                (vb, data.instance_buffer.per_instance().unwrap()),
+                ib,
+                program,
+                &camera_uniforms
+                    .add("object", object)
+                    .add("albedo_texture", data.albedo(model)),
+                &DrawParameters {
+                    depth: Depth {
+                        test: DepthTest::IfLess,
+                        write: true,
+                        ..Default::default()
+                    },
+                    backface_culling: BackfaceCullingMode::CullClockwise,
+                    // Pushes the fill back so the wireframe overlay doesn't z-fight with it
+                    polygon_offset: PolygonOffset {
+                        factor: 1.0,
+                        units: 1.0,
+                        fill: data.params.wire_overlay,
+                        ..Default::default()
+                    },
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+    }
+
+    if data.params.wire_overlay {
+        draw_wire_overlay(target, state, data, model, true);
+    }
+}
+
+/// Draws the mesh edges in `wire_color`. With `overlay` only the front facing edges not hidden by
+/// an already drawn, depth tested model are drawn, otherwise all of them.
+fn draw_wire_overlay(
+    target: &mut impl Surface,
+    state: &State,
+    data: &DrawData,
+    model: [[f32; 4]; 4],
+    overlay: bool,
+) {
+    let uniforms = {
+        let camera = state.camera.lock().unwrap();
+        uniform! {
+            view: camera.view(),
+            perspective: camera.perspective(),
+            model: model,
+            solid_color: data.params.wire_color,
+        }
+    };
+
+    for model in &data.models {
+        let object: [[f32; 4]; 4] = model.transform.into();
+        let (vb, ib) = &model.model_buffers;
+        target
+            .draw(
+                (vb, data.instance_buffer.per_instance().unwrap()),
+                ib,
+                &data.solid_program,
+                &uniforms.add("object", object),
+                &DrawParameters {
+                    depth: Depth {
+                        test: if overlay {
+                            DepthTest::IfLessOrEqual
+                        } else {
+                            DepthTest::Overwrite
+                        },
+                        write: false,
+                        ..Default::default()
+                    },
+                    backface_culling: if overlay {
+                        BackfaceCullingMode::CullClockwise
+                    } else {
+                        BackfaceCullingMode::CullingDisabled
+                    },
+                    polygon_mode: PolygonMode::Line,
+                    line_width: Some(1.0),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+    }
+}
+
+/// How `draw_points` draws the strokes
+#[derive(Copy, Clone)]
+enum PointPass<'a> {
+    /// Alpha blended in the order the sort thread left them
+    Sorted,
+    /// One depth peeled layer behind the given depth
+    Peel(&'a DepthTexture2d),
+    /// One pass of weighted blended transparency
+    Weighted(OitPass),
+}
+
+/// Draws the strokes with alpha blending, as a single depth peel layer or as one weighted
+/// blended transparency pass, depending on `pass`
+fn draw_points(
+    target: &mut impl Surface,
+    state: &State,
+    data: &DrawData,
+    model: [[f32; 4]; 4],
+    pass: PointPass,
+) {
+    let camera_uniforms = {
+        let camera = state.camera.lock().unwrap();
+        uniform! {
+            view: camera.view(),
+            perspective: camera.perspective(),
+            model: model,
+            brush_stroke: &data.brush_stroke,
+            brush_rects: &data.brush_rects,
+            camera_pos: <Point3<_> as Into<[f32; 3]>>::into(camera.position()),
+            quantization: data.params.quantization,
+            brush_size: data.params.brush_size * camera.stroke_scale(),
+            enable_brush_tbn: data.params.enable_brush_tbn,
+            point_size: data.params.point_size,
+            brush_jitter: data.params.brush_jitter,
+            stroke_rotation_jitter: data.params.stroke_rotation_jitter,
+            brush_size_jitter: data.params.brush_size_jitter,
+            view_angle_size: data.params.view_angle_size,
+            view_angle_min_size: data.params.view_angle_min_size,
+            facing_fade: data.params.facing_fade,
+            cull_backfaces: data.params.cull_backfaces,
+            stroke_length: data.params.stroke_length,
+            length_jitter: data.params.length_jitter,
+            // Depth peeled and weighted blended strokes are always composited with alpha blending
+            multiply: data.params.blend_mode == BlendMode::Multiply
+                && matches!(pass, PointPass::Sorted),
+            enable_haze: data.params.enable_haze,
+            haze_color: data.params.haze_color,
+            haze_start: data.params.haze_start,
+            haze_end: data.params.haze_end,
+            enable_fog: data.params.enable_fog,
+            fog_color: data.params.fog_color,
+            fog_start: data.params.fog_start,
+            fog_end: data.params.fog_end,
+            grain_scale: data.params.grain_scale,
+            grain_strength: data.params.grain_strength,
+            enable_flow: data.params.enable_flow,
+            flat_color: data.params.flat_color,
+            light_dir: data.params.light_dir(),
+            light_color: data.params.light_color,
+            ambient: data.params.ambient,
+        }
+    };
+
+    let color_blend = match data.params.blend_mode {
+        BlendMode::Alpha => Blend::alpha_blending().color,
+        // The shader outputs the stroke color mixed towards white by its alpha
+        BlendMode::Multiply => BlendingFunction::Addition {
+            source: LinearBlendingFactor::Zero,
+            destination: LinearBlendingFactor::SourceColor,
+        },
+    };
+
+    let program = match data.params.stroke_style {
+        StrokeStyle::Brush => &data.point_program,
+        StrokeStyle::Point => &data.point_dot_program,
+    };
+
+    for model in &data.models {
+        let object: [[f32; 4]; 4] = model.transform.into();
+        let (vb, ib) = &model.point_buffers;
+        match pass {
+            PointPass::Peel(peel_depth) => target.draw(
+                (vb, data.instance_buffer.per_instance().unwrap()),
+                ib,
+                program,
+                &camera_uniforms
+                    .add("object", object)
+                    .add("albedo_texture", data.albedo(model))
+                    .add("depth_peel", true)
+                    .add("peel_depth", peel_depth),
+                &DrawParameters {
+                    depth: Depth {
+                        test: DepthTest::IfLess,
+                        write: true,
+                        ..Default::default()
+                    },
+                    ..Default::default()
+                },
+            ),
+            PointPass::Weighted(oit_pass) => target.draw(
+                (vb, data.instance_buffer.per_instance().unwrap()),
+                ib,
+                program,
+                &camera_uniforms
+                    .add("object", object)
+                    .add("albedo_texture", data.albedo(model))
+                    .add("oit_pass", oit_pass as i32),
+                &DrawParameters {
+                    blend: oit_pass.blend(),
+                    ..Default::default()
+                },
+            ),
+            PointPass::Sorted => target.draw(
+                (vb, data.instance_buffer.per_instance().unwrap()),
+                ib,
+                program,
+                &camera_uniforms
+                    .add("object", object)
+                    .add("albedo_texture", data.albedo(model)),
+                &DrawParameters {
+                    blend: Blend {
+                        color: color_blend,
+                        alpha: BlendingFunction::Addition {
+                            source: LinearBlendingFactor::One,
+                            destination: LinearBlendingFactor::OneMinusSourceAlpha,
+                        },
+                        ..Blend::alpha_blending()
+                    },
+                    ..Default::default()
+                },
+            ),
+        }
+        .unwrap();
+    }
+}
+
+fn draw_paint_layer<'t>(
+    display: &impl Facade,
+    state: &State,
+    data: &DrawData,
+    points_texture: impl ToColorAttachment<'t>,
+    model: [[f32; 4]; 4],
+) -> Result<(), LoadError> {
+    if data.params.enable_outline {
+        draw_outline_geometry(display, &data.outline_targets, |target| {
+            draw_mesh_geometry(target, state, data, model)
+        });
+    }
+
+    let mut target = SimpleFrameBuffer::new(display, points_texture)
+        .map_err(|e| LoadError::Gl("paint framebuffer", format!("{e:?}")))?;
+    let dimensions = target.get_dimensions();
+
+    // Alpha accumulates stroke coverage so the post pass can tell painted pixels from background
+    target.clear_color_and_depth(
+        (
+            data.paint_background[0],
+            data.paint_background[1],
+            data.paint_background[2],
+            0.0,
+        ),
+        1.0,
+    );
+
+    if data.params.enable_underpainting {
+        draw_underpainting(&mut target, state, data, model);
+    }
+
+    let layers = data.params.depth_peel_layers;
+    if layers == 0 {
+        if data.params.weighted_blending {
+            draw_points_weighted(display, &mut target, state, data, model)?;
+        } else {
+            draw_points(&mut target, state, data, model, PointPass::Sorted);
+        }
+        return Ok(());
+    }
+
+    let temporary_targets;
+    let targets = if data.depth_peel_targets.dimensions() == dimensions {
+        &data.depth_peel_targets
+    } else {
+        let (width, height) = dimensions;
+        temporary_targets = DepthPeelTargets::new(display, width, height)
+            .map_err(|e| LoadError::Gl("depth peel targets", e.to_string()))?;
+        &temporary_targets
+    };
+
+    draw_depth_peeled(
+        display,
+        targets,
+        &mut target,
+        layers,
+        &data.post_process_quad,
+        &data.peel_composite_program,
+        |layer, peel_depth| draw_points(layer, state, data, model, PointPass::Peel(peel_depth)),
+    );
+    Ok(())
+}
+
+fn draw_points_weighted(
+    display: &impl Facade,
+    target: &mut impl Surface,
+    state: &State,
+    data: &DrawData,
+    model: [[f32; 4]; 4],
+) -> Result<(), LoadError> {
+    let temporary_targets;
+    let targets = if data.oit_targets.dimensions() == target.get_dimensions() {
+        &data.oit_targets
+    } else {
+        let (width, height) = target.get_dimensions();
+        temporary_targets = OitTargets::new(display, width, height)
+            .map_err(|e| LoadError::Gl("weighted blending targets", e.to_string()))?;
+        &temporary_targets
+    };
+
+    draw_weighted_blended(
+        display,
+        targets,
+        target,
+        &data.post_process_quad,
+        &data.oit_resolve_program,
+        |pass_target, oit_pass| {
+            draw_points(
+                pass_target,
+                state,
+                data,
+                model,
+                PointPass::Weighted(oit_pass),
+            )
+        },
+    );
+    Ok(())
+}
+
+/// Draws the view space normals and depth of the models for the outline edge detection
+fn draw_mesh_geometry(
+    target: &mut impl Surface,
+    state: &State,
+    data: &DrawData,
+    model: [[f32; 4]; 4],
+) {
+    let uniforms = {
         let camera = state.camera.lock().unwrap();
         uniform! {
             view: camera.view(),
             perspective: camera.perspective(),
             model: model,
-            albedo_texture: &data.albedo_texture,
         }
     };
 
-    target.clear_color_and_depth(
-        (
-            data.background[0],
-            data.background[1],
-            data.background[2],
-            1.0,
-        ),
-        1.0,
-    );
-
     for model in &data.models {
+        let object: [[f32; 4]; 4] = model.transform.into();
         let (vb, ib) = &model.model_buffers;
         target
             .draw(
-                vb,
+                (vb, data.instance_buffer.per_instance().unwrap()),
                 ib,
-                &data.color_program,
-                &camera_uniforms,
+                &data.outline_geometry_program,
+                &uniforms.add("object", object),
                 &DrawParameters {
                     depth: Depth {
                         test: DepthTest::IfLess,
@@ -689,32 +4570,48 @@ fn draw_model(target: &mut impl Surface, state: &State, data: &DrawData, model:
     }
 }
 
-fn draw_points(target: &mut impl Surface, state: &State, data: &DrawData, model: [[f32; 4]; 4]) {
-    let camera_uniforms = {
+/// Paints the models shaded and textured like `draw_model` at `underpainting_opacity`, so gaps
+/// between strokes show a toned base matching the subject rather than the paint background.
+///
+/// The paint target has no depth buffer, so only back faces are culled.
+fn draw_underpainting(
+    target: &mut impl Surface,
+    state: &State,
+    data: &DrawData,
+    model: [[f32; 4]; 4],
+) {
+    let uniforms = {
         let camera = state.camera.lock().unwrap();
         uniform! {
             view: camera.view(),
             perspective: camera.perspective(),
             model: model,
-            albedo_texture: &data.albedo_texture,
-            brush_stroke: &data.brush_stroke,
-            camera_pos: <Point3<_> as Into<[f32; 3]>>::into(camera.position()),
-            quantization: data.params.quantization,
-            brush_size: data.params.brush_size,
-            enable_brush_tbn: data.params.enable_brush_tbn,
         }
     };
+    // Mixes color and coverage with what is below by the opacity
+    let mix = BlendingFunction::Addition {
+        source: LinearBlendingFactor::ConstantAlpha,
+        destination: LinearBlendingFactor::OneMinusConstantAlpha,
+    };
 
     for model in &data.models {
-        let (vb, ib) = &model.point_buffers;
+        let object: [[f32; 4]; 4] = model.transform.into();
+        let (vb, ib) = &model.model_buffers;
         target
             .draw(
-                vb,
+                (vb, data.instance_buffer.per_instance().unwrap()),
                 ib,
-                &data.point_program,
-                &camera_uniforms,
+                &data.color_program,
+                &uniforms
+                    .add("object", object)
+                    .add("albedo_texture", data.albedo(model)),
                 &DrawParameters {
-                    blend: Blend::alpha_blending(),
+                    blend: Blend {
+                        color: mix,
+                        alpha: mix,
+                        constant_value: (0.0, 0.0, 0.0, data.params.underpainting_opacity),
+                    },
+                    backface_culling: BackfaceCullingMode::CullClockwise,
                     ..Default::default()
                 },
             )
@@ -722,63 +4619,756 @@ fn draw_points(target: &mut impl Surface, state: &State, data: &DrawData, model:
     }
 }
 
+fn draw_post_process(
+    display: &impl Facade,
+    target: &mut impl Surface,
+    data: &DrawData,
+    points_texture: &SrgbTexture2d,
+) {
+    if data.params.enable_bloom {
+        draw_bloom(
+            display,
+            &data.bloom_targets,
+            points_texture,
+            data.params.bloom_threshold,
+            &data.post_process_quad,
+            &data.bloom_threshold_program,
+            &data.bloom_blur_program,
+        );
+    }
+
+    target.clear_color(0.0, 0.0, 0.0, 1.0);
+
+    // Keep the smudge the same size on screen when rendering supersampled frames
+    let pixel_scale = points_texture.width() as f32 / data.post_process_texture.width() as f32;
+    let smudge_radius = (data.params.smudge_radius as f32 * pixel_scale).round() as i32;
+
+    target
+        .draw(
+            &data.post_process_quad.0,
+            &data.post_process_quad.1,
+            &data.post_process_program,
+            &uniform! {
+                post_process_texture: points_texture,
+                canvas_texture: data
+                    .canvas_texture
+                    .sampled()
+                    .wrap_function(SamplerWrapFunction::Repeat),
+                canvas_scale: data.params.canvas_scale,
+                enable_canvas: data.params.enable_canvas,
+                canvas_strength: data.params.canvas_strength,
+                saturation: data.params.saturation,
+                exposure: data.params.exposure,
+                tonemap: match data.params.tonemap {
+                    Tonemap::None => 0,
+                    Tonemap::Reinhard => 1,
+                    Tonemap::Aces => 2,
+                },
+                hue_shift: data.params.hue_shift.to_radians(),
+                lift: data.params.lift,
+                gamma: data.params.gamma,
+                gain: data.params.gain,
+                background: data.background,
+                paint_background: data.paint_background,
+                // 0 is the flat background
+                background_gradient: match data.params.background_gradient {
+                    None => 0,
+                    Some(GradientStyle::Vertical) => 1,
+                    Some(GradientStyle::Radial) => 2,
+                },
+                gradient_color_a: data.params.gradient_colors[0],
+                gradient_color_b: data.params.gradient_colors[1],
+                smudge_radius: smudge_radius,
+                enable_bloom: data.params.enable_bloom,
+                bloom_texture: data.bloom_targets.texture(),
+                bloom_intensity: data.params.bloom_intensity,
+                enable_outline: data.params.enable_outline,
+                // Texels are compared exactly, filtering would blend normals across the edges
+                outline_texture: data
+                    .outline_targets
+                    .texture()
+                    .sampled()
+                    .minify_filter(MinifySamplerFilter::Nearest)
+                    .magnify_filter(MagnifySamplerFilter::Nearest),
+                outline_thickness: data.params.outline_thickness,
+                outline_threshold: data.params.outline_threshold,
+                outline_color: data.params.outline_color,
+                enable_vignette: data.params.enable_vignette,
+                vignette_radius: data.params.vignette_radius,
+                vignette_softness: data.params.vignette_softness,
+            },
+            &DrawParameters::default(),
+        )
+        .unwrap();
+}
+
+/// Builds the draw data of `scene` for a `width` by `height` framebuffer and the shared state
+/// around it, exiting on error. Without a window the albedo is decoded before returning.
+fn init_renderer(
+    display: &impl Facade,
+    args: &Args,
+    scene: &Scene,
+    width: u32,
+    height: u32,
+    headless: bool,
+) -> (DrawData, Arc<State>) {
+    let mut data = init_draw_data(display, scene, width, height).unwrap_or_else(|e| {
+        error!("{e}");
+        exit(1);
+    });
+    if headless {
+        data.finish_albedo(display, true).unwrap_or_else(|e| {
+            error!("{e}");
+            exit(1);
+        });
+    }
+
+    if let Some(path) = &args.save_points {
+        match save_points(path, scene, &data) {
+            Ok(()) => info!("Saved points to '{}'", path.display()),
+            Err(e) => error!("Failed to save points to '{}': {e}", path.display()),
+        }
+    }
+
+    let aspect = width as f32 / height as f32;
+    let state = Arc::new(State {
+        view_state: Mutex::new(ViewState::Full),
+        camera: Mutex::new(scene.camera(aspect)),
+        wheel_delta: Mutex::new(None),
+        drag_delta: Mutex::new(None),
+        keys: Mutex::new(HashSet::new()),
+        model: Mutex::new(scene.model_matrix()),
+        model_changed: AtomicBool::new(false),
+        turntable: AtomicBool::new(scene.turntable_speed.is_some()),
+        turntable_speed: Mutex::new(scene.turntable_speed.unwrap_or(DEFAULT_TURNTABLE_SPEED)),
+        smooth_zoom: AtomicBool::new(true),
+        enable_gui: AtomicBool::new(true),
+        fps_overlay: AtomicBool::new(args.fps_overlay),
+        sort_threads: args.sort_threads.unwrap_or(0),
+        debug_info: DebugInfo {
+            draw_time: AtomicU64::new(0),
+            sort_time: AtomicU64::new(0),
+            fixed_time: AtomicU64::new(0),
+            sort_period: AtomicU64::new(0),
+            sorts: AtomicU64::new(0),
+        },
+        stroke_density: AtomicU32::new(scene.stroke_density),
+        max_points: Mutex::new(scene.max_points),
+        seed: Mutex::new(scene.seed),
+        sampling: Mutex::new(scene.sampling.unwrap_or_default()),
+        sort_hz: AtomicU32::new(scene.sort_hz.unwrap_or(60)),
+        sort_angle_threshold: Mutex::new(
+            scene
+                .sort_angle_threshold
+                .unwrap_or(DEFAULT_SORT_ANGLE_THRESHOLD),
+        ),
+        sort_distance_threshold: Mutex::new(
+            scene
+                .sort_distance_threshold
+                .unwrap_or(DEFAULT_SORT_DISTANCE_THRESHOLD),
+        ),
+        sort_order: Mutex::new(data.params.sort_order()),
+        sorting: AtomicBool::new(!data.params.order_independent()),
+        adaptive_density: Mutex::new(scene.adaptive_density),
+        lod_reference_distance: Mutex::new(scene.camera_distance()),
+        area_scale: Mutex::new(scene.area_scale()),
+        toast: Mutex::new(None),
+    });
+    (data, state)
+}
+
+/// Runs the mode of the command line that renders without a window, exiting with a nonzero code
+/// if it fails
+fn run_headless(
+    args: &Args,
+    state: &State,
+    display: &impl Facade,
+    data: &mut DrawData,
+    scene: &Scene,
+    other_scene: Option<&Scene>,
+    reference: Option<&Reference>,
+) {
+    if let Some(dir) = &args.bundle {
+        // Reading the bundle back checks that a viewer can
+        match write_bundle(dir, state, data, scene).and_then(|()| bundle::read_bundle(dir)) {
+            Ok((_, points)) => {
+                info!(
+                    "Wrote bundle to '{}' ({} strokes)",
+                    dir.display(),
+                    points.len()
+                );
+            }
+            Err(e) => {
+                error!("Failed to write bundle to '{}': {e}", dir.display());
+                exit(1);
+            }
+        }
+        return;
+    }
+
+    if let Some(other_scene) = other_scene {
+        run_diff(
+            state,
+            display,
+            data,
+            scene,
+            other_scene,
+            args.output.as_deref().unwrap_or(Path::new("diff.png")),
+        );
+        return;
+    }
+
+    if let Some(reference) = reference {
+        let passed = if args.update_reference {
+            reference.update(state, display, data, scene)
+        } else {
+            reference.check(state, display, data, args.tolerance)
+        };
+        exit(if passed { 0 } else { 1 });
+    }
+
+    if let (Some(dir), Some(frames)) = (&args.output, args.turntable_frames) {
+        if let Err(e) = write_turntable(state, display, data, dir, frames) {
+            error!("{e}");
+            exit(1);
+        }
+        info!("Wrote {frames} turntable frames to '{}'", dir.display());
+        return;
+    }
+
+    if let Some(output) = &args.output {
+        sort_draw_data(state, display, data);
+        let result = if output.extension().is_some_and(|ext| ext == "exr") {
+            render_linear_paint_layer(state, display, data).and_then(|(pixels, width, height)| {
+                export::save_linear_exr(&pixels, width, height, output)
+                    .map_err(|e| LoadError::Write(output.clone(), e))
+            })
+        } else {
+            export_frame(state, display, data, output, ExportSettings::default())
+        };
+        if let Err(e) = result {
+            error!("{e}");
+            exit(1);
+        }
+        info!("Wrote '{}'", output.display());
+    }
+}
+
+/// Renders the painted frame at `supersample` times the size of the offscreen targets and reads
+/// it back as linear RGBA floats, rows from bottom to top. Returns the pixels and their dimensions.
+fn render_frame(
+    state: &State,
+    display: &impl Facade,
+    data: &DrawData,
+    supersample: u32,
+) -> Result<(Vec<f32>, u32, u32), LoadError> {
+    let model: [[f32; 4]; 4] = { <Matrix4<f32> as Into<_>>::into(*state.model.lock().unwrap()) };
+
+    // The targets follow the window, or the requested size without one
+    let (width, height) = data.post_process_texture.dimensions();
+    let supersample = max_supersample(display, width, height, supersample);
+    let width = width * supersample;
+    let height = height * supersample;
+
+    let points_texture = SrgbTexture2d::empty(display, width, height)
+        .map_err(|e| LoadError::Gl("export paint texture", e.to_string()))?;
+    draw_paint_layer(display, state, data, &points_texture, model)?;
+
+    let output = float_texture(display, width, height)?;
+    {
+        let mut target = SimpleFrameBuffer::new(display, &output)
+            .map_err(|e| LoadError::Gl("export framebuffer", format!("{e:?}")))?;
+        draw_post_process(display, &mut target, data, &points_texture);
+    }
+
+    Ok((read_float_texture(&output)?, width, height))
+}
+
+/// Largest factor up to `supersample` a `width` by `height` frame can be supersampled by without
+/// its targets growing past `GL_MAX_TEXTURE_SIZE`
+fn max_supersample(display: &impl Facade, width: u32, height: u32, supersample: u32) -> u32 {
+    let max_size = display
+        .get_context()
+        .get_capabilities()
+        .max_texture_size
+        .max(1) as u32;
+    let fits = (max_size / width.max(height).max(1)).max(1);
+    if supersample > fits {
+        warn!(
+            "{supersample}x supersampling exceeds the maximum texture size of {max_size}, \
+             using {fits}x"
+        );
+    }
+    supersample.clamp(1, fits)
+}
+
+/// A `width` by `height` RGBA float texture to read rendered frames back from
+fn float_texture(display: &impl Facade, width: u32, height: u32) -> Result<Texture2d, LoadError> {
+    Texture2d::empty_with_format(
+        display,
+        UncompressedFloatFormat::F32F32F32F32,
+        MipmapsOption::NoMipmap,
+        width,
+        height,
+    )
+    .map_err(|e| LoadError::Gl("export texture", e.to_string()))
+}
+
+/// Reads back the pixels of a float texture as RGBA floats, rows from bottom to top
+fn read_float_texture(texture: &Texture2d) -> Result<Vec<f32>, LoadError> {
+    let (width, height) = texture.dimensions();
+    let image = texture
+        .main_level()
+        .first_layer()
+        .into_image(None)
+        .ok_or_else(|| LoadError::Gl("export readback", "no image to read".to_string()))?;
+    let pixels: RawImage2d<f32> = image.raw_read::<_, (f32, f32, f32, f32)>(&Rect {
+        left: 0,
+        bottom: 0,
+        width,
+        height,
+    });
+    Ok(pixels.data.into_owned())
+}
+
+/// Renders the paint layer, before post processing, into a float texture and reads it back as
+/// linear RGBA floats, rows from bottom to top. Alpha is the stroke coverage.
+fn render_linear_paint_layer(
+    state: &State,
+    display: &impl Facade,
+    data: &DrawData,
+) -> Result<(Vec<f32>, u32, u32), LoadError> {
+    let model: [[f32; 4]; 4] = { <Matrix4<f32> as Into<_>>::into(*state.model.lock().unwrap()) };
+
+    let (width, height) = data.post_process_texture.dimensions();
+    let points_texture = float_texture(display, width, height)?;
+    draw_paint_layer(display, state, data, &points_texture, model)?;
+
+    Ok((read_float_texture(&points_texture)?, width, height))
+}
+
+/// Renders the painted view offscreen at `settings.supersample` times the size of the offscreen
+/// targets and writes it to `path`.
+///
+/// The post processing pass writes to a float texture so the downsample happens on linear values.
+fn export_frame(
+    state: &State,
+    display: &impl Facade,
+    data: &DrawData,
+    path: &Path,
+    settings: ExportSettings,
+) -> Result<(), LoadError> {
+    let (pixels, width, height) = render_frame(state, display, data, settings.supersample)?;
+    // The supersample factor is lowered for frames that would be too large to render
+    let settings = ExportSettings {
+        supersample: width / data.post_process_texture.width(),
+        ..settings
+    };
+    export::save_linear_frame(&pixels, width, height, settings, path)
+        .map_err(|e| LoadError::Write(path.to_path_buf(), e))
+}
+
+/// Renders `frames` frames of a full turn of the model around the vertical axis to
+/// `frame_0000.png`, `frame_0001.png`, ... in `dir`. The model is left where it started.
+fn write_turntable(
+    state: &State,
+    display: &impl Facade,
+    data: &mut DrawData,
+    dir: &Path,
+    frames: u32,
+) -> Result<(), LoadError> {
+    fs::create_dir_all(dir).map_err(|e| LoadError::Write(dir.to_path_buf(), e.into()))?;
+    let start = *state.model.lock().unwrap();
+    for i in 0..frames {
+        *state.model.lock().unwrap() =
+            Matrix4::from_angle_y(Deg(360.0 * i as f32 / frames as f32)) * start;
+        sort_draw_data(state, display, data);
+        let path = dir.join(format!("frame_{i:04}.png"));
+        export_frame(state, display, data, &path, ExportSettings::default())?;
+    }
+    *state.model.lock().unwrap() = start;
+    Ok(())
+}
+
+/// Sorts the points of `data` for the current camera and uploads the ones drawn at its distance
+fn sort_draw_data(state: &State, display: &impl Facade, data: &mut DrawData) {
+    let model = *state.model.lock().unwrap();
+    let (view, eye) = {
+        let camera = state.camera.lock().unwrap();
+        (Matrix4::from(camera.view()), camera.position())
+    };
+
+    let mut points = data
+        .models
+        .iter()
+        .map(|m| m.points.clone())
+        .collect::<Vec<_>>();
+    let instances = sort_points(
+        &mut points,
+        &data.models.iter().map(|m| m.transform).collect::<Vec<_>>(),
+        &data.instance_transforms,
+        model,
+        view,
+        data.params.sort_order(),
+    );
+
+    let levels = if data.params.adaptive_density {
+        lod::object_levels(
+            &points
+                .iter()
+                .map(|points| lod::center(points))
+                .collect::<Vec<_>>(),
+            &data.models.iter().map(|m| m.transform).collect::<Vec<_>>(),
+            &data.instance_transforms,
+            model,
+            eye,
+            *state.lod_reference_distance.lock().unwrap(),
+            data.params.target_coverage,
+            None,
+        )
+    } else {
+        vec![0; points.len()]
+    };
+
+    data.instance_buffer = gen_instance_buffer(display, &instances);
+    for ((model, points), level) in data.models.iter_mut().zip(points).zip(levels) {
+        // Every point is kept, so a nearer camera draws them again
+        model.point_buffers = gen_point_buffers(display, &lod::lod_points(&points, level));
+        model.points = points;
+    }
+}
+
+/// Renders the loaded scene and `other_scene` with the same camera and writes their amplified
+/// difference to `output`.
+///
+/// When both scenes generate strokes from the same inputs the strokes of the first scene are
+/// reused, so only the parameters differ between the two renders.
+fn run_diff(
+    state: &State,
+    display: &impl Facade,
+    data: &mut DrawData,
+    scene: &Scene,
+    other_scene: &Scene,
+    output: &Path,
+) {
+    let render = |data: &mut DrawData| {
+        sort_draw_data(state, display, data);
+        render_frame(state, display, data, 1).unwrap_or_else(|e| {
+            error!("{e}");
+            exit(1);
+        })
+    };
+    let (a, width, height) = render(data);
+
+    let same_strokes = scene.objects == other_scene.objects
+        && scene.stroke_density == other_scene.stroke_density
+        && scene.max_points == other_scene.max_points
+        && scene.seed == other_scene.seed
+        && scene.sampling == other_scene.sampling
+        && scene.shapes == other_scene.shapes
+        && scene.stroke_mask == other_scene.stroke_mask
+        && scene.importance_map == other_scene.importance_map
+        && scene.importance_strength == other_scene.importance_strength
+        && scene.density_curvature_weight == other_scene.density_curvature_weight
+        && scene.flow_from_albedo == other_scene.flow_from_albedo
+        && scene.flow_smoothing == other_scene.flow_smoothing
+        && scene.flat_stroke_color == other_scene.flat_stroke_color;
+    let points = data
+        .models
+        .iter()
+        .map(|m| m.points.clone())
+        .collect::<Vec<_>>();
+
+    data.load_scene(display, other_scene)
+        .and_then(|()| data.finish_albedo(display, true))
+        .unwrap_or_else(|e| {
+            error!("{e}");
+            exit(1);
+        });
+    if same_strokes {
+        for (model, points) in data.models.iter_mut().zip(points) {
+            model.points = points;
+        }
+    } else {
+        warn!("The scenes generate strokes differently, the difference includes stroke placement");
+    }
+
+    let (b, _, _) = render(data);
+
+    let a = export::resolve_linear_frame(&a, width, height, ExportSettings::default());
+    let b = export::resolve_linear_frame(&b, width, height, ExportSettings::default());
+    let (difference, stats) = diff::diff_images(&a, &b);
+    match difference.save(output) {
+        Ok(()) => println!(
+            "Wrote '{}': mean delta {:.5}, max delta {:.5}",
+            output.display(),
+            stats.mean,
+            stats.max
+        ),
+        Err(e) => {
+            error!("Failed to write '{}': {e}", output.display());
+            exit(1);
+        }
+    }
+}
+
+/// Saves the strokes of `data` so they can be loaded again with `--points`
+fn save_points(path: &Path, scene: &Scene, data: &DrawData) -> Result<(), PointsError> {
+    let points = data
+        .models
+        .iter()
+        .map(|m| m.points.clone())
+        .collect::<Vec<_>>();
+    let header = points_header(scene, data.models.iter().map(|m| m.model.name.clone()))?;
+    points_file::write_points(path, &header, &points)
+}
+
+/// Writes the loaded scene as a viewer bundle to `dir`
+fn write_bundle(dir: &Path, state: &State, data: &DrawData, scene: &Scene) -> io::Result<()> {
+    let albedo_source = &scene.albedo_texture;
+    let albedo_texture = match albedo_source.extension() {
+        Some(ext) => format!("albedo.{}", ext.to_string_lossy()),
+        None => "albedo".to_string(),
+    };
+    let (canvas_texture, canvas_png) = match &scene.canvas_texture {
+        Some(path) => {
+            let name = match path.extension() {
+                Some(ext) => format!("canvas.{}", ext.to_string_lossy()),
+                None => "canvas".to_string(),
+            };
+            (name, fs::read(path)?)
+        }
+        None => ("canvas.png".to_string(), CANVAS_PNG.to_vec()),
+    };
+    let brush_atlas = brushes::load_atlas(scene.brush_dir.as_deref())
+        .map_err(|e| io::Error::other(e.to_string()))?;
+    let brushes_png = brush_atlas
+        .png()
+        .map_err(|e| io::Error::other(e.to_string()))?;
+    // The viewer draws a single point list, so the objects are placed ahead of time
+    let points = data
+        .models
+        .iter()
+        .flat_map(|m| m.points.iter().map(|p| p.transformed(m.transform)))
+        .collect::<Vec<_>>();
+    if scene
+        .objects
+        .iter()
+        .any(|object| object.albedo_texture.is_some())
+    {
+        warn!("Bundles only include the scene albedo texture, object albedo textures are ignored");
+    }
+
+    let bundle = Bundle {
+        version: bundle::VERSION,
+        crate_version: env!("CARGO_PKG_VERSION"),
+        num_points: points.len(),
+        point_attributes: &bundle::POINT_ATTRIBUTES,
+        albedo_texture,
+        brush_atlas: "brushes.png",
+        num_brushes: data.stroke_maps.brush_names.len() as u32,
+        brush_rects: brush_atlas.uv_rects(),
+        canvas_texture,
+        background: data.background,
+        paint_background: data.paint_background,
+        params: &data.params,
+        model_matrix: (*state.model.lock().unwrap()).into(),
+        instances: data
+            .instance_transforms
+            .iter()
+            .map(|&instance| instance.into())
+            .collect(),
+        camera: (&*state.camera.lock().unwrap()).into(),
+    };
+    bundle::write_bundle(
+        dir,
+        &bundle,
+        &points,
+        albedo_source,
+        &brushes_png,
+        &canvas_png,
+    )
+}
+
+/// Reference render of a scene, used to catch unintended changes to the output
+struct Reference {
+    name: String,
+    image: PathBuf,
+    points: PathBuf,
+    diff: PathBuf,
+}
+
+impl Reference {
+    fn new(dir: &Path, scene_path: &Path) -> Self {
+        let name = scene_path
+            .file_stem()
+            .unwrap()
+            .to_string_lossy()
+            .into_owned();
+        Self {
+            image: dir.join(format!("{name}.png")),
+            points: dir.join(format!("{name}.points")),
+            diff: dir.join(format!("{name}.diff.png")),
+            name,
+        }
+    }
+
+    fn render(
+        state: &State,
+        display: &impl Facade,
+        data: &mut DrawData,
+    ) -> Result<RgbImage, LoadError> {
+        sort_draw_data(state, display, data);
+        let (pixels, width, height) = render_frame(state, display, data, 1)?;
+        Ok(export::resolve_linear_frame(
+            &pixels,
+            width,
+            height,
+            ExportSettings::default(),
+        ))
+    }
+
+    /// Writes the strokes and render of the scene as its new reference
+    fn update(
+        &self,
+        state: &State,
+        display: &impl Facade,
+        data: &mut DrawData,
+        scene: &Scene,
+    ) -> bool {
+        if let Err(e) = save_points(&self.points, scene, data) {
+            error!("Failed to save '{}': {e}", self.points.display());
+            return false;
+        }
+        let image = match Self::render(state, display, data) {
+            Ok(image) => image,
+            Err(e) => {
+                error!("{e}");
+                return false;
+            }
+        };
+        if let Err(e) = image.save(&self.image) {
+            error!("Failed to save '{}': {e}", self.image.display());
+            return false;
+        }
+        println!("{}: updated", self.name);
+        true
+    }
+
+    /// Compares a render of the scene to the reference, writing a difference image if they differ
+    /// by more than `tolerance`
+    fn check(
+        &self,
+        state: &State,
+        display: &impl Facade,
+        data: &mut DrawData,
+        tolerance: f32,
+    ) -> bool {
+        let reference = match image::open(&self.image) {
+            Ok(reference) => reference.into_rgb8(),
+            Err(e) => {
+                error!("Failed to open '{}': {e}", self.image.display());
+                return false;
+            }
+        };
+        let image = match Self::render(state, display, data) {
+            Ok(image) => image,
+            Err(e) => {
+                error!("{e}");
+                return false;
+            }
+        };
+        if image.dimensions() != reference.dimensions() {
+            println!(
+                "{}: FAIL, rendered at {:?} but the reference is {:?}",
+                self.name,
+                image.dimensions(),
+                reference.dimensions()
+            );
+            return false;
+        }
+
+        let (difference, stats) = diff::diff_images(&reference, &image);
+        if stats.max <= tolerance {
+            println!("{}: ok (max delta {:.5})", self.name, stats.max);
+            return true;
+        }
+
+        println!(
+            "{}: FAIL (mean delta {:.5}, max delta {:.5}), difference written to '{}'",
+            self.name,
+            stats.mean,
+            stats.max,
+            self.diff.display()
+        );
+        if let Err(e) = difference.save(&self.diff) {
+            error!("Failed to save '{}': {e}", self.diff.display());
+        }
+        false
+    }
+}
+
+fn on_off(enabled: bool) -> &'static str {
+    if enabled {
+        "on"
+    } else {
+        "off"
+    }
+}
+
 fn draw(state: &State, display: &Display, data: &DrawData, egui_glium: &mut EguiGlium) {
     let model: [[f32; 4]; 4] = { <Matrix4<f32> as Into<_>>::into(*state.model.lock().unwrap()) };
     let view_state = { *state.view_state.lock().unwrap() };
 
     match view_state {
         ViewState::Full => {
-            // render points
+            // The window sized targets are allocated up front, so this doesn't allocate
+            if let Err(e) =
+                draw_paint_layer(display, state, data, &data.post_process_texture, model)
             {
-                let mut target =
-                    SimpleFrameBuffer::new(display, &data.post_process_texture).unwrap();
-
-                target.clear_color_and_depth(
-                    (
-                        data.background[0],
-                        data.background[1],
-                        data.background[2],
-                        1.0,
-                    ),
-                    1.0,
-                );
+                error!("{e}");
+            }
+
+            let mut target = display.draw();
 
-                draw_points(&mut target, state, data, model);
+            draw_post_process(display, &mut target, data, &data.post_process_texture);
+
+            if state.gui_visible() {
+                egui_glium.paint(display, &mut target);
             }
 
-            {
-                let mut target = display.draw();
-
-                target.clear_color(0.0, 0.0, 0.0, 1.0);
-
-                target
-                    .draw(
-                        &data.post_process_quad.0,
-                        &data.post_process_quad.1,
-                        &data.post_process_program,
-                        &uniform! {
-                            post_process_texture: &data.post_process_texture,
-                            canvas_texture: &data.canvas_texture,
-                            enable_canvas: data.params.enable_canvas,
-                            saturation: data.params.saturation,
-                        },
-                        &DrawParameters::default(),
-                    )
-                    .unwrap();
+            target.finish().unwrap();
+        }
+        ViewState::Raster | ViewState::Normals => {
+            let mut target = display.draw();
 
-                if state.enable_gui.load(Ordering::Relaxed) {
-                    egui_glium.paint(display, &mut target);
-                }
+            let program = match view_state {
+                ViewState::Normals => &data.normal_program,
+                _ => &data.color_program,
+            };
+            draw_model(&mut target, state, data, model, program);
 
-                target.finish().unwrap();
+            if state.gui_visible() {
+                egui_glium.paint(display, &mut target);
             }
+
+            target.finish().unwrap();
         }
-        ViewState::Raster => {
+        ViewState::Wireframe => {
             let mut target = display.draw();
 
-            draw_model(&mut target, state, data, model);
+            target.clear_color_and_depth(
+                (
+                    data.background[0],
+                    data.background[1],
+                    data.background[2],
+                    1.0,
+                ),
+                1.0,
+            );
+            draw_wire_overlay(&mut target, state, data, model, false);
 
-            if state.enable_gui.load(Ordering::Relaxed) {
+            if state.gui_visible() {
                 egui_glium.paint(display, &mut target);
             }
 
@@ -786,3 +5376,259 @@ fn draw(state: &State, display: &Display, data: &DrawData, egui_glium: &mut Egui
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::cmp::Reverse;
+
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+    use rayon::slice::ParallelSliceMut;
+
+    use super::*;
+
+    /// Writes a scene file referencing `obj_file` and `albedo_texture` to a new directory in the
+    /// temp dir, next to a `textures/albedo.png` file
+    fn write_scene(name: &str, obj_file: &Path, albedo_texture: &Path) -> PathBuf {
+        let dir = env::temp_dir().join(format!("paint_render_{name}_{}", std::process::id()));
+        fs::create_dir_all(dir.join("scenes")).unwrap();
+        fs::create_dir_all(dir.join("textures")).unwrap();
+        fs::write(dir.join("textures/albedo.png"), []).unwrap();
+        let path = dir.join("scenes/scene.toml");
+        let source = toml::to_string(&toml::toml! {
+            obj_file = (obj_file.to_str().unwrap())
+            albedo_texture = (albedo_texture.to_str().unwrap())
+            stroke_density = 100
+            brush_size = 0.04
+            quantization = 8
+            background = [0.5, 0.5, 0.5]
+        })
+        .unwrap();
+        fs::write(&path, source).unwrap();
+        path
+    }
+
+    #[test]
+    fn scene_assets_resolve_against_the_scene_dir() {
+        let obj_file = Path::new(env!("CARGO_MANIFEST_DIR")).join("res/models/apple.obj");
+        let path = write_scene("resolve", &obj_file, Path::new("../textures/albedo.png"));
+        let dir = path.parent().unwrap().parent().unwrap();
+
+        let LoadedScene {
+            scene,
+            dir: scene_dir,
+        } = Scene::load(&path).unwrap();
+        let dir = fs::canonicalize(dir).unwrap();
+        assert_eq!(scene_dir, dir.join("scenes"));
+        // The absolute path is kept, the relative one joined to the scene's directory
+        assert_eq!(scene.objects[0].file, fs::canonicalize(&obj_file).unwrap());
+        assert_eq!(scene.albedo_texture, dir.join("textures/albedo.png"));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn drawing_settings_keep_the_points_cache_key() {
+        let obj_file = Path::new(env!("CARGO_MANIFEST_DIR")).join("res/models/apple.obj");
+        let path = write_scene("cache_key", &obj_file, Path::new("../textures/albedo.png"));
+        let dir = path.parent().unwrap().parent().unwrap().to_path_buf();
+        let mut scene = Scene::load(&path).unwrap().scene;
+        let stroke_maps = StrokeMaps::default();
+        let key = points_cache_key(&scene, &stroke_maps).unwrap();
+
+        scene.exposure = Some(2.0);
+        scene.fov = Some(30.0);
+        assert_eq!(points_cache_key(&scene, &stroke_maps).unwrap(), key);
+        scene.stroke_density += 1;
+        assert_ne!(points_cache_key(&scene, &stroke_maps).unwrap(), key);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn points_cache_keeps_the_newest_files() {
+        let dir = env::temp_dir().join(format!("paint_render_prune_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let start = SystemTime::now();
+        for i in 0..5u64 {
+            let file = fs::File::create(dir.join(format!("{i}.points"))).unwrap();
+            file.set_modified(start - Duration::from_secs(10 - i))
+                .unwrap();
+        }
+        fs::write(dir.join("other.txt"), []).unwrap();
+
+        prune_points_cache(&dir, 2).unwrap();
+        let mut left = fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect::<Vec<_>>();
+        left.sort();
+        assert_eq!(left, ["3.points", "4.points", "other.txt"]);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn gltf_buffers_are_scene_assets() {
+        let path = write_scene(
+            "gltf",
+            Path::new("../models/mesh.gltf"),
+            Path::new("../textures/albedo.png"),
+        );
+        let dir = path.parent().unwrap().parent().unwrap().to_path_buf();
+        fs::create_dir_all(dir.join("models")).unwrap();
+        let gltf = r#"{
+            "asset": { "version": "2.0" },
+            "buffers": [
+                { "uri": "mesh.bin", "byteLength": 4 },
+                { "uri": "data:application/octet-stream;base64,AAAAAA==", "byteLength": 4 }
+            ]
+        }"#;
+        fs::write(dir.join("models/mesh.gltf"), gltf).unwrap();
+        fs::write(dir.join("models/mesh.bin"), [0; 4]).unwrap();
+
+        let scene = Scene::load(&path).unwrap().scene;
+        let models = fs::canonicalize(dir.join("models")).unwrap();
+        let assets = scene.asset_paths();
+        assert!(assets.contains(&models.join("mesh.gltf")));
+        assert!(assets.contains(&models.join("mesh.bin")));
+        assert_eq!(assets.len(), 3);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn missing_scene_assets_fail_to_load() {
+        let path = write_scene(
+            "missing",
+            Path::new("../models/missing.obj"),
+            Path::new("../textures/albedo.png"),
+        );
+        let dir = path.parent().unwrap().parent().unwrap().to_path_buf();
+
+        match Scene::load(&path) {
+            Err(LoadError::Io(missing, _)) => assert!(missing.ends_with("models/missing.obj")),
+            Err(e) => panic!("Unexpected error: {e}"),
+            Ok(_) => panic!("Loaded a scene with a missing model"),
+        }
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn invalid_camera_settings_fail_to_load() {
+        let path = write_scene(
+            "camera",
+            Path::new("../models/missing.obj"),
+            Path::new("../textures/albedo.png"),
+        );
+        let dir = path.parent().unwrap().parent().unwrap().to_path_buf();
+        let source = fs::read_to_string(&path).unwrap();
+
+        for settings in [
+            "fov = 180.0",
+            "fov = 0.0",
+            "near = 0.0",
+            "near = 1.0\nfar = 1.0",
+            "scale = [0.0, 1.0, 1.0]",
+            "scale = [1.0, -1.0, 1.0]",
+            "scale = [1.0, 1.0, nan]",
+        ] {
+            fs::write(&path, format!("{source}{settings}\n")).unwrap();
+            match Scene::load(&path) {
+                Err(LoadError::Invalid(..)) => {}
+                Err(e) => panic!("Unexpected error for {settings:?}: {e}"),
+                Ok(_) => panic!("Loaded a scene with {settings:?}"),
+            }
+        }
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    /// `count` points scattered in a unit cube
+    fn random_points(count: usize) -> Vec<Point> {
+        let mut rng = StdRng::seed_from_u64(0);
+        (0..count)
+            .map(|_| {
+                let position = [rng.gen::<f32>(), rng.gen(), rng.gen()];
+                Point::new(position, [0.0; 3], [0.0; 3], [0.0; 3], [0.0; 2], 0)
+            })
+            .collect()
+    }
+
+    /// Mean time `sort` takes to sort a copy of `points`
+    fn time_sort(points: &[Point], mut sort: impl FnMut(&mut Vec<Point>)) -> Duration {
+        let runs = 5;
+        let mut total = Duration::ZERO;
+        for _ in 0..runs {
+            let mut points = points.to_vec();
+            let start = Instant::now();
+            sort(&mut points);
+            total += start.elapsed();
+        }
+        total / runs
+    }
+
+    #[test]
+    fn sorted_points_go_from_far_to_near() {
+        let mut points = vec![random_points(10_000)];
+        let view = Matrix4::look_at_rh(
+            Point3::new(0.5, 0.5, 3.0),
+            Point3::new(0.5, 0.5, 0.5),
+            Vector3::unit_y(),
+        );
+        sort_points(
+            &mut points,
+            &[Matrix4::identity()],
+            &[],
+            Matrix4::identity(),
+            view,
+            SortOrder::FarToNear,
+        );
+
+        let depths = points[0]
+            .iter()
+            .map(|p| (view * p.position().to_homogeneous()).z)
+            .collect::<Vec<_>>();
+        assert!(depths.windows(2).all(|pair| pair[0] <= pair[1]));
+    }
+
+    #[test]
+    #[ignore = "benchmark, run with `cargo test --release -- --ignored --nocapture`"]
+    fn bench_sort() {
+        let points = random_points(1_000_000);
+        let model = Matrix4::from_angle_y(Deg(30.0));
+        let view = Matrix4::look_at_rh(
+            Point3::new(0.0, 0.5, 3.0),
+            Point3::new(0.5, 0.5, 0.5),
+            Vector3::unit_y(),
+        );
+        let perspective = cgmath::perspective(Deg(45.0), 16.0 / 9.0, 0.1, 10.0);
+
+        // The projected depth of the full model view projection as the cached key, as the points
+        // were sorted before
+        let projected = time_sort(&points, |points| {
+            points.par_sort_by_cached_key(|p| {
+                let p = perspective * view * model * p.position().to_homogeneous();
+                // Flipping the magnitude bits of negative floats orders them like `total_cmp`
+                let bits = (p.z / p.w).to_bits() as i32;
+                Reverse(bits ^ (((bits >> 31) as u32) >> 1) as i32)
+            })
+        });
+        let bucketed = time_sort(&points, |points| {
+            sort_points(
+                std::slice::from_mut(points),
+                &[Matrix4::identity()],
+                &[],
+                model,
+                view,
+                SortOrder::FarToNear,
+            );
+        });
+
+        println!(
+            "Sorting {} points: projected key {projected:?}, view depth buckets {bucketed:?}",
+            points.len()
+        );
+        assert!(bucketed < projected);
+    }
+}
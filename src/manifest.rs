@@ -0,0 +1,138 @@
+use std::{
+    fs::{self, File},
+    io::{self, BufWriter},
+    path::{Component, Path, PathBuf},
+};
+
+use cgmath::Deg;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    camera::{Camera, Projection},
+    export::ExportSettings,
+    Params,
+};
+
+/// Everything needed to reproduce an exported frame
+#[derive(Debug, Serialize)]
+pub struct Manifest<'a> {
+    pub crate_version: &'static str,
+    pub scene_file: &'a Path,
+    pub image: &'a Path,
+    pub assets: Vec<Asset>,
+    pub stroke_density: u32,
+    pub max_points: Option<usize>,
+    /// Seed of the point generation, `None` if the points were random
+    pub seed: Option<u64>,
+    pub background: [f32; 3],
+    pub params: &'a Params,
+    pub model_matrix: [[f32; 4]; 4],
+    pub camera: CameraManifest,
+    pub export: ExportSettings,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Asset {
+    /// Path of the file relative to the scene file's directory
+    pub path: PathBuf,
+    pub sha256: String,
+}
+
+impl Asset {
+    /// Hashes the file at `path`, recording it relative to `scene_dir` so the manifest doesn't
+    /// depend on where the scene is checked out
+    pub fn hash(path: &Path, scene_dir: &Path) -> io::Result<Asset> {
+        Ok(Asset {
+            path: relative_to(path, scene_dir),
+            sha256: sha256(path)?,
+        })
+    }
+}
+
+/// Hex SHA-256 of the contents of the file at `path`
+pub fn sha256(path: &Path) -> io::Result<String> {
+    Ok(format!("{:x}", Sha256::digest(fs::read(path)?)))
+}
+
+/// Path of `path` relative to `base`, both absolute. Goes up out of `base` with `..` components
+/// when `path` isn't inside it, and stays absolute when they don't share a root (another drive).
+fn relative_to(path: &Path, base: &Path) -> PathBuf {
+    let mut path_components = path.components().peekable();
+    let mut base_components = base.components().peekable();
+    if path_components.peek() != base_components.peek() {
+        return path.to_path_buf();
+    }
+    while let (Some(a), Some(b)) = (path_components.peek(), base_components.peek()) {
+        if a != b {
+            break;
+        }
+        path_components.next();
+        base_components.next();
+    }
+    base_components
+        .map(|_| Component::ParentDir)
+        .chain(path_components)
+        .collect()
+}
+
+#[derive(Debug, Serialize)]
+pub struct CameraManifest {
+    pub position: [f32; 3],
+    pub direction: [f32; 3],
+    pub fov_degrees: f32,
+    /// Height of the view of an orthographic camera, `None` for perspective
+    pub ortho_height: Option<f32>,
+    pub aspect_ratio: f32,
+    pub near: f32,
+    pub far: f32,
+}
+
+impl From<&Camera> for CameraManifest {
+    fn from(camera: &Camera) -> Self {
+        Self {
+            position: camera.position().into(),
+            direction: camera.direction().into(),
+            fov_degrees: Deg::from(camera.fov()).0,
+            ortho_height: match camera.projection() {
+                Projection::Perspective => None,
+                Projection::Orthographic { height } => Some(height),
+            },
+            aspect_ratio: camera.aspect_ratio(),
+            near: camera.near(),
+            far: camera.far(),
+        }
+    }
+}
+
+pub fn write_manifest(path: &Path, manifest: &Manifest) -> io::Result<()> {
+    let file = BufWriter::new(File::create(path)?);
+    serde_json::to_writer_pretty(file, manifest)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn asset_paths_are_relative_to_the_scene_dir() {
+        let base = Path::new("/home/user/scenes");
+        assert_eq!(
+            relative_to(Path::new("/home/user/scenes/apple.obj"), base),
+            Path::new("apple.obj")
+        );
+        assert_eq!(
+            relative_to(Path::new("/home/user/scenes/textures/apple.png"), base),
+            Path::new("textures/apple.png")
+        );
+        assert_eq!(
+            relative_to(Path::new("/home/user/models/apple.obj"), base),
+            Path::new("../models/apple.obj")
+        );
+        assert_eq!(
+            relative_to(Path::new("/opt/apple.obj"), base),
+            Path::new("../../../opt/apple.obj")
+        );
+    }
+}
@@ -1,13 +1,16 @@
+use cgmath::{prelude::*, Vector3};
 use glium::{
+    backend::Facade,
     implement_vertex,
     index::{NoIndices, PrimitiveType},
-    Display, IndexBuffer, VertexBuffer,
+    IndexBuffer, VertexBuffer,
 };
+use serde::{Deserialize, Serialize};
 use tobj::Mesh;
 
 use crate::point_gen::Point;
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
 pub struct Vertex {
     position: [f32; 3],
     normal: [f32; 3],
@@ -15,51 +18,98 @@ pub struct Vertex {
 }
 implement_vertex!(Vertex, position, normal, tex_coords);
 
-pub fn gen_point_buffers(display: &Display, points: &[Point]) -> (VertexBuffer<Point>, NoIndices) {
+impl Vertex {
+    pub fn new(
+        position: impl Into<[f32; 3]>,
+        normal: impl Into<[f32; 3]>,
+        tex_coords: impl Into<[f32; 2]>,
+    ) -> Self {
+        Self {
+            position: position.into(),
+            normal: normal.into(),
+            tex_coords: tex_coords.into(),
+        }
+    }
+}
+
+pub fn gen_point_buffers(
+    display: &impl Facade,
+    points: &[Point],
+) -> (VertexBuffer<Point>, NoIndices) {
     (
         VertexBuffer::new(display, points).unwrap(),
         NoIndices(PrimitiveType::Points),
     )
 }
 
-pub fn gen_buffers(display: &Display, mesh: &Mesh) -> (VertexBuffer<Vertex>, IndexBuffer<u32>) {
+pub fn gen_buffers(display: &impl Facade, mesh: &Mesh) -> (VertexBuffer<Vertex>, IndexBuffer<u32>) {
     let mut vertices = vec![];
 
-    let has_normals = !mesh.normals.is_empty();
     let has_tex_coords = !mesh.texcoords.is_empty();
 
-    if has_normals {
+    // Without normals the raster view would be unshaded
+    let smoothed;
+    let normals = if mesh.normals.is_empty() {
+        smoothed = smooth_normals(mesh);
+        &smoothed
+    } else {
         assert_eq!(mesh.positions.len() / 3, mesh.normals.len() / 3);
-    }
+        &mesh.normals
+    };
 
     if has_tex_coords {
         assert_eq!(mesh.positions.len() / 3, mesh.texcoords.len() / 2);
     }
 
-    for position in mesh.positions.chunks_exact(3) {
+    for (i, position) in mesh.positions.chunks_exact(3).enumerate() {
         let position = [position[0], position[1], position[2]];
-        vertices.push(Vertex {
-            position,
-            normal: [0.0, 0.0, 0.0],
-            tex_coords: [0.0, 0.0],
-        });
+        let normal = [normals[i * 3], normals[i * 3 + 1], normals[i * 3 + 2]];
+        let tex_coords = if has_tex_coords {
+            [mesh.texcoords[i * 2], mesh.texcoords[i * 2 + 1]]
+        } else {
+            [0.0, 0.0]
+        };
+        vertices.push(Vertex::new(position, normal, tex_coords));
     }
 
-    if has_normals {
-        for (i, normal) in mesh.normals.chunks_exact(3).enumerate() {
-            let normal = [normal[0], normal[1], normal[2]];
-            vertices[i].normal = normal;
-        }
-    }
+    let vb = VertexBuffer::new(display, &vertices).unwrap();
+    let ib = IndexBuffer::new(display, PrimitiveType::TrianglesList, &mesh.indices).unwrap();
+    (vb, ib)
+}
 
-    if has_tex_coords {
-        for (i, tex_coord) in mesh.texcoords.chunks_exact(2).enumerate() {
-            let tex_coord = [tex_coord[0], tex_coord[1]];
-            vertices[i].tex_coords = tex_coord;
+/// Smooth unit vertex normals of a mesh without normals, as flat `x, y, z` triples like
+/// `Mesh::normals`. The normals of the triangles sharing a vertex are accumulated weighted by
+/// their area.
+pub fn smooth_normals(mesh: &Mesh) -> Vec<f32> {
+    let position = |i: u32| {
+        let i = i as usize * 3;
+        Vector3::new(
+            mesh.positions[i],
+            mesh.positions[i + 1],
+            mesh.positions[i + 2],
+        )
+    };
+
+    let mut normals = vec![Vector3::zero(); mesh.positions.len() / 3];
+    for triangle in mesh.indices.chunks_exact(3) {
+        let [a, b, c] = [triangle[0], triangle[1], triangle[2]].map(position);
+        // The length of the cross product is twice the area
+        let normal = (b - a).cross(c - a);
+        for &i in triangle {
+            normals[i as usize] += normal;
         }
     }
 
-    let vb = VertexBuffer::new(display, &vertices).unwrap();
-    let ib = IndexBuffer::new(display, PrimitiveType::TrianglesList, &mesh.indices).unwrap();
-    (vb, ib)
+    normals
+        .into_iter()
+        .flat_map(|normal| {
+            // Vertices of degenerate triangles only
+            let normal = if normal.magnitude2() > 0.0 {
+                normal.normalize()
+            } else {
+                Vector3::unit_y()
+            };
+            [normal.x, normal.y, normal.z]
+        })
+        .collect()
 }
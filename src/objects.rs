@@ -1,30 +1,251 @@
-use std::{path::Path, process::exit, time::Instant};
+use std::{
+    collections::HashMap,
+    fmt,
+    path::{Path, PathBuf},
+    time::Instant,
+};
 
-use glium::{index::NoIndices, Display, IndexBuffer, VertexBuffer};
-use log::{error, info};
-use tobj::{LoadOptions, Model};
+use cgmath::{prelude::*, Matrix3, Matrix4, Point3};
+use glium::{backend::Facade, index::NoIndices, IndexBuffer, VertexBuffer};
+use log::{info, warn};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use serde::{Deserialize, Deserializer, Serialize};
+use tobj::{LoadError, LoadOptions, Model};
 
 use crate::{
-    mesh::{gen_buffers, gen_point_buffers, Vertex},
-    point_gen::{gen_point_list, Point},
+    gltf_file::load_gltf,
+    instances::InstanceDesc,
+    mesh::{gen_buffers, gen_point_buffers, smooth_normals, Vertex},
+    point_gen::{gen_point_list, prune_points, BrushRef, MeshSampler, Point, Sampling, StrokeMaps},
 };
 
+/// Settings for a single shape of the OBJ file, set with `[shape.<name>]` in the scene
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+pub struct ShapeOverride {
+    /// Multiplier of the scene stroke density
+    pub density_scale: Option<f32>,
+    /// Brushes the shape's strokes are drawn with
+    pub brushes: Option<Vec<BrushRef>>,
+}
+
+/// One OBJ file of the scene, given in `obj_file` as a path or as a table with its own settings
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(from = "ObjectEntry", into = "ObjectEntry")]
+pub struct ObjectDesc {
+    pub file: PathBuf,
+    /// Albedo of the object's strokes instead of the scene's
+    pub albedo_texture: Option<PathBuf>,
+    /// Stroke density of the object instead of the scene's
+    pub stroke_density: Option<u32>,
+    /// `position`, `rotation` and `scale` of the object in the scene
+    pub placement: InstanceDesc,
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(untagged)]
+enum ObjectEntry {
+    Path(PathBuf),
+    Object {
+        file: PathBuf,
+        albedo_texture: Option<PathBuf>,
+        stroke_density: Option<u32>,
+        #[serde(flatten)]
+        placement: InstanceDesc,
+    },
+}
+
+impl From<ObjectEntry> for ObjectDesc {
+    fn from(entry: ObjectEntry) -> Self {
+        match entry {
+            ObjectEntry::Path(file) => ObjectDesc {
+                file,
+                albedo_texture: None,
+                stroke_density: None,
+                placement: InstanceDesc::default(),
+            },
+            ObjectEntry::Object {
+                file,
+                albedo_texture,
+                stroke_density,
+                placement,
+            } => ObjectDesc {
+                file,
+                albedo_texture,
+                stroke_density,
+                placement,
+            },
+        }
+    }
+}
+
+impl From<ObjectDesc> for ObjectEntry {
+    /// Written as just the path when the object has no settings of its own
+    fn from(object: ObjectDesc) -> Self {
+        if object.albedo_texture.is_none()
+            && object.stroke_density.is_none()
+            && object.placement == InstanceDesc::default()
+        {
+            ObjectEntry::Path(object.file)
+        } else {
+            ObjectEntry::Object {
+                file: object.file,
+                albedo_texture: object.albedo_texture,
+                stroke_density: object.stroke_density,
+                placement: object.placement,
+            }
+        }
+    }
+}
+
+impl ObjectDesc {
+    /// Transform from the object's OBJ file into the scene
+    pub fn transform(&self) -> Matrix4<f32> {
+        self.placement.transform()
+    }
+}
+
+/// Deserializes `obj_file`, either a single object or an array of them
+pub fn deserialize_objects<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Vec<ObjectDesc>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        Many(Vec<ObjectDesc>),
+        One(ObjectDesc),
+    }
+
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::Many(objects) => objects,
+        OneOrMany::One(object) => vec![object],
+    })
+}
+
+/// A shape of one of the scene's objects with its settings
+#[derive(Debug, Clone)]
+pub struct SceneModel {
+    pub model: Model,
+    pub shape: ShapeOverride,
+    /// Index of the object the shape belongs to
+    pub object: usize,
+    /// Transform of the object
+    pub transform: Matrix4<f32>,
+    /// Stroke density of the object relative to the scene's
+    pub density_scale: f32,
+    /// Diffuse texture of the shape's material in the MTL file, if it has one
+    pub material_albedo: Option<PathBuf>,
+}
+
 pub struct ModelData {
-    #[allow(dead_code)]
     pub model: Model,
+    pub shape: ShapeOverride,
+    pub object: usize,
+    pub transform: Matrix4<f32>,
+    pub density_scale: f32,
+    pub material_albedo: Option<PathBuf>,
     pub model_buffers: (VertexBuffer<Vertex>, IndexBuffer<u32>),
-    #[allow(dead_code)]
     pub points: Vec<Point>,
     pub point_buffers: (VertexBuffer<Point>, NoIndices),
 }
 
-pub fn gen_models(
-    obj_file: impl AsRef<Path>,
-    stroke_density: f32,
-    display: &Display,
-) -> Vec<ModelData> {
-    let obj_file = obj_file.as_ref();
-    let (models, _materials) = tobj::load_obj(
+impl ModelData {
+    pub fn scene_model(&self) -> SceneModel {
+        SceneModel {
+            model: self.model.clone(),
+            shape: self.shape.clone(),
+            object: self.object,
+            transform: self.transform,
+            density_scale: self.density_scale,
+            material_albedo: self.material_albedo.clone(),
+        }
+    }
+}
+
+/// Failure to load the shapes of an object's file
+#[derive(Debug)]
+pub enum ModelError {
+    Obj(LoadError),
+    Gltf(gltf::Error),
+}
+
+impl fmt::Display for ModelError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ModelError::Obj(e) => write!(f, "{e}"),
+            ModelError::Gltf(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl From<LoadError> for ModelError {
+    fn from(e: LoadError) -> Self {
+        ModelError::Obj(e)
+    }
+}
+
+impl From<gltf::Error> for ModelError {
+    fn from(e: gltf::Error) -> Self {
+        ModelError::Gltf(e)
+    }
+}
+
+/// Whether `path` is a glTF or GLB file rather than an OBJ file, from its extension
+pub fn is_gltf(path: &Path) -> bool {
+    path.extension()
+        .and_then(|extension| extension.to_str())
+        .is_some_and(|extension| {
+            extension.eq_ignore_ascii_case("gltf") || extension.eq_ignore_ascii_case("glb")
+        })
+}
+
+/// Loads the shapes of an OBJ, glTF or GLB file, each with the diffuse texture of its material
+pub fn load_models(
+    model_file: impl AsRef<Path>,
+    uv_channel: usize,
+) -> Result<Vec<(Model, Option<PathBuf>)>, ModelError> {
+    let model_file = model_file.as_ref();
+    let mut models = if is_gltf(model_file) {
+        load_gltf(model_file, uv_channel)?
+            .into_iter()
+            .map(|model| (model, None))
+            .collect()
+    } else {
+        load_obj(model_file, uv_channel)?
+    };
+
+    for (model, _) in &mut models {
+        info!(
+            "Loaded model {} with {} triangles",
+            model.name,
+            model.mesh.indices.len() / 3,
+        );
+        // Strokes are oriented by the normals
+        if model.mesh.normals.is_empty() {
+            warn!(
+                "Model {} has no normals, using smooth normals computed from its triangles",
+                model.name
+            );
+            model.mesh.normals = smooth_normals(&model.mesh);
+        }
+        if model.mesh.texcoords.is_empty() {
+            warn!(
+                "Model {} has no uvs, its strokes all sample the albedo at (0, 0)",
+                model.name
+            );
+            model.mesh.texcoords = vec![0.0; model.mesh.positions.len() / 3 * 2];
+        }
+    }
+
+    Ok(models)
+}
+
+/// Loads the shapes of an OBJ file, split by material, with the diffuse texture of their material
+/// relative to the OBJ file's directory
+fn load_obj(
+    obj_file: &Path,
+    uv_channel: usize,
+) -> Result<Vec<(Model, Option<PathBuf>)>, LoadError> {
+    let (models, materials) = tobj::load_obj(
         obj_file,
         &LoadOptions {
             single_index: true,
@@ -32,48 +253,419 @@ pub fn gen_models(
             ignore_points: true,
             ignore_lines: true,
         },
-    )
-    .unwrap_or_else(|e| {
-        error!("Failed to load obj file '{}': {e}", obj_file.display());
-        exit(1);
+    )?;
+
+    // OBJ meshes carry a single uv set
+    if uv_channel != 0 {
+        warn!(
+            "uv channel {uv_channel} does not exist in '{}', using channel 0",
+            obj_file.display()
+        );
+    }
+
+    // The scene's albedo is used without the materials
+    let materials = materials.unwrap_or_else(|e| {
+        warn!(
+            "Failed to load the materials of '{}': {e}",
+            obj_file.display()
+        );
+        vec![]
     });
+    let obj_dir = obj_file.parent().unwrap_or(Path::new(""));
 
-    for model in &models {
-        info!(
-            "Loaded model {} with {} triangles",
-            model.name,
-            model.mesh.indices.len() / 3,
+    Ok(models
+        .into_iter()
+        .map(|model| {
+            let albedo = model
+                .mesh
+                .material_id
+                .and_then(|id| materials.get(id))
+                .filter(|material| !material.diffuse_texture.is_empty())
+                .map(|material| obj_dir.join(&material.diffuse_texture));
+            (model, albedo)
+        })
+        .collect())
+}
+
+/// Loads the shapes of every object, in order, and pairs each with its settings from `shapes`.
+/// `stroke_density` is the scene's, that objects with their own are relative to.
+/// Fails with the path of the OBJ file that couldn't be loaded.
+pub fn load_objects(
+    objects: &[ObjectDesc],
+    uv_channel: usize,
+    shapes: &HashMap<String, ShapeOverride>,
+    stroke_density: u32,
+) -> Result<Vec<SceneModel>, (PathBuf, ModelError)> {
+    let mut scene_models = vec![];
+    for (object, desc) in objects.iter().enumerate() {
+        let models = load_models(&desc.file, uv_channel).map_err(|e| (desc.file.clone(), e))?;
+        // Relative so the scene density (and its UI slider) scales every object
+        let density_scale = desc
+            .stroke_density
+            .map_or(1.0, |density| density as f32 / stroke_density.max(1) as f32);
+        scene_models.extend(
+            models
+                .into_iter()
+                .map(|(model, material_albedo)| SceneModel {
+                    shape: shapes.get(&model.name).cloned().unwrap_or_default(),
+                    model,
+                    object,
+                    transform: desc.transform(),
+                    density_scale,
+                    material_albedo,
+                }),
         );
     }
 
-    // FIXME: ugly hack
-    let mut points = vec![];
-    for model in &models {
-        points.extend(gen_point_list(&model, stroke_density));
+    for name in shapes.keys() {
+        if !scene_models.iter().any(|m| &m.model.name == name) {
+            warn!(
+                "Scene has settings for shape '{name}', which is not in any OBJ file (shapes: {})",
+                scene_models
+                    .iter()
+                    .map(|m| m.model.name.as_str())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+        }
     }
 
-    // Generate buffers and point lists for each model
+    Ok(scene_models)
+}
+
+/// Generates the buffers of each model. `points` holds the point list of each model, models
+/// without one get no points.
+pub fn gen_model_data(
+    models: Vec<SceneModel>,
+    points: Vec<Vec<Point>>,
+    display: &impl Facade,
+) -> Vec<ModelData> {
+    let mut points = points.into_iter();
     models
         .into_iter()
-        .enumerate()
-        .map(|(i, model)| {
-            let start = Instant::now();
-            // FIXME: ugly hack
-            let points = if i == 0 { points.clone() } else { vec![] };
-            info!(
-                "Generated {} points for model {} ({:?})",
-                points.len(),
-                model.name,
-                start.elapsed()
-            );
-            let model_buffers = gen_buffers(display, &model.mesh);
+        .map(|m| {
+            let points = points.next().unwrap_or_default();
+            info!("{} points for model {}", points.len(), m.model.name);
+            let model_buffers = gen_buffers(display, &m.model.mesh);
             let point_buffers = gen_point_buffers(display, &points);
             ModelData {
-                model,
+                model: m.model,
+                shape: m.shape,
+                object: m.object,
+                transform: m.transform,
+                density_scale: m.density_scale,
+                material_albedo: m.material_albedo,
                 model_buffers,
                 points,
                 point_buffers,
             }
         })
-        .collect::<Vec<_>>()
+        .collect()
+}
+
+/// Smallest and largest corner of the box around every shape, placed in the scene. `None`
+/// without any vertices.
+pub fn scene_bounds(models: &[SceneModel]) -> Option<(Point3<f32>, Point3<f32>)> {
+    models
+        .iter()
+        .flat_map(|m| {
+            m.model
+                .mesh
+                .positions
+                .chunks_exact(3)
+                .map(|p| m.transform.transform_point(Point3::new(p[0], p[1], p[2])))
+        })
+        .fold(None, |bounds, p| {
+            let (min, max) = bounds.unwrap_or((p, p));
+            Some((
+                Point3::new(min.x.min(p.x), min.y.min(p.y), min.z.min(p.z)),
+                Point3::new(max.x.max(p.x), max.y.max(p.y), max.z.max(p.z)),
+            ))
+        })
+}
+
+/// Approximate factor by which `transform` scales surface areas, so points generated in object
+/// space keep their density once transformed into the scene
+pub fn area_scale(transform: Matrix4<f32>) -> f32 {
+    let volume_scale = Matrix3::from_cols(
+        transform.x.truncate(),
+        transform.y.truncate(),
+        transform.z.truncate(),
+    )
+    .determinant()
+    .abs();
+    volume_scale.powf(2.0 / 3.0)
+}
+
+/// Prepares every shape for point generation, in the order of `models`
+pub fn gen_mesh_samplers(models: &[SceneModel]) -> Vec<MeshSampler> {
+    models.iter().map(|m| MeshSampler::new(&m.model)).collect()
+}
+
+/// Generates points at `stroke_density` on every shape and adds them to the list of the first
+/// model of its object with the same material, with their relative curvature from
+/// `gen_point_list` and their flat color from the shape's albedo
+fn add_object_points(
+    points: &mut [Vec<(Point, f32)>],
+    models: &[SceneModel],
+    samplers: &[MeshSampler],
+    stroke_density: f32,
+    sampling: Sampling,
+    stroke_maps: &StrokeMaps,
+    rng: &mut impl Rng,
+) {
+    // Each list is drawn with a single albedo, so shapes of other materials get their own
+    let mut firsts = HashMap::new();
+    for (i, (m, sampler)) in models.iter().zip(samplers).enumerate() {
+        let first = *firsts
+            .entry((m.object, m.material_albedo.as_deref()))
+            .or_insert(i);
+        let shape_points = gen_point_list(
+            &m.model,
+            sampler,
+            stroke_density
+                * m.density_scale
+                * m.shape.density_scale.unwrap_or(1.0)
+                * area_scale(m.transform),
+            sampling,
+            stroke_maps,
+            m.shape.brushes.as_deref(),
+            rng,
+        );
+        match &stroke_maps.albedo {
+            Some(colors) => points[first].extend(shape_points.into_iter().map(|(p, curvature)| {
+                let color = colors.sample(m.object, m.material_albedo.as_deref(), p.uv);
+                (p.with_color(color), curvature)
+            })),
+            None => points[first].extend(shape_points),
+        }
+    }
+}
+
+/// Changes the density of points from `gen_scene_points` from `from_density` to `to_density`
+/// while keeping the existing points: more are added, or a random share of them is removed.
+/// Only valid for `Sampling::Random` without `max_points`, where the result is distributed like
+/// newly generated points.
+pub fn change_scene_density(
+    points: &mut [Vec<Point>],
+    models: &[SceneModel],
+    samplers: &[MeshSampler],
+    from_density: f32,
+    to_density: f32,
+    stroke_maps: &StrokeMaps,
+) {
+    let start = Instant::now();
+    let mut rng = StdRng::from_entropy();
+    if to_density > from_density {
+        // Independent random points add up to random points at the summed density
+        let mut added = vec![vec![]; points.len()];
+        add_object_points(
+            &mut added,
+            models,
+            samplers,
+            to_density - from_density,
+            Sampling::Random,
+            stroke_maps,
+            &mut rng,
+        );
+        // A new jitter seed gives the added points their own jitter
+        let jitter_seed = rng.gen();
+        for (points, mut added) in points.iter_mut().zip(added) {
+            set_jitter(&mut added, jitter_seed);
+            points.extend(added.into_iter().map(|(p, _)| p));
+        }
+    } else {
+        let keep = to_density / from_density;
+        for points in points.iter_mut() {
+            points.retain(|_| rng.gen::<f32>() < keep);
+        }
+    }
+    info!(
+        "Changed the density from {from_density} to {to_density}, {} points ({:?})",
+        points.iter().map(Vec::len).sum::<usize>(),
+        start.elapsed()
+    );
+}
+
+/// Generates the points of every shape, as one list per model. The points of all the shapes of
+/// an object with the same material go into the list of the first of them, so they are sorted
+/// together.
+/// `samplers` are the shapes' samplers from `gen_mesh_samplers`. With a `seed` the same arguments
+/// always give the same points, otherwise they are different every time.
+pub fn gen_scene_points(
+    models: &[SceneModel],
+    samplers: &[MeshSampler],
+    stroke_density: f32,
+    sampling: Sampling,
+    stroke_maps: &StrokeMaps,
+    max_points: Option<usize>,
+    seed: Option<u64>,
+) -> Vec<Vec<Point>> {
+    let start = Instant::now();
+    let mut rng = seed.map_or_else(StdRng::from_entropy, StdRng::seed_from_u64);
+    let jitter_seed = seed.unwrap_or_else(|| StdRng::from_entropy().gen());
+    let mut points = vec![vec![]; models.len()];
+    add_object_points(
+        &mut points,
+        models,
+        samplers,
+        stroke_density,
+        sampling,
+        stroke_maps,
+        &mut rng,
+    );
+    // Before pruning, so the points kept at any max_points keep their jitter
+    for points in points.iter_mut() {
+        set_jitter(points, jitter_seed);
+    }
+
+    let total = points.iter().map(Vec::len).sum::<usize>();
+    if let Some(max_points) = max_points.filter(|&max_points| total > max_points) {
+        // Every object keeps its share of the points
+        for points in points.iter_mut().filter(|points| !points.is_empty()) {
+            let max_object_points = max_points * points.len() / total;
+            prune_points(
+                points,
+                max_object_points,
+                stroke_maps.mask.as_ref(),
+                &mut rng,
+            );
+        }
+        let kept = points.iter().map(Vec::len).sum::<usize>();
+        info!(
+            "Pruned {} of {total} points to fit max_points, an effective density of {}",
+            total - kept,
+            stroke_density * kept as f32 / total as f32
+        );
+    }
+    info!(
+        "Generated {} points ({:?})",
+        points.iter().map(Vec::len).sum::<usize>(),
+        start.elapsed()
+    );
+    points
+        .into_iter()
+        .map(|points| points.into_iter().map(|(p, _)| p).collect())
+        .collect()
+}
+
+/// Sets the jitter of `points` from `jitter_seed` and their index in the list as generated
+fn set_jitter(points: &mut [(Point, f32)], jitter_seed: u64) {
+    for (i, (p, _)) in points.iter_mut().enumerate() {
+        *p = p.with_jitter(jitter_seed, i as u64);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tobj::Mesh;
+
+    use super::*;
+
+    /// A unit square in the xy plane facing +z as the only shape of the scene
+    fn square_scene() -> Vec<SceneModel> {
+        let mesh = Mesh {
+            positions: vec![0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 1.0, 1.0, 0.0, 0.0, 1.0, 0.0],
+            normals: vec![0.0, 0.0, 1.0, 0.0, 0.0, 1.0, 0.0, 0.0, 1.0, 0.0, 0.0, 1.0],
+            texcoords: vec![0.0, 0.0, 1.0, 0.0, 1.0, 1.0, 0.0, 1.0],
+            indices: vec![0, 1, 2, 0, 2, 3],
+            ..Mesh::default()
+        };
+        vec![SceneModel {
+            model: Model::new(mesh, "square".to_string()),
+            shape: ShapeOverride::default(),
+            object: 0,
+            transform: Matrix4::identity(),
+            density_scale: 1.0,
+            material_albedo: None,
+        }]
+    }
+
+    #[test]
+    fn same_seed_gives_identical_points() {
+        let models = square_scene();
+        let samplers = gen_mesh_samplers(&models);
+        let stroke_maps = StrokeMaps {
+            brush_names: vec!["1.png".to_string(), "2.png".to_string()],
+            ..StrokeMaps::default()
+        };
+        // Pruning to max_points draws from the seeded generator too
+        let gen = |seed| {
+            let points = gen_scene_points(
+                &models,
+                &samplers,
+                2000.0,
+                Sampling::Random,
+                &stroke_maps,
+                Some(1000),
+                seed,
+            );
+            bincode::serialize(&points).unwrap()
+        };
+
+        assert_eq!(gen(Some(7)), gen(Some(7)));
+        assert_ne!(gen(Some(7)), gen(Some(8)));
+        assert_ne!(gen(None), gen(None));
+    }
+
+    #[test]
+    fn max_points_keeps_the_jitter_of_kept_points() {
+        let models = square_scene();
+        let samplers = gen_mesh_samplers(&models);
+        let stroke_maps = StrokeMaps {
+            brush_names: vec!["1.png".to_string()],
+            ..StrokeMaps::default()
+        };
+        let gen = |max_points| {
+            gen_scene_points(
+                &models,
+                &samplers,
+                2000.0,
+                Sampling::Random,
+                &stroke_maps,
+                Some(max_points),
+                Some(7),
+            )
+            .remove(0)
+        };
+
+        let more = gen(1000);
+        let fewer = gen(500);
+        assert_eq!(fewer.len(), 500);
+        // The same seed prunes the same points first, so every kept point is unchanged
+        for p in &fewer {
+            assert!(more.contains(p), "{p:?}");
+        }
+    }
+
+    #[test]
+    fn shapes_of_other_materials_keep_their_points() {
+        let square = square_scene().remove(0);
+        let with_material = |material: &str| SceneModel {
+            material_albedo: Some(PathBuf::from(material)),
+            ..square.clone()
+        };
+        let models = [
+            with_material("a.png"),
+            with_material("b.png"),
+            with_material("a.png"),
+        ];
+        let stroke_maps = StrokeMaps {
+            brush_names: vec!["1.png".to_string()],
+            ..StrokeMaps::default()
+        };
+        let points = gen_scene_points(
+            &models,
+            &gen_mesh_samplers(&models),
+            100.0,
+            Sampling::Random,
+            &stroke_maps,
+            None,
+            Some(0),
+        );
+
+        let counts = points.iter().map(Vec::len).collect::<Vec<_>>();
+        assert!(counts[0] > counts[1] && counts[1] > 0, "{counts:?}");
+        assert_eq!(counts[2], 0);
+    }
 }
@@ -0,0 +1,115 @@
+use glium::{
+    backend::Facade,
+    framebuffer::SimpleFrameBuffer,
+    texture::{MipmapsOption, Texture2d, TextureCreationError, UncompressedFloatFormat},
+    uniform, Blend, BlendingFunction, DrawParameters, IndexBuffer, LinearBlendingFactor, Program,
+    Surface, VertexBuffer,
+};
+
+use crate::PostProcessVert;
+
+/// Pass of weighted blended transparency the strokes are drawn for, matches `oit_pass` in the
+/// point shaders
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum OitPass {
+    /// Weighted premultiplied color and alpha, summed
+    Accum = 1,
+    /// Product of one minus the alpha of every stroke
+    Revealage = 2,
+}
+
+impl OitPass {
+    /// Blending the strokes are drawn with in this pass
+    pub fn blend(self) -> Blend {
+        let function = match self {
+            OitPass::Accum => BlendingFunction::Addition {
+                source: LinearBlendingFactor::One,
+                destination: LinearBlendingFactor::One,
+            },
+            OitPass::Revealage => BlendingFunction::Addition {
+                source: LinearBlendingFactor::Zero,
+                destination: LinearBlendingFactor::OneMinusSourceColor,
+            },
+        };
+        Blend {
+            color: function,
+            alpha: function,
+            constant_value: (0.0, 0.0, 0.0, 0.0),
+        }
+    }
+}
+
+/// Offscreen buffers the strokes are accumulated in, in any order
+pub struct OitTargets {
+    accum: Texture2d,
+    revealage: Texture2d,
+}
+
+impl OitTargets {
+    pub fn new(
+        display: &impl Facade,
+        width: u32,
+        height: u32,
+    ) -> Result<Self, TextureCreationError> {
+        let color = |format| {
+            Texture2d::empty_with_format(display, format, MipmapsOption::NoMipmap, width, height)
+        };
+        Ok(Self {
+            accum: color(UncompressedFloatFormat::F16F16F16F16)?,
+            revealage: color(UncompressedFloatFormat::F16)?,
+        })
+    }
+
+    pub fn dimensions(&self) -> (u32, u32) {
+        (self.accum.width(), self.accum.height())
+    }
+}
+
+/// Draws the strokes with weighted blended order independent transparency and composites them
+/// over `target`.
+///
+/// `draw_pass` is called once per `OitPass` with the framebuffer to draw into; the strokes must
+/// be drawn with `OitPass::blend`.
+pub fn draw_weighted_blended(
+    display: &impl Facade,
+    targets: &OitTargets,
+    target: &mut impl Surface,
+    quad: &(VertexBuffer<PostProcessVert>, IndexBuffer<u8>),
+    resolve_program: &Program,
+    mut draw_pass: impl FnMut(&mut SimpleFrameBuffer, OitPass),
+) {
+    {
+        let mut accum = SimpleFrameBuffer::new(display, &targets.accum).unwrap();
+        accum.clear_color(0.0, 0.0, 0.0, 0.0);
+        draw_pass(&mut accum, OitPass::Accum);
+    }
+    {
+        // Fully revealed until a stroke covers it
+        let mut revealage = SimpleFrameBuffer::new(display, &targets.revealage).unwrap();
+        revealage.clear_color(1.0, 1.0, 1.0, 1.0);
+        draw_pass(&mut revealage, OitPass::Revealage);
+    }
+
+    // The weighted average color, covering as much as all the strokes together
+    target
+        .draw(
+            &quad.0,
+            &quad.1,
+            resolve_program,
+            &uniform! {
+                accum_texture: &targets.accum,
+                revealage_texture: &targets.revealage,
+            },
+            &DrawParameters {
+                blend: Blend {
+                    alpha: BlendingFunction::Addition {
+                        source: LinearBlendingFactor::One,
+                        destination: LinearBlendingFactor::OneMinusSourceAlpha,
+                    },
+                    ..Blend::alpha_blending()
+                },
+                ..Default::default()
+            },
+        )
+        .unwrap();
+}
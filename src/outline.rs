@@ -0,0 +1,54 @@
+use glium::{
+    backend::Facade,
+    framebuffer::SimpleFrameBuffer,
+    texture::{DepthFormat, DepthTexture2d, MipmapsOption, Texture2d, UncompressedFloatFormat},
+    Surface,
+};
+
+/// Offscreen buffers the mesh is rendered into for the outline edge detection in `post.frag`
+pub struct OutlineTargets {
+    /// View space normal in `rgb` and view depth in `a`, zero where there is no mesh
+    geometry: Texture2d,
+    depth: DepthTexture2d,
+}
+
+impl OutlineTargets {
+    /// Targets for a `width` by `height` frame
+    pub fn new(display: &impl Facade, width: u32, height: u32) -> Self {
+        Self {
+            geometry: Texture2d::empty_with_format(
+                display,
+                UncompressedFloatFormat::F32F32F32F32,
+                MipmapsOption::NoMipmap,
+                width,
+                height,
+            )
+            .unwrap(),
+            depth: DepthTexture2d::empty_with_format(
+                display,
+                DepthFormat::F32,
+                MipmapsOption::NoMipmap,
+                width,
+                height,
+            )
+            .unwrap(),
+        }
+    }
+
+    /// The normals and depth drawn by the last `draw_outline_geometry`
+    pub fn texture(&self) -> &Texture2d {
+        &self.geometry
+    }
+}
+
+/// Clears the targets and lets `draw_geometry` draw the mesh into them, depth tested
+pub fn draw_outline_geometry(
+    display: &impl Facade,
+    targets: &OutlineTargets,
+    draw_geometry: impl FnOnce(&mut SimpleFrameBuffer),
+) {
+    let mut target =
+        SimpleFrameBuffer::with_depth_buffer(display, &targets.geometry, &targets.depth).unwrap();
+    target.clear_color_and_depth((0.0, 0.0, 0.0, 0.0), 1.0);
+    draw_geometry(&mut target);
+}
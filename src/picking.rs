@@ -0,0 +1,139 @@
+use cgmath::{prelude::*, Matrix4, Point3, Vector2, Vector3};
+
+use crate::{camera::Camera, objects::ModelData};
+
+/// The surface point under the cursor
+#[derive(Debug, Clone)]
+pub struct Pick {
+    pub model_index: usize,
+    pub model_name: String,
+    /// World space position of the hit
+    pub position: Point3<f32>,
+    /// Interpolated texture coordinates of the hit (if the mesh has any)
+    pub uv: Option<[f32; 2]>,
+}
+
+/// Casts a ray through the pixel at `cursor` (in physical pixels) and returns the closest surface
+/// hit across all models and instances.
+pub fn pick(
+    models: &[ModelData],
+    instances: &[Matrix4<f32>],
+    model_matrix: Matrix4<f32>,
+    camera: &Camera,
+    cursor: (f32, f32),
+    window_size: (u32, u32),
+) -> Option<Pick> {
+    let (world_origin, world_direction) = camera.ray_from_pixel(
+        cursor.0.floor(),
+        cursor.1.floor(),
+        window_size.0,
+        window_size.1,
+    );
+
+    let mut closest: Option<(f32, Pick)> = None;
+
+    for &instance in instances {
+        for (model_index, model) in models.iter().enumerate() {
+            let world = model_matrix * instance * model.transform;
+            let Some(inverse) = world.invert() else {
+                continue;
+            };
+
+            // Ray in model space. `t` stays the world space distance, so it is comparable across
+            // instances and models
+            let origin = (inverse * world_origin.to_homogeneous()).truncate();
+            let direction = (inverse * world_direction.extend(0.0)).truncate();
+
+            let mesh = &model.model.mesh;
+            let position = |i: u32| {
+                let i = i as usize * 3;
+                Vector3::new(
+                    mesh.positions[i],
+                    mesh.positions[i + 1],
+                    mesh.positions[i + 2],
+                )
+            };
+            let tex_coord = |i: u32| {
+                let i = i as usize * 2;
+                Vector2::new(mesh.texcoords[i], mesh.texcoords[i + 1])
+            };
+
+            for triangle in mesh.indices.chunks_exact(3) {
+                let a = position(triangle[0]);
+                let b = position(triangle[1]);
+                let c = position(triangle[2]);
+
+                let Some((t, u, v)) = intersect_triangle(origin, direction, a, b, c) else {
+                    continue;
+                };
+
+                if closest
+                    .as_ref()
+                    .is_some_and(|(closest_t, _)| *closest_t <= t)
+                {
+                    continue;
+                }
+
+                let uv = if mesh.texcoords.is_empty() {
+                    None
+                } else {
+                    let uv = tex_coord(triangle[0]) * (1.0 - u - v)
+                        + tex_coord(triangle[1]) * u
+                        + tex_coord(triangle[2]) * v;
+                    Some(uv.into())
+                };
+
+                let position = world * (origin + direction * t).extend(1.0);
+                closest = Some((
+                    t,
+                    Pick {
+                        model_index,
+                        model_name: model.model.name.clone(),
+                        position: Point3::from_vec(position.truncate()),
+                        uv,
+                    },
+                ));
+            }
+        }
+    }
+
+    closest.map(|(_, pick)| pick)
+}
+
+/// Möller–Trumbore ray/triangle intersection. Returns `(t, u, v)` where the hit is at
+/// `origin + direction * t` and `a + (b - a) * u + (c - a) * v`.
+fn intersect_triangle(
+    origin: Vector3<f32>,
+    direction: Vector3<f32>,
+    a: Vector3<f32>,
+    b: Vector3<f32>,
+    c: Vector3<f32>,
+) -> Option<(f32, f32, f32)> {
+    let ab = b - a;
+    let ac = c - a;
+    let p = direction.cross(ac);
+    let det = ab.dot(p);
+    if det.abs() < f32::EPSILON {
+        return None;
+    }
+    let inv_det = 1.0 / det;
+
+    let ao = origin - a;
+    let u = ao.dot(p) * inv_det;
+    if !(0.0..=1.0).contains(&u) {
+        return None;
+    }
+
+    let q = ao.cross(ab);
+    let v = direction.dot(q) * inv_det;
+    if v < 0.0 || u + v > 1.0 {
+        return None;
+    }
+
+    let t = ac.dot(q) * inv_det;
+    if t < 0.0 {
+        return None;
+    }
+
+    Some((t, u, v))
+}
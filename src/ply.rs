@@ -0,0 +1,41 @@
+//! Strokes exported as a binary PLY point cloud, for inspecting their placement in other tools.
+//!
+//! Every stroke is a vertex with its position `x, y, z`, normal `nx, ny, nz` and uv `u, v` as
+//! floats, and its brush as the custom int property `brush_index`.
+
+use std::{
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::Path,
+};
+
+use crate::point_gen::Point;
+
+pub fn write_ply(path: &Path, points: &[Point]) -> io::Result<()> {
+    let mut file = BufWriter::new(File::create(path)?);
+    write!(
+        file,
+        "ply\n\
+         format binary_little_endian 1.0\n\
+         comment paint_render strokes\n\
+         element vertex {}\n\
+         property float x\n\
+         property float y\n\
+         property float z\n\
+         property float nx\n\
+         property float ny\n\
+         property float nz\n\
+         property float u\n\
+         property float v\n\
+         property int brush_index\n\
+         end_header\n",
+        points.len()
+    )?;
+    for point in points {
+        for float in point.position.iter().chain(&point.normal).chain(&point.uv) {
+            file.write_all(&float.to_le_bytes())?;
+        }
+        file.write_all(&point.brush_index.to_le_bytes())?;
+    }
+    file.flush()
+}
@@ -1,9 +1,20 @@
-use cgmath::{prelude::*, Vector2, Vector3};
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::{Path, PathBuf},
+    time::Instant,
+};
+
+use cgmath::{prelude::*, Matrix3, Matrix4, Point3, Vector2, Vector3};
 use glium::implement_vertex;
-use log::{info, warn};
-use tobj::Model;
+use image::{GrayImage, RgbaImage};
+use log::{debug, info, warn};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use tobj::{Mesh, Model};
+
+use crate::flow::FlowField;
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct Point {
     pub position: [f32; 3],
     pub normal: [f32; 3],
@@ -11,106 +22,1048 @@ pub struct Point {
     pub bitangent: [f32; 3],
     pub uv: [f32; 2],
     pub brush_index: i32,
+    /// Random length variation in `[0, 1)`, scaled by `length_jitter` when drawn
+    pub stroke_length_rand: f32,
+    /// Random rotation and size variation in `[0, 1)`, scaled by the jitter parameters when drawn
+    pub jitter: [f32; 2],
+    /// Stroke direction in uv space from the albedo, zero to follow `tangent`
+    pub flow: [f32; 2],
+    /// Linear albedo at `uv` the stroke is painted with when drawn with a flat color
+    pub color: [f32; 3],
+    /// Random rank in `[0, 1)`, adaptive density draws the points under a fraction, see `lod`
+    pub lod: f32,
 }
-implement_vertex!(Point, position, normal, tangent, bitangent, uv, brush_index);
+implement_vertex!(
+    Point,
+    position,
+    normal,
+    tangent,
+    bitangent,
+    uv,
+    brush_index,
+    stroke_length_rand,
+    jitter,
+    flow,
+    color
+);
 
-/// Generates points on the surface of a model with a density of `density` points per unit squared
-pub fn gen_point_list(model: &Model, density: f32) -> Vec<Point> {
-    let num_brushes = env!("PR_NUM_BRUSHES").parse::<u32>().unwrap();
+impl Point {
+    pub fn new(
+        position: impl Into<[f32; 3]>,
+        normal: impl Into<[f32; 3]>,
+        tangent: impl Into<[f32; 3]>,
+        bitangent: impl Into<[f32; 3]>,
+        uv: impl Into<[f32; 2]>,
+        brush_index: i32,
+    ) -> Self {
+        Self {
+            position: position.into(),
+            normal: normal.into(),
+            tangent: tangent.into(),
+            bitangent: bitangent.into(),
+            uv: uv.into(),
+            brush_index,
+            stroke_length_rand: 0.5,
+            jitter: [0.5, 0.5],
+            flow: [0.0, 0.0],
+            color: [0.0, 0.0, 0.0],
+            lod: 0.0,
+        }
+    }
 
-    let mesh = &model.mesh;
+    /// Sets the random length, rotation and size variation and level of detail rank of the point
+    /// at `index` in its list from a hash of `seed` and `index` alone, so it doesn't depend on the
+    /// order points were sampled in or on the renderer
+    pub fn with_jitter(self, seed: u64, index: u64) -> Self {
+        let random = |stream| point_random(seed, index, stream);
+        Self {
+            stroke_length_rand: random(0),
+            jitter: [random(1), random(2)],
+            lod: random(3),
+            ..self
+        }
+    }
 
-    let mut points = vec![];
+    pub fn position(&self) -> Point3<f32> {
+        self.position.into()
+    }
+
+    pub fn normal(&self) -> Vector3<f32> {
+        self.normal.into()
+    }
 
-    let mut total_area = 0.0;
+    pub fn tangent(&self) -> Vector3<f32> {
+        self.tangent.into()
+    }
 
-    for triangle in mesh.indices.chunks(3) {
-        if triangle.len() != 3 {
-            warn!(
-                "Found a polygon with {} vertices ({triangle:?})",
-                triangle.len()
-            );
+    pub fn bitangent(&self) -> Vector3<f32> {
+        self.bitangent.into()
+    }
+
+    pub fn uv(&self) -> Vector2<f32> {
+        self.uv.into()
+    }
+
+    pub fn with_flow(self, flow: impl Into<[f32; 2]>) -> Self {
+        Self {
+            flow: flow.into(),
+            ..self
+        }
+    }
+
+    pub fn with_color(self, color: impl Into<[f32; 3]>) -> Self {
+        Self {
+            color: color.into(),
+            ..self
+        }
+    }
+
+    /// The point moved by `transform`, with its normal kept perpendicular to the surface
+    pub fn transformed(self, transform: Matrix4<f32>) -> Self {
+        let linear = Matrix3::from_cols(
+            transform.x.truncate(),
+            transform.y.truncate(),
+            transform.z.truncate(),
+        );
+        let normal_matrix = linear.invert().unwrap_or(linear).transpose();
+        Self {
+            position: transform.transform_point(self.position()).into(),
+            normal: (normal_matrix * self.normal()).normalize().into(),
+            tangent: (linear * self.tangent()).into(),
+            bitangent: (linear * self.bitangent()).into(),
+            ..self
+        }
+    }
+}
+
+/// splitmix64, a fast hash whose every output bit depends on every input bit
+fn splitmix64(x: u64) -> u64 {
+    let x = x.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    let x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    let x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    x ^ (x >> 31)
+}
+
+/// Random value in `[0, 1)` of the point at `index`, always the same for the same `seed`, `index`
+/// and `stream`. Each random property of a point uses its own `stream`.
+pub fn point_random(seed: u64, index: u64, stream: u64) -> f32 {
+    let hash = splitmix64(splitmix64(splitmix64(seed) ^ index) ^ stream);
+    // The top 24 bits fill the mantissa of an f32 exactly
+    (hash >> 40) as f32 / (1 << 24) as f32
+}
+
+/// A brush referenced by its index in the brush atlas or by its file name (with or without the
+/// extension)
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum BrushRef {
+    Index(u32),
+    Name(String),
+}
+
+impl BrushRef {
+    /// Index of the brush in an atlas of the brushes `brush_names`, `None` if there is no such
+    /// brush
+    pub fn index(&self, brush_names: &[String]) -> Option<u32> {
+        match self {
+            BrushRef::Index(index) => Some(*index).filter(|&i| (i as usize) < brush_names.len()),
+            BrushRef::Name(name) => brush_names
+                .iter()
+                .position(|brush| {
+                    brush == name || Path::new(brush).file_stem().is_some_and(|s| s == &**name)
+                })
+                .map(|i| i as u32),
+        }
+    }
+}
+
+/// Samples a stroke mask at `uv` with nearest filtering, matching the orientation of textures
+/// uploaded with `from_raw_rgba_reversed`
+fn sample_mask(mask: &GrayImage, uv: Vector2<f32>) -> f32 {
+    let x = (uv.x.rem_euclid(1.0) * mask.width() as f32) as u32;
+    let y = ((1.0 - uv.y.rem_euclid(1.0)) * mask.height() as f32) as u32;
+    let x = x.min(mask.width() - 1);
+    let y = y.min(mask.height() - 1);
+    mask.get_pixel(x, y).0[0] as f32 / 255.0
+}
+
+/// Samples an sRGB texture at `uv` like `sample_mask`, as a linear color like the shaders see
+/// the albedo texture
+fn sample_color(image: &RgbaImage, uv: Vector2<f32>) -> [f32; 3] {
+    let x = (uv.x.rem_euclid(1.0) * image.width() as f32) as u32;
+    let y = ((1.0 - uv.y.rem_euclid(1.0)) * image.height() as f32) as u32;
+    let x = x.min(image.width() - 1);
+    let y = y.min(image.height() - 1);
+    let [r, g, b, _] = image.get_pixel(x, y).0;
+    [r, g, b].map(|c| {
+        let c = c as f32 / 255.0;
+        if c <= 0.04045 {
+            c / 12.92
+        } else {
+            ((c + 0.055) / 1.055).powf(2.4)
+        }
+    })
+}
+
+/// Tangent and bitangent of a triangle from its positions and uvs, or `None` if the uvs are
+/// degenerate
+fn triangle_tangent(
+    [a, b, c]: [Vector3<f32>; 3],
+    [auv, buv, cuv]: [Vector2<f32>; 3],
+) -> Option<(Vector3<f32>, Vector3<f32>)> {
+    let ab = b - a;
+    let ac = c - a;
+
+    let duv_ab = buv - auv;
+    let duv_ac = cuv - auv;
+
+    let determinant = duv_ab.x * duv_ac.y - duv_ab.y * duv_ac.x;
+    if determinant.abs() < 1.0e-12 || !determinant.is_finite() {
+        return None;
+    }
+    let r = 1.0 / determinant;
+    let tangent = (ab * duv_ac.y - ac * duv_ab.y) * r;
+    let bitangent = (ac * duv_ab.x - ab * duv_ac.x) * r;
+    Some((tangent, bitangent))
+}
+
+/// Arbitrary unit tangent perpendicular to the unit normal `n`, from world up (or x for normals
+/// close to up)
+fn fallback_tangent(n: Vector3<f32>) -> Vector3<f32> {
+    let up = if n.y.abs() < 0.99 {
+        Vector3::unit_y()
+    } else {
+        Vector3::unit_x()
+    };
+    (up - n * n.dot(up)).normalize()
+}
+
+/// Triangles of `mesh`, with polygons of more than 3 vertices fan-triangulated from their first
+/// vertex, and the number of polygons that were split. The face sizes are only known from
+/// `Mesh::face_arities`, without them the indices are taken as triangles.
+fn mesh_triangles(mesh: &Mesh) -> (Vec<[u32; 3]>, usize) {
+    if mesh.face_arities.is_empty() {
+        let triangles = mesh
+            .indices
+            .chunks_exact(3)
+            .map(|triangle| [triangle[0], triangle[1], triangle[2]])
+            .collect();
+        return (triangles, 0);
+    }
+
+    let mut triangles = vec![];
+    let mut num_split = 0;
+    let mut start = 0;
+    for &arity in &mesh.face_arities {
+        let face = &mesh.indices[start..start + arity as usize];
+        start += arity as usize;
+        if face.len() > 3 {
+            num_split += 1;
+        }
+        // Points and lines have no area to sample
+        for i in 2..face.len() {
+            triangles.push([face[0], face[i - 1], face[i]]);
+        }
+    }
+    (triangles, num_split)
+}
+
+/// Smooth per-vertex tangents and bitangents.
+///
+/// The tangents of every triangle sharing a vertex are accumulated, then orthonormalized against
+/// the vertex normal (keeping the handedness of the accumulated bitangent), so stroke directions
+/// are continuous across triangle edges. Vertices only used by triangles with degenerate uvs get
+/// an arbitrary tangent frame.
+fn vertex_tangents(mesh: &Mesh, triangles: &[[u32; 3]]) -> Vec<(Vector3<f32>, Vector3<f32>)> {
+    let position = |i: u32| {
+        let i = i as usize * 3;
+        Vector3::new(
+            mesh.positions[i],
+            mesh.positions[i + 1],
+            mesh.positions[i + 2],
+        )
+    };
+    let normal = |i: usize| {
+        Vector3::new(
+            mesh.normals[i * 3],
+            mesh.normals[i * 3 + 1],
+            mesh.normals[i * 3 + 2],
+        )
+    };
+    let tex_coord = |i: u32| {
+        let i = i as usize * 2;
+        Vector2::new(mesh.texcoords[i], mesh.texcoords[i + 1])
+    };
+
+    let mut tangents = vec![(Vector3::zero(), Vector3::zero()); mesh.positions.len() / 3];
+
+    for triangle in triangles {
+        let positions = [
+            position(triangle[0]),
+            position(triangle[1]),
+            position(triangle[2]),
+        ];
+        let uvs = [
+            tex_coord(triangle[0]),
+            tex_coord(triangle[1]),
+            tex_coord(triangle[2]),
+        ];
+        let Some((tangent, bitangent)) = triangle_tangent(positions, uvs) else {
             continue;
+        };
+        for &i in triangle {
+            let (t, b) = &mut tangents[i as usize];
+            *t += tangent;
+            *b += bitangent;
         }
-        let a = &mesh.positions[(triangle[0] * 3) as usize..(triangle[0] * 3 + 3) as usize];
-        let b = &mesh.positions[(triangle[1] * 3) as usize..(triangle[1] * 3 + 3) as usize];
-        let c = &mesh.positions[(triangle[2] * 3) as usize..(triangle[2] * 3 + 3) as usize];
-        let a = Vector3::new(a[0], a[1], a[2]);
-        let b = Vector3::new(b[0], b[1], b[2]);
-        let c = Vector3::new(c[0], c[1], c[2]);
-
-        let an = &mesh.normals[(triangle[0] * 3) as usize..(triangle[0] * 3 + 3) as usize];
-        let bn = &mesh.normals[(triangle[1] * 3) as usize..(triangle[1] * 3 + 3) as usize];
-        let cn = &mesh.normals[(triangle[2] * 3) as usize..(triangle[2] * 3 + 3) as usize];
-        let an = Vector3::new(an[0], an[1], an[2]);
-        let bn = Vector3::new(bn[0], bn[1], bn[2]);
-        let cn = Vector3::new(cn[0], cn[1], cn[2]);
-
-        let auv = &mesh.texcoords[(triangle[0] * 2) as usize..(triangle[0] * 2 + 2) as usize];
-        let buv = &mesh.texcoords[(triangle[1] * 2) as usize..(triangle[1] * 2 + 2) as usize];
-        let cuv = &mesh.texcoords[(triangle[2] * 2) as usize..(triangle[2] * 2 + 2) as usize];
-        let auv = Vector2::new(auv[0], auv[1]);
-        let buv = Vector2::new(buv[0], buv[1]);
-        let cuv = Vector2::new(cuv[0], cuv[1]);
-
-        let ab = b - a;
-        let ac = c - a;
-
-        let duv_ab = buv - auv;
-        let duv_ac = cuv - auv;
-
-        let r = 1.0 / (duv_ab.x * duv_ac.y - duv_ab.y * duv_ac.x);
-        let tangent = (ab * duv_ac.y - ac * duv_ab.y) * r;
-        let bitangent = (ac * duv_ab.x - ab * duv_ac.x) * r;
-
-        let area = ab.cross(ac).magnitude() / 2.0;
-        total_area += area;
-        let num_points_f32 = area * density;
-        let mut num_points = num_points_f32.floor() as usize;
-        let num_points_remainder = num_points_f32.fract();
+    }
 
-        if rand::random::<f32>() < num_points_remainder {
-            num_points += 1;
+    for (i, (t, b)) in tangents.iter_mut().enumerate() {
+        let n = normal(i).normalize();
+        let tangent = *t - n * n.dot(*t);
+        let tangent = if tangent.magnitude2() > 1.0e-12 {
+            tangent.normalize()
+        } else {
+            fallback_tangent(n)
+        };
+        let handedness = if n.cross(tangent).dot(*b) < 0.0 {
+            -1.0
+        } else {
+            1.0
+        };
+        *b = n.cross(tangent) * handedness;
+        *t = tangent;
+    }
+
+    tangents
+}
+
+/// Version of the stroke generation, bumped whenever the same scene gives other strokes so strokes
+/// cached by an older build aren't loaded
+pub const GENERATOR_VERSION: u32 = 2;
+
+/// Minimum distance between Poisson disk samples relative to the mean spacing `1 / sqrt(density)`,
+/// low enough that dart throwing reaches the density long before the surface is saturated
+const POISSON_RADIUS: f32 = 0.6;
+
+/// Attempts per point after which Poisson disk sampling gives up on reaching the density
+const POISSON_MAX_ATTEMPTS: usize = 30;
+
+/// How points are placed on the surface, set with `sampling` in the scene
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Sampling {
+    /// Independent uniformly distributed points, which clump and leave gaps
+    #[default]
+    Random,
+    /// Points no closer than a minimum distance, evenly spread but slower to generate
+    Poisson,
+}
+
+/// Highest triangle curvature relative to the mean, so a few sharp triangles don't take the whole
+/// stroke budget
+const MAX_RELATIVE_CURVATURE: f32 = 4.0;
+
+/// Vertex data of one triangle, in vertex order
+struct SampleTriangle {
+    positions: [Vector3<f32>; 3],
+    normals: [Vector3<f32>; 3],
+    uvs: [Vector2<f32>; 3],
+    tangents: [(Vector3<f32>, Vector3<f32>); 3],
+    area: f32,
+    /// Curvature relative to the area weighted mean of the mesh, which is 1
+    curvature: f32,
+}
+
+/// Curvature of a triangle estimated from how far its vertex normals diverge: the largest angle
+/// between two of them over the mean edge length
+fn triangle_curvature(positions: [Vector3<f32>; 3], normals: [Vector3<f32>; 3]) -> f32 {
+    let angle = |a: Vector3<f32>, b: Vector3<f32>| {
+        if a.is_zero() || b.is_zero() {
+            return 0.0;
         }
-        for _ in 0..num_points {
-            let mut r1 = rand::random();
-            let mut r2 = rand::random();
-            if r1 + r2 >= 1.0 {
-                r1 = 1.0 - r1;
-                r2 = 1.0 - r2;
+        a.normalize().dot(b.normalize()).clamp(-1.0, 1.0).acos()
+    };
+    let max_angle = angle(normals[0], normals[1])
+        .max(angle(normals[1], normals[2]))
+        .max(angle(normals[2], normals[0]));
+    let mean_edge = ((positions[1] - positions[0]).magnitude()
+        + (positions[2] - positions[1]).magnitude()
+        + (positions[0] - positions[2]).magnitude())
+        / 3.0;
+    if mean_edge > 0.0 {
+        max_angle / mean_edge
+    } else {
+        0.0
+    }
+}
+
+/// Per-triangle data of a mesh needed to generate points on it.
+///
+/// Computing it (mostly the smooth tangents) is the costly part of point generation, so it is
+/// built once per mesh and reused when the points are regenerated with a new density.
+pub struct MeshSampler {
+    triangles: Vec<SampleTriangle>,
+    total_area: f32,
+    /// Largest relative curvature of the triangles
+    max_curvature: f32,
+}
+
+impl MeshSampler {
+    pub fn new(model: &Model) -> Self {
+        let start = Instant::now();
+        let mesh = &model.mesh;
+        let (mesh_triangles, num_split) = mesh_triangles(mesh);
+        if num_split > 0 {
+            debug!(
+                "{}: fan-triangulated {num_split} polygons with more than 3 vertices",
+                model.name
+            );
+        }
+        let vertex_tangents = vertex_tangents(mesh, &mesh_triangles);
+
+        let position = |i: u32| {
+            let i = i as usize * 3;
+            Vector3::new(
+                mesh.positions[i],
+                mesh.positions[i + 1],
+                mesh.positions[i + 2],
+            )
+        };
+        let normal = |i: u32| {
+            let i = i as usize * 3;
+            Vector3::new(mesh.normals[i], mesh.normals[i + 1], mesh.normals[i + 2])
+        };
+        let tex_coord = |i: u32| {
+            let i = i as usize * 2;
+            Vector2::new(mesh.texcoords[i], mesh.texcoords[i + 1])
+        };
+
+        let mut triangles = vec![];
+        for vertices in mesh_triangles {
+            let positions = vertices.map(position);
+            let area = (positions[1] - positions[0])
+                .cross(positions[2] - positions[0])
+                .magnitude()
+                / 2.0;
+            let normals = vertices.map(normal);
+            triangles.push(SampleTriangle {
+                positions,
+                normals,
+                uvs: vertices.map(tex_coord),
+                tangents: vertices.map(|i| vertex_tangents[i as usize]),
+                area,
+                curvature: triangle_curvature(positions, normals),
+            });
+        }
+
+        let total_area: f32 = triangles.iter().map(|triangle| triangle.area).sum();
+
+        // Normalize the curvature so that weighting the density by it keeps the stroke count,
+        // capping it first and normalizing again as the cap lowers the mean
+        let mean_curvature = |triangles: &[SampleTriangle]| {
+            triangles
+                .iter()
+                .map(|triangle| triangle.area * triangle.curvature)
+                .sum::<f32>()
+                / total_area
+        };
+        let mean = mean_curvature(&triangles);
+        if mean > 0.0 {
+            for triangle in &mut triangles {
+                triangle.curvature = (triangle.curvature / mean).min(MAX_RELATIVE_CURVATURE);
+            }
+            let mean = mean_curvature(&triangles);
+            for triangle in &mut triangles {
+                triangle.curvature /= mean;
+            }
+        } else {
+            // Flat or flat shaded, every triangle is as curved as the mean
+            for triangle in &mut triangles {
+                triangle.curvature = 1.0;
             }
+        }
+        let max_curvature = triangles
+            .iter()
+            .map(|triangle| triangle.curvature)
+            .fold(1.0, f32::max);
+        info!(
+            "Prepared {} triangles of {} for sampling ({:?})",
+            triangles.len(),
+            model.name,
+            start.elapsed()
+        );
+
+        Self {
+            triangles,
+            total_area,
+            max_curvature,
+        }
+    }
+}
 
-            let p = a + ab * r1 + ac * r2;
+/// Grayscale textures sampled by uv that control where strokes are placed
+#[derive(Debug, Clone, Default)]
+pub struct StrokeMaps {
+    /// Points are kept with a probability equal to the mask value
+    pub mask: Option<GrayImage>,
+    /// Bright regions get more points and dark regions fewer, by a strength from 0 to 1
+    pub importance: Option<(GrayImage, f32)>,
+    /// Points are oriented along the features of the albedo
+    pub flow: Option<FlowField>,
+    /// Albedos the points take their flat color from
+    pub albedo: Option<FlatColors>,
+    /// How much (0 to 1) the density is scaled by the curvature of the surface relative to its mean
+    pub curvature_weight: f32,
+    /// File names of the brushes strokes are drawn with, in the order of the brush atlas
+    pub brush_names: Vec<String>,
+}
 
-            let ap = p - a;
-            let bp = p - b;
+/// Albedos of a scene for the flat stroke colors, picked per shape like the albedo it's drawn with
+#[derive(Debug, Clone, Default)]
+pub struct FlatColors {
+    pub scene: RgbaImage,
+    /// Albedo of each object, if it has its own
+    pub objects: Vec<Option<RgbaImage>>,
+    /// Albedos of the shapes' materials by path, without those that failed to load
+    pub materials: BTreeMap<PathBuf, RgbaImage>,
+}
 
-            let u = (ac.cross(ap).magnitude() / 2.0) / area;
-            let v = (ab.cross(bp).magnitude() / 2.0) / area;
-            let w = 1.0 - u - v;
+impl FlatColors {
+    /// Linear color at `uv` of a shape of `object` with the material albedo `material`, from the
+    /// object's albedo, then the material's, then the scene's
+    pub fn sample(&self, object: usize, material: Option<&Path>, uv: [f32; 2]) -> [f32; 3] {
+        let albedo = self.objects[object]
+            .as_ref()
+            .or_else(|| material.and_then(|path| self.materials.get(path)))
+            .unwrap_or(&self.scene);
+        sample_color(albedo, uv.into())
+    }
+}
 
-            let n = an * u + bn * v + cn * w;
-            let uv = auv * u + buv * v + cuv * w;
+/// Generates points on the surface of a model with a density of `density` points per unit squared
+///
+/// If a stroke mask is given, each point is kept with a probability equal to the mask value at
+/// its uv, so black regions of the mask get no strokes. With an importance map of strength `s`
+/// the local density is scaled by `mix(1, 2 * importance, s)`, so mid gray keeps `density`. A
+/// curvature weight `c` scales it by `mix(1, curvature / mean curvature, c)`, so curved regions
+/// get more points and flat ones fewer while the total stays about the same. If
+/// `brushes` is given, strokes only use those brushes. Every random choice is drawn from `rng`,
+/// so the same rng state gives the same points.
+///
+/// Each point comes with the curvature of the surface under it relative to the mean of the mesh,
+/// which `prune_points` keeps the more curved points by. Flat colors are left black, since the
+/// albedo depends on the object and material of the shape (see `FlatColors`).
+pub fn gen_point_list(
+    model: &Model,
+    sampler: &MeshSampler,
+    density: f32,
+    sampling: Sampling,
+    stroke_maps: &StrokeMaps,
+    brushes: Option<&[BrushRef]>,
+    rng: &mut impl Rng,
+) -> Vec<(Point, f32)> {
+    let num_brushes = stroke_maps.brush_names.len() as u32;
 
-            points.push(Point {
-                position: p.into(),
-                normal: n.into(),
-                tangent: tangent.into(),
-                bitangent: bitangent.into(),
-                uv: uv.into(),
-                brush_index: (rand::random::<u32>() % num_brushes) as i32,
-            })
+    let brushes = match brushes {
+        Some(brushes) => {
+            let valid = brushes
+                .iter()
+                .filter_map(|brush| brush.index(&stroke_maps.brush_names))
+                .collect::<Vec<_>>();
+            if valid.len() != brushes.len() {
+                warn!(
+                    "{}: ignoring unknown brushes (brushes: {})",
+                    model.name,
+                    stroke_maps.brush_names.join(", ")
+                );
+            }
+            valid
         }
+        None => vec![],
+    };
+    let brushes = if brushes.is_empty() {
+        (0..num_brushes).collect()
+    } else {
+        brushes
+    };
+
+    // Points are generated at the highest local density and rejected down to the importance and
+    // curvature
+    let max_importance_scale = stroke_maps
+        .importance
+        .as_ref()
+        .map_or(1.0, |(_, strength)| 1.0 + strength);
+
+    let curvature_weight = stroke_maps.curvature_weight;
+    let curvature_scale = |curvature: f32| 1.0 - curvature_weight + curvature_weight * curvature;
+    let max_curvature_scale = curvature_scale(sampler.max_curvature);
+
+    let max_density = density * max_importance_scale * max_curvature_scale;
+    let samples = match sampling {
+        Sampling::Random => random_samples(sampler, max_density, rng),
+        Sampling::Poisson => poisson_samples(sampler, max_density, rng),
+    };
+
+    let mut points = vec![];
+
+    for (triangle, r1, r2) in samples {
+        let triangle = &sampler.triangles[triangle];
+        let [a, b, c] = triangle.positions;
+        let [an, bn, cn] = triangle.normals;
+        let [auv, buv, cuv] = triangle.uvs;
+        let [(at, abt), (bt, bbt), (ct, cbt)] = triangle.tangents;
+
+        let p = a + (b - a) * r1 + (c - a) * r2;
+
+        // Barycentric weights of a, b and c
+        let u = 1.0 - r1 - r2;
+        let v = r1;
+        let w = r2;
+
+        let n = an * u + bn * v + cn * w;
+        let uv = auv * u + buv * v + cuv * w;
+        let tangent = at * u + bt * v + ct * w;
+        let bitangent = abt * u + bbt * v + cbt * w;
+
+        if let Some(mask) = &stroke_maps.mask {
+            if rng.gen::<f32>() >= sample_mask(mask, uv) {
+                continue;
+            }
+        }
+        if let Some((importance, strength)) = &stroke_maps.importance {
+            let scale = 1.0 - strength + 2.0 * strength * sample_mask(importance, uv);
+            if rng.gen::<f32>() * max_importance_scale >= scale {
+                continue;
+            }
+        }
+        if curvature_weight > 0.0
+            && rng.gen::<f32>() * max_curvature_scale >= curvature_scale(triangle.curvature)
+        {
+            continue;
+        }
+
+        let flow = stroke_maps
+            .flow
+            .as_ref()
+            .map_or(Vector2::zero(), |flow| flow.sample(uv));
+
+        points.push((
+            Point::new(
+                p,
+                n,
+                tangent,
+                bitangent,
+                uv,
+                brushes[rng.gen_range(0..brushes.len())] as i32,
+            )
+            .with_flow(flow),
+            triangle.curvature,
+        ));
     }
 
+    let total_area = sampler.total_area;
     let actual_density = points.len() as f32 / total_area;
 
     let error = (100.0 * (actual_density - density) / density).abs();
 
     info!(
-        "{}:\n\tTotal area: {total_area}\n\texpected density: {density}\n\tactual density: {actual_density}\n\terror: {error}%",
+        "{}:\n\tTotal area: {total_area}\n\texpected density: {density}\n\t\
+         actual density: {actual_density}\n\terror: {error}%",
         model.name,
     );
 
     points
 }
+
+/// Uniform random point on the unit triangle, as the weights of its second and third vertices
+fn random_barycentric(rng: &mut impl Rng) -> (f32, f32) {
+    let r1: f32 = rng.gen();
+    let r2: f32 = rng.gen();
+    if r1 + r2 >= 1.0 {
+        (1.0 - r1, 1.0 - r2)
+    } else {
+        (r1, r2)
+    }
+}
+
+/// Independent random samples with `density` points per unit squared on average, as triangle
+/// indices and barycentric coordinates
+fn random_samples(
+    sampler: &MeshSampler,
+    density: f32,
+    rng: &mut impl Rng,
+) -> Vec<(usize, f32, f32)> {
+    let mut samples = vec![];
+    for (i, triangle) in sampler.triangles.iter().enumerate() {
+        let num_points_f32 = triangle.area * density;
+        let mut num_points = num_points_f32.floor() as usize;
+        if rng.gen::<f32>() < num_points_f32.fract() {
+            num_points += 1;
+        }
+        for _ in 0..num_points {
+            let (r1, r2) = random_barycentric(rng);
+            samples.push((i, r1, r2));
+        }
+    }
+    samples
+}
+
+/// Poisson disk samples with `density` points per unit squared, by dart throwing: random points
+/// closer than the minimum distance to an accepted one are rejected. Distances are measured in
+/// 3D rather than along the surface.
+fn poisson_samples(
+    sampler: &MeshSampler,
+    density: f32,
+    rng: &mut impl Rng,
+) -> Vec<(usize, f32, f32)> {
+    let target_f32 = sampler.total_area * density;
+    let mut target = target_f32.floor() as usize;
+    if rng.gen::<f32>() < target_f32.fract() {
+        target += 1;
+    }
+    if target == 0 || sampler.total_area <= 0.0 {
+        return vec![];
+    }
+
+    let radius = POISSON_RADIUS / density.sqrt();
+    let cell = |p: Vector3<f32>| {
+        let p = p / radius;
+        (p.x.floor() as i32, p.y.floor() as i32, p.z.floor() as i32)
+    };
+
+    // Triangles are picked with a probability proportional to their area
+    let cumulative_area = sampler
+        .triangles
+        .iter()
+        .scan(0.0, |sum, triangle| {
+            *sum += triangle.area;
+            Some(*sum)
+        })
+        .collect::<Vec<f32>>();
+
+    let mut grid: HashMap<(i32, i32, i32), Vec<Vector3<f32>>> = HashMap::new();
+    let mut samples = vec![];
+
+    for _ in 0..target * POISSON_MAX_ATTEMPTS {
+        if samples.len() == target {
+            break;
+        }
+
+        let area = rng.gen::<f32>() * sampler.total_area;
+        let i = cumulative_area
+            .partition_point(|&sum| sum <= area)
+            .min(sampler.triangles.len() - 1);
+        let (r1, r2) = random_barycentric(rng);
+        let [a, b, c] = sampler.triangles[i].positions;
+        let p = a + (b - a) * r1 + (c - a) * r2;
+
+        // The cells are as large as the radius, so any point that is too close is in a
+        // neighboring cell
+        let (x, y, z) = cell(p);
+        let too_close = (-1..=1).any(|dx| {
+            (-1..=1).any(|dy| {
+                (-1..=1).any(|dz| {
+                    grid.get(&(x + dx, y + dy, z + dz))
+                        .is_some_and(|cell| cell.iter().any(|q| q.distance2(p) < radius * radius))
+                })
+            })
+        });
+        if too_close {
+            continue;
+        }
+
+        grid.entry((x, y, z)).or_default().push(p);
+        samples.push((i, r1, r2));
+    }
+
+    if samples.len() < target {
+        warn!(
+            "Poisson disk sampling placed {} of {target} points before giving up",
+            samples.len()
+        );
+    }
+    samples
+}
+
+/// Prunes `points` from `gen_point_list` down to at most `max_points`, keeping the most
+/// important ones.
+///
+/// A point's importance is its stroke mask value (or 1 without a mask) times one plus its relative
+/// curvature, scaled by a random factor. Flat regions lose their points first, and the surviving
+/// points of evenly curved surfaces stay evenly spread.
+pub fn prune_points(
+    points: &mut Vec<(Point, f32)>,
+    max_points: usize,
+    stroke_mask: Option<&GrayImage>,
+    rng: &mut impl Rng,
+) {
+    if points.len() <= max_points {
+        return;
+    }
+
+    let mut importance = |(p, curvature): &(Point, f32)| {
+        let mask = stroke_mask.map_or(1.0, |mask| sample_mask(mask, p.uv()));
+        mask * (1.0 + curvature) * (1.0 - rng.gen::<f32>())
+    };
+
+    let mut scored = points
+        .drain(..)
+        .map(|p| (importance(&p), p))
+        .collect::<Vec<_>>();
+    scored.select_nth_unstable_by(max_points, |(a, _), (b, _)| b.total_cmp(a));
+    scored.truncate(max_points);
+
+    points.extend(scored.into_iter().map(|(_, p)| p));
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{Luma, Rgba};
+    use rand::{rngs::StdRng, SeedableRng};
+    use tobj::Mesh;
+
+    use super::*;
+
+    /// A unit square in the xy plane facing +z, with uvs equal to its positions
+    fn square() -> Model {
+        let mesh = Mesh {
+            positions: vec![0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 1.0, 1.0, 0.0, 0.0, 1.0, 0.0],
+            normals: vec![0.0, 0.0, 1.0, 0.0, 0.0, 1.0, 0.0, 0.0, 1.0, 0.0, 0.0, 1.0],
+            texcoords: vec![0.0, 0.0, 1.0, 0.0, 1.0, 1.0, 0.0, 1.0],
+            indices: vec![0, 1, 2, 0, 2, 3],
+            ..Mesh::default()
+        };
+        Model::new(mesh, "square".to_string())
+    }
+
+    fn stroke_maps() -> StrokeMaps {
+        StrokeMaps {
+            brush_names: vec!["1.png".to_string()],
+            ..StrokeMaps::default()
+        }
+    }
+
+    fn gen_points(model: &Model, density: f32, stroke_maps: &StrokeMaps, seed: u64) -> Vec<Point> {
+        gen_point_list(
+            model,
+            &MeshSampler::new(model),
+            density,
+            Sampling::Random,
+            stroke_maps,
+            None,
+            &mut StdRng::seed_from_u64(seed),
+        )
+        .into_iter()
+        .map(|(p, _)| p)
+        .collect()
+    }
+
+    #[test]
+    fn no_points_where_the_mask_is_black() {
+        let model = square();
+        // The left half of the uv square is masked out
+        let mask = GrayImage::from_fn(64, 64, |x, _| Luma([if x < 32 { 0 } else { 255 }]));
+        let stroke_maps = StrokeMaps {
+            mask: Some(mask),
+            ..stroke_maps()
+        };
+
+        let points = gen_points(&model, 2000.0, &stroke_maps, 1);
+        assert!(!points.is_empty());
+        assert!(
+            points.iter().all(|p| p.uv[0] >= 0.5),
+            "points in the masked half"
+        );
+    }
+    #[test]
+    fn white_importance_gets_more_points_than_black() {
+        let model = square();
+        // Black on the left half of the uv square, white on the right
+        let importance = GrayImage::from_fn(64, 64, |x, _| Luma([if x < 32 { 0 } else { 255 }]));
+
+        for strength in [0.5, 1.0] {
+            let stroke_maps = StrokeMaps {
+                importance: Some((importance.clone(), strength)),
+                ..stroke_maps()
+            };
+            let points = gen_points(&model, 4000.0, &stroke_maps, 2);
+            let white = points.iter().filter(|p| p.uv[0] >= 0.5).count();
+            let black = points.len() - white;
+            // mix(1, 2 * importance, strength) is 1 - strength for black and 1 + strength for white
+            assert!(
+                white as f32 > 1.5 * black as f32,
+                "strength {strength}: {white} white and {black} black points"
+            );
+        }
+    }
+
+    #[test]
+    fn vertical_stripes_give_vertical_flow() {
+        let model = square();
+        let stripes = RgbaImage::from_fn(64, 64, |x, _| {
+            let c = if x / 4 % 2 == 0 { 0 } else { 255 };
+            image::Rgba([c, c, c, 255])
+        });
+        let stroke_maps = StrokeMaps {
+            flow: Some(FlowField::from_image(&stripes, 2)),
+            ..stroke_maps()
+        };
+
+        let points = gen_points(&model, 2000.0, &stroke_maps, 3);
+        let vertical = points
+            .iter()
+            .filter(|p| p.flow[1].abs() > 4.0 * p.flow[0].abs())
+            .count();
+        assert!(
+            vertical as f32 > 0.9 * points.len() as f32,
+            "{vertical} of {} strokes are vertical",
+            points.len()
+        );
+    }
+
+    #[test]
+    fn tangents_are_continuous_across_shared_edges() {
+        // A flat square whose uvs are sheared differently on either side of the diagonal 0-2
+        let mesh = Mesh {
+            positions: vec![0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 1.0, 1.0, 0.0, 0.0, 1.0, 0.0],
+            normals: vec![0.0, 0.0, 1.0, 0.0, 0.0, 1.0, 0.0, 0.0, 1.0, 0.0, 0.0, 1.0],
+            texcoords: vec![0.0, 0.0, 1.0, 0.0, 1.5, 1.5, 0.0, 1.0],
+            indices: vec![0, 1, 2, 0, 2, 3],
+            ..Mesh::default()
+        };
+        let sampler = MeshSampler::new(&Model::new(mesh, "sheared".to_string()));
+        let [first, second] = &sampler.triangles[..] else {
+            panic!("expected two triangles");
+        };
+
+        // Per-triangle tangents jump at the edge
+        let flat = |t: &SampleTriangle| triangle_tangent(t.positions, t.uvs).unwrap().0;
+        let jump = flat(first).normalize().angle(flat(second).normalize());
+        assert!(jump.0 > 0.1, "the uvs don't shear the tangents ({jump:?})");
+
+        // Smooth tangents interpolated to the middle of the edge agree on both sides
+        let first_mid = (first.tangents[0].0 + first.tangents[2].0) * 0.5;
+        let second_mid = (second.tangents[0].0 + second.tangents[1].0) * 0.5;
+        assert!(
+            (first_mid - second_mid).magnitude() < 1.0e-6,
+            "{first_mid:?} != {second_mid:?}"
+        );
+    }
+
+    #[test]
+    fn points_round_trip_through_serialization() {
+        let point = Point::new(
+            Vector3::new(0.1, -2.0, 3.5),
+            Vector3::unit_z(),
+            Vector3::unit_x(),
+            Vector3::unit_y(),
+            Vector2::new(0.25, 0.75),
+            3,
+        )
+        .with_jitter(42, 7)
+        .with_flow([0.6, -0.8])
+        .with_color([0.2, 0.4, 0.8]);
+
+        let bytes = bincode::serialize(&point).unwrap();
+        assert_eq!(bincode::deserialize::<Point>(&bytes).unwrap(), point);
+        let json = serde_json::to_string(&point).unwrap();
+        assert_eq!(serde_json::from_str::<Point>(&json).unwrap(), point);
+
+        assert_eq!(point.position(), Point3::new(0.1, -2.0, 3.5));
+        assert_eq!(point.uv(), Vector2::new(0.25, 0.75));
+    }
+
+    #[test]
+    fn jitter_depends_only_on_seed_and_index() {
+        let point = Point::new([0.0; 3], [0.0; 3], [0.0; 3], [0.0; 3], [0.0; 2], 0);
+        let jitter = |seed, index| {
+            let p = point.with_jitter(seed, index);
+            [p.stroke_length_rand, p.jitter[0], p.jitter[1]]
+        };
+
+        assert_eq!(jitter(7, 3), jitter(7, 3));
+        assert_ne!(jitter(7, 3), jitter(7, 4));
+        assert_ne!(jitter(7, 3), jitter(8, 3));
+        assert!((0..1000)
+            .flat_map(|i| jitter(7, i))
+            .all(|x| (0.0..1.0).contains(&x)));
+    }
+
+    /// A wavy `n` by `n` grid of quads, big enough for generation times to be measurable
+    fn grid(n: u32) -> Model {
+        let mut mesh = Mesh::default();
+        for y in 0..=n {
+            for x in 0..=n {
+                let (u, v) = (x as f32 / n as f32, y as f32 / n as f32);
+                let z = 0.05 * (u * 20.0).sin() * (v * 20.0).cos();
+                mesh.positions.extend([u, v, z]);
+                mesh.normals.extend([0.0, 0.0, 1.0]);
+                mesh.texcoords.extend([u, v]);
+            }
+        }
+        for y in 0..n {
+            for x in 0..n {
+                let i = y * (n + 1) + x;
+                mesh.indices
+                    .extend([i, i + 1, i + n + 2, i, i + n + 2, i + n + 1]);
+            }
+        }
+        Model::new(mesh, "grid".to_string())
+    }
+
+    #[test]
+    #[ignore = "benchmark, run with `cargo test --release -- --ignored --nocapture`"]
+    fn bench_regeneration() {
+        let model = grid(400);
+        let stroke_maps = stroke_maps();
+        let generate = |sampler: &MeshSampler| {
+            gen_point_list(
+                &model,
+                sampler,
+                20000.0,
+                Sampling::Random,
+                &stroke_maps,
+                None,
+                &mut StdRng::seed_from_u64(0),
+            )
+        };
+
+        let start = Instant::now();
+        let sampler = MeshSampler::new(&model);
+        let num_points = generate(&sampler).len();
+        let first = start.elapsed();
+
+        let runs = 5;
+        let start = Instant::now();
+        for _ in 0..runs {
+            generate(&sampler);
+        }
+        let regenerated = start.elapsed() / runs;
+
+        println!(
+            "Generating {num_points} points on {} triangles: first {first:?}, again with the \
+             same sampler {regenerated:?}",
+            model.mesh.indices.len() / 3
+        );
+        assert!(regenerated < first);
+    }
+
+    #[test]
+    fn flat_colors_prefer_the_object_then_the_material() {
+        let solid = |value| RgbaImage::from_pixel(2, 2, Rgba([value, value, value, 255]));
+        let colors = FlatColors {
+            scene: solid(0),
+            objects: vec![None, Some(solid(255))],
+            materials: BTreeMap::from([(PathBuf::from("material.png"), solid(188))]),
+        };
+        let material = Some(Path::new("material.png"));
+        let red = |object, material| colors.sample(object, material, [0.5, 0.5])[0];
+
+        assert_eq!(red(1, material), 1.0);
+        assert!((red(0, material) - 0.5).abs() < 0.01);
+        assert_eq!(red(0, None), 0.0);
+        // Materials that failed to load use the scene's albedo
+        assert_eq!(red(0, Some(Path::new("missing.png"))), 0.0);
+    }
+}
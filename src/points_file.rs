@@ -0,0 +1,91 @@
+use std::{
+    fmt,
+    fs::File,
+    io::{self, BufReader, BufWriter, Read, Write},
+    path::Path,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::point_gen::Point;
+
+const MAGIC: &[u8; 8] = b"PRPOINTS";
+pub const VERSION: u32 = 8;
+
+/// Describes what a `.points` file was generated from
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PointsHeader {
+    pub stroke_density: u32,
+    pub max_points: Option<usize>,
+    pub seed: Option<u64>,
+    /// sha256 of each OBJ file the points were generated on
+    pub obj_sha256: Vec<String>,
+    /// Names of the shapes, in the order of the point lists
+    pub shapes: Vec<String>,
+}
+
+#[derive(Debug)]
+pub enum PointsError {
+    Io(io::Error),
+    Format(bincode::Error),
+    NotPoints,
+    Version(u32),
+    Mismatch(String),
+}
+
+impl fmt::Display for PointsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PointsError::Io(e) => write!(f, "{e}"),
+            PointsError::Format(e) => write!(f, "invalid points file: {e}"),
+            PointsError::NotPoints => write!(f, "not a points file"),
+            PointsError::Version(version) => {
+                write!(f, "unsupported points file version {version}")
+            }
+            PointsError::Mismatch(reason) => write!(f, "points don't match the scene: {reason}"),
+        }
+    }
+}
+
+impl From<io::Error> for PointsError {
+    fn from(e: io::Error) -> Self {
+        PointsError::Io(e)
+    }
+}
+
+impl From<bincode::Error> for PointsError {
+    fn from(e: bincode::Error) -> Self {
+        PointsError::Format(e)
+    }
+}
+
+/// Writes the point list of every shape to `path`
+pub fn write_points(
+    path: &Path,
+    header: &PointsHeader,
+    points: &[Vec<Point>],
+) -> Result<(), PointsError> {
+    let mut file = BufWriter::new(File::create(path)?);
+    file.write_all(MAGIC)?;
+    bincode::serialize_into(&mut file, &(VERSION, header, points))?;
+    file.flush()?;
+    Ok(())
+}
+
+/// Reads a file written by `write_points`
+pub fn read_points(path: &Path) -> Result<(PointsHeader, Vec<Vec<Point>>), PointsError> {
+    let mut file = BufReader::new(File::open(path)?);
+
+    let mut magic = [0; 8];
+    file.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(PointsError::NotPoints);
+    }
+
+    let version: u32 = bincode::deserialize_from(&mut file)?;
+    if version != VERSION {
+        return Err(PointsError::Version(version));
+    }
+
+    Ok(bincode::deserialize_from(&mut file)?)
+}
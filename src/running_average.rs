@@ -4,6 +4,8 @@ pub struct RunningAverage<T, const N: usize> {
     values: [T; N],
     index: usize,
     sum: T,
+    /// Number of values added, up to `N`
+    len: usize,
 }
 
 impl<T, const N: usize> RunningAverage<T, N>
@@ -15,6 +17,7 @@ where
             values: [T::zero(); N],
             index: 0,
             sum: T::zero(),
+            len: 0,
         }
     }
 
@@ -22,9 +25,77 @@ where
         self.sum = self.sum - self.values[self.index] + value;
         self.values[self.index] = value;
         self.index = (self.index + 1) % N;
+        self.len = (self.len + 1).min(N);
     }
 
+    /// Average of the values added so far, at most the last `N`, zero before any are added
     pub fn average(&self) -> T {
-        self.sum / T::from(N).unwrap()
+        if self.len == 0 {
+            return T::zero();
+        }
+        self.sum / T::from(self.len).unwrap()
+    }
+
+    /// Forgets every value added so far
+    pub fn reset(&mut self) {
+        self.values = [T::zero(); N];
+        self.index = 0;
+        self.sum = T::zero();
+        self.len = 0;
+    }
+
+    /// Number of values averaged, at most `N`
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Whether `N` values have been added since the last reset
+    pub fn is_full(&self) -> bool {
+        self.len == N
+    }
+
+    /// The values added so far, at most the last `N`
+    fn samples(&self) -> &[T] {
+        // Until the buffer is full the values fill it from the start
+        &self.values[..self.len]
+    }
+
+    /// Smallest of the last `N` values, zero before any are added
+    pub fn min(&self) -> T
+    where
+        T: PartialOrd,
+    {
+        self.samples()
+            .iter()
+            .copied()
+            .reduce(|a, b| if b < a { b } else { a })
+            .unwrap_or_else(T::zero)
+    }
+
+    /// Largest of the last `N` values, zero before any are added
+    pub fn max(&self) -> T
+    where
+        T: PartialOrd,
+    {
+        self.samples()
+            .iter()
+            .copied()
+            .reduce(|a, b| if b > a { b } else { a })
+            .unwrap_or_else(T::zero)
+    }
+
+    /// Population variance of the last `N` values
+    pub fn variance(&self) -> f64 {
+        let samples = self.samples();
+        if samples.is_empty() {
+            return 0.0;
+        }
+        let values = samples.iter().map(|v| v.to_f64().unwrap());
+        let mean = values.clone().sum::<f64>() / samples.len() as f64;
+        values.map(|v| (v - mean).powi(2)).sum::<f64>() / samples.len() as f64
+    }
+
+    pub fn std_dev(&self) -> f64 {
+        self.variance().sqrt()
     }
 }
@@ -0,0 +1,18 @@
+uniform sampler2D source_texture;
+// Offset between taps in uv, along the blurred axis
+uniform vec2 direction;
+
+out vec4 color;
+in vec2 v_pos;
+
+// Gaussian weights of the center tap and the taps on each side of it
+const float WEIGHTS[5] = float[](0.2270, 0.1945, 0.1216, 0.0541, 0.0162);
+
+void main() {
+    vec3 sum = texture(source_texture, v_pos).rgb * WEIGHTS[0];
+    for (int i = 1; i < 5; i++) {
+        sum += texture(source_texture, v_pos + float(i) * direction).rgb * WEIGHTS[i];
+        sum += texture(source_texture, v_pos - float(i) * direction).rgb * WEIGHTS[i];
+    }
+    color = vec4(sum, 1.0);
+}
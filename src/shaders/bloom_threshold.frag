@@ -0,0 +1,15 @@
+uniform sampler2D source_texture;
+// Luminance above which pixels bloom
+uniform float threshold;
+
+out vec4 color;
+in vec2 v_pos;
+
+const vec3 LUMINANCE = vec3(0.2126, 0.7152, 0.0722);
+
+void main() {
+    vec3 c = texture(source_texture, v_pos).rgb;
+    float lum = dot(c, LUMINANCE);
+    // Keeps only the part of the brightness above the threshold, with the pixel's hue
+    color = vec4(c * max(lum - threshold, 0.0) / max(lum, 1.0e-4), 1.0);
+}
@@ -8,5 +8,5 @@ uniform sampler2D albedo_texture;
 void main() {
     vec3 tex = texture(albedo_texture, v_tex_coords).rgb;
     color.xyz = tex * (max(dot(v_normal, vec3(0.0, 0.0, 1.0)), 0.0) + 0.3);
-    color.w = gl_FragCoord.z;
+    color.w = 1.0;
 }
\ No newline at end of file
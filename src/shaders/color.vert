@@ -1,16 +1,19 @@
 uniform mat4 view;
 uniform mat4 perspective;
 uniform mat4 model;
+// Transform of the model's object within the scene
+uniform mat4 object;
 
 in vec3 position;
 in vec3 normal;
 in vec2 tex_coords;
+in mat4 instance_model;
 
 out vec3 v_normal;
 out vec2 v_tex_coords;
 
 void main() {
-    gl_Position = perspective * view * model * vec4(position, 1.0);
+    gl_Position = perspective * view * model * instance_model * object * vec4(position, 1.0);
     v_normal = normal;
     v_tex_coords = tex_coords;
 }
\ No newline at end of file
@@ -0,0 +1,8 @@
+out vec4 color;
+
+in vec3 v_normal;
+in vec2 v_tex_coords;
+
+void main() {
+    color = vec4(normalize(v_normal) * 0.5 + 0.5, 1.0);
+}
@@ -0,0 +1,14 @@
+uniform sampler2D accum_texture;
+uniform sampler2D revealage_texture;
+
+out vec4 color;
+in vec2 v_pos;
+
+void main() {
+    float revealage = texture(revealage_texture, v_pos).r;
+    if (revealage >= 1.0) {
+        discard;
+    }
+    vec4 accum = texture(accum_texture, v_pos);
+    color = vec4(accum.rgb / max(accum.a, 1.0e-5), 1.0 - revealage);
+}
@@ -0,0 +1,9 @@
+out vec4 color;
+
+in vec3 v_view_normal;
+in float v_view_depth;
+
+void main() {
+    // The depth is never zero in front of the camera, so zero marks the background
+    color = vec4(normalize(v_view_normal), max(v_view_depth, 1.0e-4));
+}
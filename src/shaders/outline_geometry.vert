@@ -0,0 +1,20 @@
+uniform mat4 view;
+uniform mat4 perspective;
+uniform mat4 model;
+// Transform of the model's object within the scene
+uniform mat4 object;
+
+in vec3 position;
+in vec3 normal;
+in mat4 instance_model;
+
+out vec3 v_view_normal;
+out float v_view_depth;
+
+void main() {
+    mat4 model_view = view * model * instance_model * object;
+    vec4 view_position = model_view * vec4(position, 1.0);
+    gl_Position = perspective * view_position;
+    v_view_normal = mat3(model_view) * normal;
+    v_view_depth = -view_position.z;
+}
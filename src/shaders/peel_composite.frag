@@ -0,0 +1,12 @@
+uniform sampler2D layer_texture;
+uniform bool premultiply;
+
+out vec4 color;
+in vec2 v_pos;
+
+void main() {
+    color = texture(layer_texture, v_pos);
+    if (premultiply) {
+        color.rgb *= color.a;
+    }
+}
@@ -1,24 +1,52 @@
 uniform sampler2D camera_texture;
 uniform sampler2D brush_stroke;
+uniform bool depth_peel;
+uniform bool multiply;
+uniform sampler2D peel_depth;
+// Weighted blended transparency pass: 0 off, 1 accumulation, 2 revealage
+uniform int oit_pass;
 
 out vec4 color;
 
 in vec4 g_color;
 in float g_brush_index;
 in vec2 g_uv;
+in float g_view_depth;
+in vec3 g_world_position;
+in float g_facing_alpha;
 
 void main() {
-    vec2 coord = g_uv;
+    // `g_uv` is already in the rectangle of the brush in the atlas
+    vec4 brush = texture(brush_stroke, g_uv);
 
-    coord.x /= PR_NUM_BRUSHES;
-    coord.x += g_brush_index / PR_NUM_BRUSHES;
-    vec4 brush = texture(brush_stroke, coord);
-    if (coord.x > 1.0) {
+    float intensity = 1.0 - brush.x;
+
+    color = g_color;
+    if (enable_haze) {
+        color.rgb = apply_haze(color.rgb, g_view_depth);
+    }
+    if (enable_fog) {
+        color.rgb = apply_fog(color.rgb, g_view_depth);
+    }
+    color.a = intensity * grain(g_world_position) * g_facing_alpha;
+    if (color.a <= 0.0) {
         discard;
     }
 
-    float intensity = 1.0 - brush.x;
+    if (multiply) {
+        color.rgb = mix(vec3(1.0), color.rgb, color.a);
+    }
 
-    color = g_color;
-    color.a = intensity;
-}
\ No newline at end of file
+    if (depth_peel) {
+        // Transparent parts of the brush must not hide the layers behind them
+        if (color.a < 0.05 || gl_FragCoord.z <= texelFetch(peel_depth, ivec2(gl_FragCoord.xy), 0).r) {
+            discard;
+        }
+    }
+
+    if (oit_pass == 1) {
+        color = vec4(color.rgb * color.a, color.a) * oit_weight(color.a);
+    } else if (oit_pass == 2) {
+        color = vec4(color.a);
+    }
+}
@@ -1,11 +1,27 @@
 uniform mat4 view;
 uniform mat4 perspective;
 uniform mat4 model;
+// Transform of the model's object within the scene
+uniform mat4 object;
 uniform sampler2D color_texture;
 uniform sampler2D albedo_texture;
 uniform sampler2D brush_stroke;
 uniform vec3 camera_pos;
 uniform int quantization;
+uniform float point_size;
+// Orient strokes along `flow` where it is set
+uniform bool enable_flow;
+// Paint each stroke with its own `color` instead of sampling the albedo texture
+uniform bool flat_color;
+// Unit direction towards the light
+uniform vec3 light_dir;
+uniform vec3 light_color;
+uniform float ambient;
+// Range of the cosine between the normal and the view direction over which strokes fade out, 0
+// disables the fade
+uniform float facing_fade;
+// Don't draw strokes whose normal faces away from the camera
+uniform bool cull_backfaces;
 
 in vec3 position;
 in vec3 normal;
@@ -13,11 +29,25 @@ in vec3 tangent;
 in vec3 bitangent;
 in vec2 uv;
 in int brush_index;
+in float stroke_length_rand;
+// Random rotation and size variation of the point
+in vec2 jitter;
+in vec2 flow;
+in vec3 color;
+in mat4 instance_model;
 
 out float v_brush_index;
 out vec4 v_color;
 out vec3 v_tangent;
 out vec3 v_bitangent;
+out vec2 v_jitter;
+out vec3 v_world_normal;
+out vec3 v_world_position;
+out float v_length;
+// Distance in front of the camera, used for haze and fog
+out float v_view_depth;
+// Opacity from `facing_fade`, zero for strokes that aren't drawn
+out float v_facing_alpha;
 
 // http://lolengine.net/blog/2013/07/27/rgb-to-hsv-in-glsl
 vec3 hsv2rgb(vec3 c) {
@@ -37,31 +67,62 @@ vec3 rgb2hsv(vec3 c) {
     return vec3(abs(q.z + (q.w - q.y) / (6.0 * d + e)), d / (q.x + e), q.x);
 }
 
-const vec3 TO_LIGHT_DIR = normalize(vec3(-1.0, 1.0, 1.0));
-
 void main() {
+    mat4 world = model * instance_model * object;
+
     v_brush_index = float(brush_index);
 
-    gl_Position = perspective * view * model * vec4(position, 1.0);
+    v_jitter = jitter;
+    v_length = stroke_length_rand;
+
+    vec4 view_position = view * world * vec4(position, 1.0);
+    v_view_depth = -view_position.z;
+
+    gl_Position = perspective * view_position;
+    // Only used by the point stroke style, which has no geometry shader
+    gl_PointSize = point_size;
+
+    // The tangent and bitangent follow u and v, so the uv space flow is a rotation of them
+    vec3 stroke_tangent = tangent;
+    vec3 stroke_bitangent = bitangent;
+    if (enable_flow && flow != vec2(0.0)) {
+        stroke_tangent = flow.x * tangent + flow.y * bitangent;
+        stroke_bitangent = -flow.y * tangent + flow.x * bitangent;
+    }
 
-    v_tangent = (view * model * vec4(tangent, 0.0)).xyz;
-    v_bitangent = (view * model * vec4(bitangent, 0.0)).xyz;
+    v_tangent = (view * world * vec4(stroke_tangent, 0.0)).xyz;
+    v_bitangent = (view * world * vec4(stroke_bitangent, 0.0)).xyz;
 
     // Shading
 
-    vec3 n = normalize((model * vec4(normal, 0.0)).xyz);
+    // Inverse transpose keeps normals perpendicular under non-uniform scale
+    vec3 n = normalize(transpose(inverse(mat3(world))) * normal);
+
+    vec3 p = (world * vec4(position, 1.0)).xyz;
 
-    vec3 p = (model * vec4(position, 1.0)).xyz;
+    v_world_normal = n;
+    v_world_position = p;
 
     vec3 to_view = normalize(p - camera_pos);
 
-    vec3 r = normalize(reflect(TO_LIGHT_DIR, n));
+    float facing = dot(n, -to_view);
+    v_facing_alpha = facing_fade > 0.0 ? smoothstep(0.0, facing_fade, facing) : 1.0;
+    if (cull_backfaces && facing < 0.0) {
+        v_facing_alpha = 0.0;
+    }
+    if (v_facing_alpha <= 0.0) {
+        // Outside the clip volume, so the point stroke style doesn't rasterize it either
+        gl_Position = vec4(0.0, 0.0, 2.0, 1.0);
+    }
+
+    vec3 r = normalize(reflect(light_dir, n));
 
     float kS = pow(max(dot(r, to_view), 0.0), 20.0);
 
-    float kD = max(dot(n, TO_LIGHT_DIR), 0.0);
+    float kD = max(dot(n, light_dir), 0.0);
 
-    v_color = texture(albedo_texture, uv) * (kD + 0.2) + vec4(1.0, 1.0, 1.0, 1.0) * kS;
+    vec3 albedo = flat_color ? color : texture(albedo_texture, uv).rgb;
+    v_color = vec4(albedo * (kD * light_color + ambient) + light_color * kS, 1.0);
 
     // Apply quantization to brightness
     if (quantization != 0) {
@@ -0,0 +1,48 @@
+uniform bool depth_peel;
+uniform bool multiply;
+uniform sampler2D peel_depth;
+// Weighted blended transparency pass: 0 off, 1 accumulation, 2 revealage
+uniform int oit_pass;
+
+out vec4 color;
+
+in vec4 v_color;
+in float v_view_depth;
+in vec3 v_world_position;
+in float v_facing_alpha;
+
+void main() {
+    // Round dot with a soft edge
+    float d = length(gl_PointCoord - vec2(0.5)) * 2.0;
+    if (d > 1.0) {
+        discard;
+    }
+
+    color = v_color;
+    if (enable_haze) {
+        color.rgb = apply_haze(color.rgb, v_view_depth);
+    }
+    if (enable_fog) {
+        color.rgb = apply_fog(color.rgb, v_view_depth);
+    }
+    color.a = (1.0 - smoothstep(0.8, 1.0, d)) * grain(v_world_position) * v_facing_alpha;
+    if (color.a <= 0.0) {
+        discard;
+    }
+
+    if (multiply) {
+        color.rgb = mix(vec3(1.0), color.rgb, color.a);
+    }
+
+    if (depth_peel) {
+        if (color.a < 0.05 || gl_FragCoord.z <= texelFetch(peel_depth, ivec2(gl_FragCoord.xy), 0).r) {
+            discard;
+        }
+    }
+
+    if (oit_pass == 1) {
+        color = vec4(color.rgb * color.a, color.a) * oit_weight(color.a);
+    } else if (oit_pass == 2) {
+        color = vec4(color.a);
+    }
+}
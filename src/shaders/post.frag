@@ -1,21 +1,174 @@
 uniform sampler2D post_process_texture;
 uniform sampler2D canvas_texture;
 uniform bool enable_canvas;
+// How much (0 to 1) of the canvas texture shows through the paint
+uniform float canvas_strength;
+// Number of times the canvas texture repeats across the frame
+uniform float canvas_scale;
 uniform float saturation;
+// Multiplier of the colors before tone mapping
+uniform float exposure;
+// 0 for none, 1 for Reinhard and 2 for ACES
+uniform int tonemap;
+// Rotation of the hues in radians
+uniform float hue_shift;
+// Per-channel grading of the tone mapped colors
+uniform vec3 lift;
+uniform vec3 gamma;
+uniform vec3 gain;
+uniform vec3 background;
+uniform vec3 paint_background;
+// 0 for the flat background, 1 for a vertical and 2 for a radial gradient
+uniform int background_gradient;
+uniform vec3 gradient_color_a;
+uniform vec3 gradient_color_b;
+// Kuwahara filter radius in pixels, 0 disables smudging
+uniform int smudge_radius;
+uniform bool enable_bloom;
+// Blurred bright parts of the paint, added on top of it
+uniform sampler2D bloom_texture;
+uniform float bloom_intensity;
+uniform bool enable_outline;
+// View space normals in `rgb` and view depth in `a` of the mesh, zero depth where there is none
+uniform sampler2D outline_texture;
+// Width in pixels of the outline
+uniform float outline_thickness;
+// Edge strength above which the outline is drawn
+uniform float outline_threshold;
+uniform vec3 outline_color;
+uniform bool enable_vignette;
+// Distance from the center, relative to the corners, where the vignette starts darkening
+uniform float vignette_radius;
+// Distance over which the vignette fades to black
+uniform float vignette_softness;
 
 out vec4 color;
 in vec2 v_pos;
 
+const vec3 LUMINANCE = vec3(0.2126, 0.7152, 0.0722);
+
+// Kuwahara filter: averages the quadrant around the pixel with the lowest luminance variance,
+// flattening color regions while keeping the edges between them
+vec4 kuwahara(vec2 uv, int radius) {
+    vec2 texel = 1.0 / vec2(textureSize(post_process_texture, 0));
+
+    vec4 best_mean = vec4(0.0);
+    float best_variance = 1.0e20;
+
+    for (int q = 0; q < 4; q++) {
+        ivec2 dir = ivec2(q % 2 == 0 ? -1 : 1, q < 2 ? -1 : 1);
+
+        vec4 sum = vec4(0.0);
+        float lum_sum = 0.0;
+        float lum_sq_sum = 0.0;
+        for (int y = 0; y <= radius; y++) {
+            for (int x = 0; x <= radius; x++) {
+                vec4 s = texture(post_process_texture, uv + vec2(dir * ivec2(x, y)) * texel);
+                float lum = dot(s.rgb, LUMINANCE);
+                sum += s;
+                lum_sum += lum;
+                lum_sq_sum += lum * lum;
+            }
+        }
+
+        float n = float((radius + 1) * (radius + 1));
+        float mean_lum = lum_sum / n;
+        float variance = lum_sq_sum / n - mean_lum * mean_lum;
+        if (variance < best_variance) {
+            best_variance = variance;
+            best_mean = sum / n;
+        }
+    }
+
+    return best_mean;
+}
+
+// Krzysztof Narkowicz's fit of the ACES filmic curve
+// https://knarkowicz.wordpress.com/2016/01/06/aces-filmic-tone-mapping-curve/
+vec3 aces(vec3 x) {
+    return clamp((x * (2.51 * x + 0.03)) / (x * (2.43 * x + 0.59) + 0.14), 0.0, 1.0);
+}
+
+// Rotates `c` around the gray axis by `angle` radians, keeping its luminance roughly the same
+vec3 rotate_hue(vec3 c, float angle) {
+    // Rodrigues' rotation around the unit (1, 1, 1) axis
+    const vec3 k = vec3(0.57735);
+    float cos_angle = cos(angle);
+    return c * cos_angle + cross(k, c) * sin(angle) + k * dot(k, c) * (1.0 - cos_angle);
+}
+
+// Sobel filter over the mesh normals and depth, 1 on silhouettes and creases. Only pixels covered
+// by the mesh are outlined, so silhouette lines sit on the inside of the edge.
+float outline(vec2 uv) {
+    vec4 center = texture(outline_texture, uv);
+    if (center.a <= 0.0) {
+        return 0.0;
+    }
+
+    vec2 texel = outline_thickness / vec2(textureSize(outline_texture, 0));
+    vec4 gx = vec4(0.0);
+    vec4 gy = vec4(0.0);
+    for (int y = -1; y <= 1; y++) {
+        for (int x = -1; x <= 1; x++) {
+            vec4 s = texture(outline_texture, uv + vec2(x, y) * texel);
+            gx += float(x * (y == 0 ? 2 : 1)) * s;
+            gy += float(y * (x == 0 ? 2 : 1)) * s;
+        }
+    }
+
+    float normal_edge = length(vec2(length(gx.rgb), length(gy.rgb)));
+    // Relative to the depth so distant objects aren't outlined more than near ones
+    float depth_edge = length(vec2(gx.a, gy.a)) / center.a;
+    return smoothstep(0.5 * outline_threshold, outline_threshold, max(normal_edge, depth_edge));
+}
+
+vec3 background_color(vec2 pos) {
+    if (background_gradient == 1) {
+        return mix(gradient_color_b, gradient_color_a, pos.y);
+    } else if (background_gradient == 2) {
+        return mix(gradient_color_a, gradient_color_b, length(pos - 0.5) / length(vec2(0.5)));
+    }
+    return background;
+}
+
 void main() {
-    vec3 c = texture(post_process_texture, v_pos).xyz;
-    if (enable_canvas) {
-        c *= texture(canvas_texture, v_pos).x;
+    vec4 paint = smudge_radius > 0 ? kuwahara(v_pos, smudge_radius)
+                                   : texture(post_process_texture, v_pos);
+    // Swap the paint background for the displayed background where there are no strokes. This is
+    // a no-op when both colors are the same.
+    float covered = smoothstep(0.0, 0.25, paint.a);
+    vec3 c = paint.rgb + (1.0 - covered) * (background_color(v_pos) - paint_background);
+    if (enable_bloom) {
+        c += bloom_intensity * texture(bloom_texture, v_pos).rgb;
     }
 
-    vec3 c_lum_scale = vec3(0.2126, 0.7152, 0.0722) * c;
-    vec3 c_lum = vec3(c_lum_scale.x + c_lum_scale.y + c_lum_scale.z);
+    c *= exposure;
+    if (tonemap == 1) {
+        c = c / (1.0 + c);
+    } else if (tonemap == 2) {
+        c = aces(c);
+    }
+
+    c = gain * (c + lift * (1.0 - c));
+    c = pow(max(c, 0.0), 1.0 / max(gamma, 1.0e-2));
+    c = rotate_hue(c, hue_shift);
+
+    vec3 c_lum = vec3(dot(c, LUMINANCE));
 
     c = mix(c_lum, c, saturation);
 
+    if (enable_outline) {
+        c = mix(c, outline_color, outline(v_pos));
+    }
+
+    if (enable_canvas) {
+        c *= mix(1.0, texture(canvas_texture, v_pos * canvas_scale).x, canvas_strength);
+    }
+
+    if (enable_vignette) {
+        float d = length(v_pos - 0.5) / length(vec2(0.5));
+        c *= 1.0 - smoothstep(vignette_radius, vignette_radius + max(vignette_softness, 1.0e-4), d);
+    }
+
     color = vec4(c, 1.0);
 }
\ No newline at end of file
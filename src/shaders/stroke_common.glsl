@@ -0,0 +1,64 @@
+// Uniforms and helpers shared by the stroke fragment shaders, included before them
+
+uniform bool enable_haze;
+uniform vec3 haze_color;
+uniform float haze_start;
+uniform float haze_end;
+uniform bool enable_fog;
+uniform vec3 fog_color;
+uniform float fog_start;
+uniform float fog_end;
+// Size in world units of the grain noise features
+uniform float grain_scale;
+// How much (0 to 1) the grain lightens strokes, 0 disables it
+uniform float grain_strength;
+
+float hash13(vec3 p3) {
+    p3 = fract(p3 * 0.1031);
+    p3 += dot(p3, p3.zyx + 31.32);
+    return fract((p3.x + p3.y) * p3.z);
+}
+
+// Smooth value noise in [0, 1]
+float value_noise(vec3 p) {
+    vec3 i = floor(p);
+    vec3 f = fract(p);
+    f = f * f * (3.0 - 2.0 * f);
+    return mix(mix(mix(hash13(i), hash13(i + vec3(1, 0, 0)), f.x),
+                   mix(hash13(i + vec3(0, 1, 0)), hash13(i + vec3(1, 1, 0)), f.x), f.y),
+               mix(mix(hash13(i + vec3(0, 0, 1)), hash13(i + vec3(1, 0, 1)), f.x),
+                   mix(hash13(i + vec3(0, 1, 1)), hash13(i + vec3(1, 1, 1)), f.x), f.y),
+               f.z);
+}
+
+// Spatially coherent opacity of the strokes around `world_position`, so patches of the painting
+// are lighter or heavier
+float grain(vec3 world_position) {
+    if (grain_strength <= 0.0) {
+        return 1.0;
+    }
+    return 1.0 - grain_strength * value_noise(world_position / max(grain_scale, 1.0e-4));
+}
+
+// Weight of a fragment in the weighted blended average, favoring strokes close to the camera
+float oit_weight(float alpha) {
+    float z = 1.0 - gl_FragCoord.z;
+    return clamp(alpha * max(1.0e-2, 3.0e3 * z * z * z), 1.0e-2, 3.0e3);
+}
+
+const vec3 LUMINANCE = vec3(0.2126, 0.7152, 0.0722);
+
+// Desaturates and tints the color towards `haze_color` with distance, like atmospheric
+// perspective
+vec3 apply_haze(vec3 color, float depth) {
+    // Guarded like `apply_fog`, smoothstep is undefined when the start isn't below the end
+    float amount = smoothstep(0.0, 1.0, (depth - haze_start) / max(haze_end - haze_start, 1.0e-5));
+    vec3 hazed = mix(vec3(dot(color, LUMINANCE)), haze_color, 0.5);
+    return mix(color, hazed, amount);
+}
+
+// Fades the color towards `fog_color` between `fog_start` and `fog_end`
+vec3 apply_fog(vec3 color, float depth) {
+    float amount = clamp((depth - fog_start) / max(fog_end - fog_start, 1.0e-5), 0.0, 1.0);
+    return mix(color, fog_color, amount);
+}
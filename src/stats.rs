@@ -0,0 +1,73 @@
+//! Per frame timings appended to a CSV file with `--stats-csv`, for profiling across runs.
+//!
+//! Every row is one frame: seconds since the log was opened, the draw, sort and fixed update
+//! times and the time since the previous frame in milliseconds, and the number of strokes drawn.
+//! The times are the raw samples behind the averages shown in the UI. The sort time is empty on
+//! frames no sort finished before.
+
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, BufWriter, Write},
+    path::Path,
+    time::{Duration, Instant},
+};
+
+const HEADER: &str = "time_s,draw_ms,sort_ms,fixed_ms,frame_ms,points";
+
+/// How often the rows are written out, so little is lost if the app is killed
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Times of one frame in microseconds
+pub struct FrameStats {
+    pub draw: u64,
+    /// Time of the sort that finished since the last frame, if one did
+    pub sort: Option<u64>,
+    pub fixed: u64,
+    pub frame: u64,
+    pub points: usize,
+}
+
+pub struct StatsLog {
+    file: BufWriter<File>,
+    start: Instant,
+    last_flush: Instant,
+}
+
+impl StatsLog {
+    /// Opens `path` to append to, writing the header if the file is new or empty
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let empty = file.metadata()?.len() == 0;
+        let mut file = BufWriter::new(file);
+        if empty {
+            writeln!(file, "{HEADER}")?;
+        }
+        let now = Instant::now();
+        Ok(Self {
+            file,
+            start: now,
+            last_flush: now,
+        })
+    }
+
+    pub fn write_frame(&mut self, stats: &FrameStats) -> io::Result<()> {
+        let ms = |micros: u64| micros as f64 / 1000.0;
+        writeln!(
+            self.file,
+            "{:.3},{:.3},{},{:.3},{:.3},{}",
+            self.start.elapsed().as_secs_f64(),
+            ms(stats.draw),
+            stats
+                .sort
+                .map_or(String::new(), |sort| format!("{:.3}", ms(sort))),
+            ms(stats.fixed),
+            ms(stats.frame),
+            stats.points
+        )?;
+        if self.last_flush.elapsed() >= FLUSH_INTERVAL {
+            self.last_flush = Instant::now();
+            self.file.flush()?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,33 @@
+//! Offscreen rendering on a machine without a display server, as on CI
+
+use std::{env, fs, process::Command};
+
+#[test]
+fn renders_or_fails_cleanly_without_a_display() {
+    let output = env::temp_dir().join(format!("paint_render_headless_{}.png", std::process::id()));
+    let status = Command::new(env!("CARGO_BIN_EXE_paint_render"))
+        .current_dir(env!("CARGO_MANIFEST_DIR"))
+        .args([
+            "res/scenes/apple.toml",
+            "--no-cache",
+            "--width",
+            "64",
+            "--height",
+            "64",
+        ])
+        .arg("--output")
+        .arg(&output)
+        .env_remove("DISPLAY")
+        .env_remove("WAYLAND_DISPLAY")
+        .status()
+        .unwrap();
+
+    // Without OSMesa there is no context to render with, which is an error rather than a panic
+    match status.code() {
+        Some(0) => {
+            assert!(output.exists());
+            fs::remove_file(output).unwrap();
+        }
+        code => assert_eq!(code, Some(1)),
+    }
+}